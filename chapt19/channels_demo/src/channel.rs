@@ -0,0 +1,342 @@
+//! Bounded channels for the indexing pipeline, sized in bytes.
+//!
+//! The stages of the pipeline in `main.rs` run at different speeds. With
+//! unbounded channels, a fast stage feeding a slow one fills up memory: if
+//! reading files is faster than indexing them, every document ends up loaded
+//! into memory at once. The channels here hold at most a given number of
+//! bytes; a sender that would go over the limit waits until the receiving
+//! end catches up. This is called *backpressure*.
+//!
+//! Since items vary a lot in size (a document can be a few bytes or many
+//! megabytes), the limit is in bytes rather than a count of items. Each item
+//! type says how big it is by implementing `Weight`. An item bigger than the
+//! whole limit is still allowed through, when the channel is empty;
+//! otherwise it could never be sent at all.
+//!
+//! Each channel also keeps statistics (see `ChannelStats`), which show which
+//! stage of the pipeline is the bottleneck: the stage before a channel that's
+//! always full, and after a channel that's always empty.
+//!
+//! Unlike `std::sync::mpsc`, both ends can be cloned, so several threads can
+//! take items from the same channel.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Types that can be sent through a bounded channel.
+pub trait Weight {
+    /// Roughly how many bytes of memory this value uses, including the heap
+    /// data it owns.
+    fn weight(&self) -> usize;
+}
+
+/// Create a channel that holds up to `capacity` bytes of items.
+pub fn bounded<T: Weight>(name: &'static str, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            bytes: 0,
+            senders: 1,
+            receivers: 1
+        }),
+        not_full: Condvar::new(),
+        not_empty: Condvar::new(),
+        capacity,
+        stats: Arc::new(ChannelStats::new(name))
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_full: Condvar,
+    not_empty: Condvar,
+    capacity: usize,
+    stats: Arc<ChannelStats>
+}
+
+struct State<T> {
+    /// The items in the channel, with their weights.
+    queue: VecDeque<(T, usize)>,
+
+    /// The total weight of the items in `queue`.
+    bytes: usize,
+
+    /// The number of live `Sender`s and `Receiver`s. When there are no
+    /// senders left and the queue is empty, receivers get `None`; when there
+    /// are no receivers, sending fails.
+    senders: usize,
+    receivers: usize
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// The sending end of a bounded channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>
+}
+
+impl<T: Weight> Sender<T> {
+    /// Send an item, first waiting until there's room for it. Fails, giving
+    /// the item back, if all the receivers have been dropped.
+    pub fn send(&self, item: T) -> Result<(), T> {
+        let weight = item.weight();
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        let start = Instant::now();
+        while state.receivers > 0
+            && !state.queue.is_empty()
+            && state.bytes + weight > shared.capacity
+        {
+            state = shared.not_full.wait(state).unwrap();
+        }
+        shared.stats.send_wait.add(start.elapsed());
+        if state.receivers == 0 {
+            return Err(item);
+        }
+        state.queue.push_back((item, weight));
+        state.bytes += weight;
+        shared.stats.record_send(weight, state.queue.len(), state.bytes);
+        drop(state);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.stats.closed.set(self.shared.stats.created.elapsed());
+            drop(state);
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+/// The receiving end of a bounded channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>
+}
+
+impl<T> Receiver<T> {
+    /// Take the next item from the channel, waiting for one if necessary.
+    /// Returns `None` once the channel is empty and all the senders have been
+    /// dropped.
+    pub fn recv(&self) -> Option<T> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        let start = Instant::now();
+        while state.queue.is_empty() && state.senders > 0 {
+            state = shared.not_empty.wait(state).unwrap();
+        }
+        shared.stats.recv_wait.add(start.elapsed());
+        let (item, weight) = state.queue.pop_front()?;
+        state.bytes -= weight;
+        drop(state);
+        shared.not_full.notify_all();
+        Some(item)
+    }
+
+    /// The statistics for this channel. They keep being updated as long as
+    /// the channel is in use.
+    pub fn stats(&self) -> Arc<ChannelStats> {
+        self.shared.stats.clone()
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.shared.lock().receivers += 1;
+        Receiver { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Nobody will ever take these, so free the memory now, and wake
+            // up any senders so they can see there's no one listening.
+            state.queue.clear();
+            state.bytes = 0;
+            drop(state);
+            self.shared.not_full.notify_all();
+        }
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+/// A total amount of time, which threads can add to.
+#[derive(Default)]
+struct Timer(AtomicU64);
+
+impl Timer {
+    fn add(&self, time: Duration) {
+        self.0.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn set(&self, time: Duration) {
+        self.0.store(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// What went through a channel, and how full it got.
+pub struct ChannelStats {
+    name: &'static str,
+    created: Instant,
+
+    /// How long after `created` the last sender was dropped, or zero if
+    /// that hasn't happened yet.
+    closed: Timer,
+
+    items: AtomicU64,
+    bytes: AtomicU64,
+
+    /// The sum of the queue length, in items, after each send. Divide by
+    /// `items` to get the average.
+    depth_total: AtomicU64,
+    max_items: AtomicU64,
+    max_bytes: AtomicU64,
+
+    /// Total time senders spent waiting for room in the channel, and
+    /// receivers spent waiting for items.
+    send_wait: Timer,
+    recv_wait: Timer
+}
+
+impl ChannelStats {
+    fn new(name: &'static str) -> ChannelStats {
+        ChannelStats {
+            name,
+            created: Instant::now(),
+            closed: Timer::default(),
+            items: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            depth_total: AtomicU64::new(0),
+            max_items: AtomicU64::new(0),
+            max_bytes: AtomicU64::new(0),
+            send_wait: Timer::default(),
+            recv_wait: Timer::default()
+        }
+    }
+
+    fn record_send(&self, weight: usize, depth: usize, bytes: usize) {
+        self.items.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(weight as u64, Ordering::Relaxed);
+        self.depth_total.fetch_add(depth as u64, Ordering::Relaxed);
+        self.max_items.fetch_max(depth as u64, Ordering::Relaxed);
+        self.max_bytes.fetch_max(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The heading for the table that `Display` prints one row of.
+    pub const HEADING: &'static str =
+        "channel            items      MB   items/s    MB/s  avg depth  max depth    max MB  \
+         send wait  recv wait";
+}
+
+impl fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let items = self.items.load(Ordering::Relaxed);
+        let mb = self.bytes.load(Ordering::Relaxed) as f64 / (1 << 20) as f64;
+        let secs = match self.closed.get() {
+            d if d.is_zero() => self.created.elapsed(),
+            d => d
+        }.as_secs_f64().max(1e-9);
+        let avg_depth = if items == 0 {
+            0.0
+        } else {
+            self.depth_total.load(Ordering::Relaxed) as f64 / items as f64
+        };
+        write!(f, "{:<14} {:>9} {:>7.1} {:>9.1} {:>7.1} {:>10.1} {:>10} {:>9.1} {:>9.2}s {:>9.2}s",
+               self.name, items, mb, items as f64 / secs, mb / secs, avg_depth,
+               self.max_items.load(Ordering::Relaxed),
+               self.max_bytes.load(Ordering::Relaxed) as f64 / (1 << 20) as f64,
+               self.send_wait.get().as_secs_f64(), self.recv_wait.get().as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    impl Weight for Vec<u8> {
+        fn weight(&self) -> usize {
+            self.len()
+        }
+    }
+
+    #[test]
+    fn backpressure() {
+        let (sender, receiver) = bounded::<Vec<u8>>("test", 10);
+        let stats = receiver.stats();
+        let producer = thread::spawn(move || {
+            for n in 0..20 {
+                sender.send(vec![n; 4]).unwrap();
+            }
+            // Too big to ever fit, but it gets through once the channel empties.
+            sender.send(vec![99; 50]).unwrap();
+        });
+        // Wait until the channel is full: two items are in it, and the third
+        // can't fit, so the producer is blocked until something is received.
+        while stats.items.load(Ordering::Relaxed) < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(receiver.shared.lock().bytes, 8);
+        let received: Vec<Vec<u8>> = receiver.collect();
+        producer.join().unwrap();
+
+        assert_eq!(received.len(), 21);
+        assert_eq!(received[7], vec![7; 4]);
+        assert_eq!(stats.items.load(Ordering::Relaxed), 21);
+        assert!(stats.max_bytes.load(Ordering::Relaxed) <= 50);
+        assert_eq!(stats.max_items.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn disconnection() {
+        let (sender, receiver) = bounded::<Vec<u8>>("test", 10);
+        let receiver2 = receiver.clone();
+        drop(receiver);
+        sender.send(vec![1]).unwrap();
+        assert_eq!(receiver2.recv(), Some(vec![1]));
+        drop(receiver2);
+        assert_eq!(sender.send(vec![2]), Err(vec![2]));
+
+        let (sender, receiver) = bounded::<Vec<u8>>("test", 10);
+        let sender2 = sender.clone();
+        drop(sender);
+        sender2.send(vec![3]).unwrap();
+        drop(sender2);
+        assert_eq!(receiver.recv(), Some(vec![3]));
+        assert_eq!(receiver.recv(), None);
+    }
+}
@@ -0,0 +1,302 @@
+//! Finding copies of documents, so that each is indexed only once.
+//!
+//! Mirrored directories and backups mean the same file is often given to the
+//! indexer several times, and without this, every copy would show up in
+//! search results. The file reader stage checks each document against the
+//! ones before it. A document that turns out to be a copy doesn't get a
+//! document id of its own; instead, its path is added to the original's
+//! record in the document table, as a `Duplicate`.
+//!
+//! Copies are found by the hash of the file's contents that `DocumentInfo`
+//! already records. Since the hash isn't cryptographically strong, a match
+//! is confirmed by loading the earlier file again and comparing the text.
+//!
+//! With `--near-duplicates`, documents that are merely very similar are
+//! collapsed too. For each document, we compute a MinHash signature of its
+//! *shingles*, the runs of `SHINGLE_WORDS` consecutive tokens: for each of
+//! `NUM_HASHES` hash functions, the least hash of any shingle. The fraction
+//! of positions where two signatures agree estimates the Jaccard similarity
+//! of the two sets of shingles. Rather than comparing each document against
+//! all the others, the signatures are split into bands, and only documents
+//! that agree on every position of at least one band are compared (this is
+//! locality-sensitive hashing). Only the first of a group of near-duplicates
+//! is indexed, so words that appear only in the others won't find them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use crate::docs::{content_hash, DocumentInfo, Duplicate, Encoding};
+use crate::tokenize::{Token, Tokenizer};
+
+/// How many consecutive tokens make up a shingle.
+const SHINGLE_WORDS: usize = 4;
+
+/// The number of hash functions in a MinHash signature.
+const NUM_HASHES: usize = 64;
+
+/// How many bands a signature is split into. Two documents are compared if
+/// all `NUM_HASHES / BANDS` positions in any one band match.
+const BANDS: usize = 16;
+
+/// How similar two documents must be to count as near-duplicates: the
+/// fraction of their signatures that must match.
+pub const NEAR_DUPLICATE_THRESHOLD: f64 = 0.9;
+
+type Signature = [u64; NUM_HASHES];
+
+/// Keeps track of the documents seen so far, to spot copies.
+pub struct Deduplicator {
+    /// How documents are decoded, for loading one again to compare it.
+    encoding: Encoding,
+
+    /// The first document with each contents hash and size: the id of the
+    /// document it was indexed as (for a near-duplicate, the original's), and
+    /// its own path.
+    exact: HashMap<(u64, u64), (u32, PathBuf)>,
+
+    /// Signatures of the documents seen so far, if looking for
+    /// near-duplicates.
+    near: Option<NearDuplicates>,
+
+    /// The copies found so far, by the id of the document they duplicate.
+    found: BTreeMap<u32, Vec<Duplicate>>
+}
+
+impl Deduplicator {
+    /// Prepare to check documents decoded as `encoding`. If `near_duplicates`
+    /// is true, documents that are nearly the same count as copies too.
+    pub fn new(encoding: Encoding, near_duplicates: bool) -> Deduplicator {
+        Deduplicator {
+            encoding,
+            exact: HashMap::new(),
+            near: near_duplicates.then(NearDuplicates::default),
+            found: BTreeMap::new()
+        }
+    }
+
+    /// Remember a document that's already in the index being updated, so
+    /// that new copies of it are collapsed into it. (Only exact copies: the
+    /// index doesn't store the tokens needed to check for near-duplicates.)
+    pub fn add_existing(&mut self, doc: &DocumentInfo) {
+        self.exact.entry((doc.hash, doc.size)).or_insert((doc.id, doc.path.clone()));
+    }
+
+    /// Check the document just loaded, `info` and `text`, which would get
+    /// the id `doc_id`. If it's a copy of a document seen earlier, remember
+    /// it as a duplicate of that document and return true; the caller
+    /// should then skip it, and give `doc_id` to the next document.
+    /// Otherwise, remember it and return false.
+    pub fn is_duplicate(&mut self, doc_id: usize, info: &DocumentInfo, text: &str,
+                        tokenizer: &dyn Tokenizer) -> bool {
+        let doc_id = doc_id as u32;
+        let key = (info.hash, info.size);
+        let original = match self.exact.get(&key) {
+            Some((id, path)) if self.has_text(path, text) => Some(*id),
+            _ => None
+        };
+        let original = original.or_else(|| {
+            let near = self.near.as_mut()?;
+            let signature = signature(&tokenizer.tokenize(text))?;
+            near.find_or_insert(doc_id, signature)
+        });
+
+        // Exact copies of this document, if any turn up later, are copies of
+        // whichever document it was indexed as. A near-duplicate isn't
+        // indexed at all, so its `doc_id` will go to the next document.
+        self.exact.entry(key).or_insert((original.unwrap_or(doc_id), info.path.clone()));
+        match original {
+            Some(id) => {
+                self.found.entry(id).or_default().push(info.clone().into_duplicate());
+                true
+            }
+            None => false
+        }
+    }
+
+    /// True if the file at `path` still loads as `text`.
+    fn has_text(&self, path: &Path, text: &str) -> bool {
+        DocumentInfo::load(path.to_owned(), self.encoding).is_ok_and(|(_, t)| t.text == text)
+    }
+
+    /// The number of copies found.
+    pub fn count(&self) -> usize {
+        self.found.values().map(|dups| dups.len()).sum()
+    }
+
+    /// The copies found, as document records that list only duplicates,
+    /// sorted by id, ready to be merged into the document table (see the
+    /// `docs` module).
+    pub fn into_records(self) -> Vec<DocumentInfo> {
+        self.found.into_iter()
+            .map(|(id, duplicates)| DocumentInfo { id, duplicates, ..DocumentInfo::default() })
+            .collect()
+    }
+}
+
+/// The MinHash signatures of the documents seen so far, indexed by band.
+#[derive(Default)]
+struct NearDuplicates {
+    signatures: Vec<(u32, Signature)>,
+
+    /// For each band and the hash of that band of a signature, the indexes in
+    /// `signatures` of the documents with that band.
+    bands: HashMap<(usize, u64), Vec<usize>>
+}
+
+impl NearDuplicates {
+    /// Find the document most similar to the one with `signature`, as long
+    /// as it's at least `NEAR_DUPLICATE_THRESHOLD` similar, and return its
+    /// id. If there's no such document, remember this one, as `doc_id`.
+    fn find_or_insert(&mut self, doc_id: u32, signature: Signature) -> Option<u32> {
+        let mut candidates: Vec<usize> = band_keys(&signature)
+            .filter_map(|key| self.bands.get(&key))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        // Ties go to the earliest document.
+        let mut best: Option<(f64, u32)> = None;
+        for i in candidates {
+            let (id, other) = &self.signatures[i];
+            let s = similarity(&signature, other);
+            if s >= NEAR_DUPLICATE_THRESHOLD && best.is_none_or(|(b, _)| s > b) {
+                best = Some((s, *id));
+            }
+        }
+        if let Some((_, id)) = best {
+            return Some(id);
+        }
+
+        let i = self.signatures.len();
+        for key in band_keys(&signature) {
+            self.bands.entry(key).or_default().push(i);
+        }
+        self.signatures.push((doc_id, signature));
+        None
+    }
+}
+
+/// The keys under which a signature is filed in `NearDuplicates::bands`.
+fn band_keys(signature: &Signature) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature.chunks(NUM_HASHES / BANDS)
+        .enumerate()
+        .map(|(band, rows)| (band, rows.iter().fold(band as u64, |h, &r| mix(h ^ r))))
+}
+
+/// The fraction of positions where two signatures agree.
+fn similarity(a: &Signature, b: &Signature) -> f64 {
+    let same = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    same as f64 / NUM_HASHES as f64
+}
+
+/// The MinHash signature of a document's tokens, or `None` if there are
+/// none. A document shorter than a shingle is a single shingle.
+fn signature(tokens: &[Token]) -> Option<Signature> {
+    if tokens.is_empty() {
+        return None;
+    }
+    let mut signature = [u64::MAX; NUM_HASHES];
+    let mut shingle = vec![];
+    for window in tokens.windows(SHINGLE_WORDS.min(tokens.len())) {
+        shingle.clear();
+        for token in window {
+            shingle.extend_from_slice(token.text.as_bytes());
+            shingle.push(0xff);
+        }
+        let base = content_hash(&shingle);
+        for (i, min) in signature.iter_mut().enumerate() {
+            let h = mix(base.wrapping_add((i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+            *min = (*min).min(h);
+        }
+    }
+    Some(signature)
+}
+
+/// Scramble the bits of `x` (the SplitMix64 finalizer). Adding a different
+/// constant to the input first gives each hash function of the signature.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tmp::TmpDir;
+    use crate::tokenize::UnicodeTokenizer;
+
+    /// Write `text` to a new file in `tmp_dir` and load it.
+    fn load(tmp_dir: &TmpDir, text: &str) -> (DocumentInfo, String) {
+        let (path, _) = tmp_dir.create().unwrap();
+        fs::write(&path, text).unwrap();
+        let (info, loaded) = DocumentInfo::load(path, Encoding::Utf8).unwrap();
+        (info, loaded.text)
+    }
+
+    fn words(n: usize, seed: usize) -> Vec<String> {
+        (0..n).map(|i| format!("w{}", (i * 7919 + seed) % 1000)).collect()
+    }
+
+    #[test]
+    fn exact() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let mut dedup = Deduplicator::new(Encoding::Utf8, false);
+        let docs = ["one two three", "four five", "one two three", "one two three."];
+        let results: Vec<bool> = docs.iter().enumerate().map(|(doc_id, text)| {
+            let (info, text) = load(&tmp_dir, text);
+            dedup.is_duplicate(doc_id, &info, &text, &UnicodeTokenizer)
+        }).collect();
+        assert_eq!(results, vec![false, false, true, false]);
+        assert_eq!(dedup.count(), 1);
+
+        let records = dedup.into_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, 0);
+        assert!(records[0].is_duplicates_only());
+    }
+
+    #[test]
+    fn near() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let original = words(400, 1);
+        let mut edited = original.clone();
+        edited[200] = "changed".to_string();
+        let different = words(400, 2);
+
+        let docs = [original.join(" "), different.join(" "), edited.join(" ")];
+        for near_duplicates in [false, true] {
+            let mut dedup = Deduplicator::new(Encoding::Utf8, near_duplicates);
+            let results: Vec<bool> = docs.iter().enumerate().map(|(doc_id, text)| {
+                let (info, text) = load(&tmp_dir, text);
+                dedup.is_duplicate(doc_id, &info, &text, &UnicodeTokenizer)
+            }).collect();
+            assert_eq!(results, vec![false, false, near_duplicates]);
+        }
+
+        // An exact copy of a near-duplicate is a copy of the original, not
+        // of the document that got the near-duplicate's unused id.
+        let docs = [original.join(" "), edited.join(" "), different.join(" "), edited.join(" ")];
+        let mut dedup = Deduplicator::new(Encoding::Utf8, true);
+        let mut doc_id = 0;
+        for text in &docs {
+            let (info, text) = load(&tmp_dir, text);
+            if !dedup.is_duplicate(doc_id, &info, &text, &UnicodeTokenizer) {
+                doc_id += 1;
+            }
+        }
+        assert_eq!(doc_id, 2);
+        let records = dedup.into_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, 0);
+        assert_eq!(records[0].duplicates.len(), 2);
+
+        let tokens = UnicodeTokenizer.tokenize(&original.join(" "));
+        let a = signature(&tokens).unwrap();
+        assert_eq!(similarity(&a, &a), 1.0);
+        assert!(similarity(&a, &signature(&tokens[..200]).unwrap()) < NEAR_DUPLICATE_THRESHOLD);
+        assert_eq!(signature(&[]), None);
+    }
+}
@@ -0,0 +1,367 @@
+//! The document table.
+//!
+//! Besides the hits for each term, an index file stores a little information
+//! about each document: where it came from, so that a search hit can be
+//! turned back into a filename; enough about the file to tell later whether
+//! it has changed (size, modification time, and a hash of the contents); and
+//! how many words it contains, which ranking needs in order to compare hits
+//! in long documents fairly against hits in short ones.
+//!
+//! The table also records which tokenizer was used to break the documents
+//! into terms (see the `tokenize` module), and how the files were decoded,
+//! so that a snippet can be made from a file by decoding and tokenizing it
+//! exactly as it was when it was indexed.
+//!
+//! A document that has been deleted or replaced by a newer version is marked
+//! with a *tombstone*: a record with `deleted` set. The merge code drops the
+//! hits for tombstoned documents, and the final merge drops the tombstones
+//! themselves (see `merge::merge_streams`).
+//!
+//! When several files have the same contents, only the first is indexed; the
+//! others are listed in its record as *duplicates* (see `dedup.rs`). Since
+//! the first file's record may already have been written to a temporary file
+//! by the time a copy turns up, copies are written separately, as records
+//! with an empty `path` and only `duplicates`. The merge folds each of these
+//! into the record for the document with the same id.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::loader::{load_text, LoadedText};
+
+/// What the index knows about a single document.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentInfo {
+    /// The document id, the same number used in `Hit`s.
+    pub id: u32,
+
+    /// The number of words in the document's body and title, the fields a
+    /// query searches by default (see `field::in_scope`). This is what
+    /// ranking compares to the average.
+    pub length: u32,
+
+    /// The path of the file, as it was given to the indexer.
+    pub path: PathBuf,
+
+    /// The size of the file in bytes.
+    pub size: u64,
+
+    /// The file's last modification time, in seconds since the Unix epoch.
+    pub mtime: u64,
+
+    /// A hash of the file's contents (see `content_hash`).
+    pub hash: u64,
+
+    /// True if this record is a tombstone: the document has been removed
+    /// from the index, and any hits for it should be ignored.
+    pub deleted: bool,
+
+    /// Other files with the same contents, which weren't indexed separately.
+    pub duplicates: Vec<Duplicate>
+}
+
+/// A file that wasn't indexed because it's a copy of a document that was.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Duplicate {
+    /// The path of the file, as it was given to the indexer.
+    pub path: PathBuf,
+
+    /// The size, modification time, and hash of this file, as in
+    /// `DocumentInfo`. With `--near-duplicates`, the hash may differ from the
+    /// document's.
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: u64
+}
+
+impl DocumentInfo {
+    /// Load the file at `path` into memory and turn it into text, using
+    /// the loader for its kind of file (see `loader.rs`) and decoding it as
+    /// `encoding`.
+    ///
+    /// Returns the text, along with a `DocumentInfo` describing the file. Its
+    /// `id` and `length` are left at 0 for the indexing step to fill in.
+    pub fn load(path: PathBuf, encoding: Encoding) -> io::Result<(DocumentInfo, LoadedText)> {
+        let mut f = File::open(&path)?;
+        let metadata = f.metadata()?;
+        let mut bytes = vec![];
+        f.read_to_end(&mut bytes)?;
+        let hash = content_hash(&bytes);
+        let text = load_text(&path, bytes, encoding)?;
+
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let info = DocumentInfo {
+            id: 0,
+            length: 0,
+            size: metadata.len(),
+            mtime,
+            hash,
+            path,
+            deleted: false,
+            duplicates: vec![]
+        };
+        Ok((info, text))
+    }
+
+    /// True if this record only lists duplicates of a document whose own
+    /// record is elsewhere (see the comment at the top of this file).
+    pub fn is_duplicates_only(&self) -> bool {
+        self.path.as_os_str().is_empty() && !self.duplicates.is_empty()
+    }
+
+    /// Describe this file as a duplicate of some other document.
+    pub fn into_duplicate(self) -> Duplicate {
+        Duplicate { path: self.path, size: self.size, mtime: self.mtime, hash: self.hash }
+    }
+}
+
+/// How to turn the bytes of a file into text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// The file must be valid UTF-8; if it isn't, loading it fails.
+    Utf8,
+
+    /// UTF-8, but any invalid bytes are replaced with U+FFFD REPLACEMENT
+    /// CHARACTER.
+    Utf8Lossy,
+
+    /// ISO 8859-1: each byte is the character with that code point. Every
+    /// file is valid Latin-1, so this never fails.
+    Latin1
+}
+
+impl Encoding {
+    /// The name of this encoding, as given to `--encoding` and recorded in
+    /// the document table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf8",
+            Encoding::Utf8Lossy => "utf8-lossy",
+            Encoding::Latin1 => "latin1"
+        }
+    }
+
+    /// Decode the contents of a file.
+    pub fn decode(self, bytes: Vec<u8>) -> io::Result<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "not valid UTF-8 (at byte {}); try --encoding utf8-lossy or latin1",
+                    err.utf8_error().valid_up_to()))
+            }),
+            Encoding::Utf8Lossy => Ok(match String::from_utf8(bytes) {
+                Ok(text) => text,
+                Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned()
+            }),
+            Encoding::Latin1 => Ok(bytes.into_iter().map(char::from).collect())
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(Encoding::Utf8),
+            "utf8-lossy" | "utf-8-lossy" => Ok(Encoding::Utf8Lossy),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            _ => Err(format!("unknown encoding `{}`; try utf8, utf8-lossy, or latin1", s))
+        }
+    }
+}
+
+/// Hash a file's contents, using 64-bit FNV-1a. This isn't cryptographically
+/// strong, but unlike `std`'s `DefaultHasher` it is guaranteed never to change,
+/// which matters since these hashes are saved to disk.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Flag bit in a document record marking it as a tombstone.
+const DELETED: u32 = 1;
+
+/// Flag bit in a document record saying that a list of duplicates follows
+/// the path.
+const DUPLICATES: u32 = 2;
+
+/// The document table of an index file, as read from disk.
+pub struct DocumentTable {
+    /// The spec of the tokenizer used to index the documents.
+    pub tokenizer: String,
+
+    /// How the documents were decoded.
+    pub encoding: Encoding,
+
+    /// The total number of words in all the documents that haven't been
+    /// deleted.
+    pub total_words: u64,
+
+    /// The documents, sorted by id.
+    pub documents: Vec<DocumentInfo>
+}
+
+/// Write the document table for an index file.
+///
+/// The table starts with the tokenizer spec and the name of the encoding
+/// (each a u32 length, then UTF-8), the total number of words in all the
+/// documents that haven't been deleted (a u64), and the number of records (a
+/// u32), followed by one record per document. `documents` must be sorted by
+/// id.
+///
+/// A record is the id, length, and flags (u32s); the size, modification
+/// time, and hash (u64s); and the path. If the `DUPLICATES` flag is set, it
+/// ends with the number of duplicates (a u32) and, for each one, its size,
+/// modification time, hash, and path.
+pub fn write_documents<W: Write>(out: &mut W, tokenizer: &str, encoding: Encoding,
+                                 documents: &[DocumentInfo])
+    -> io::Result<()>
+{
+    write_string(out, tokenizer)?;
+    write_string(out, encoding.name())?;
+    let total_words: u64 = documents.iter()
+        .filter(|d| !d.deleted)
+        .map(|d| d.length as u64)
+        .sum();
+    out.write_u64::<LittleEndian>(total_words)?;
+    out.write_u32::<LittleEndian>(documents.len() as u32)?;
+    for doc in documents {
+        out.write_u32::<LittleEndian>(doc.id)?;
+        out.write_u32::<LittleEndian>(doc.length)?;
+        let mut flags = if doc.deleted { DELETED } else { 0 };
+        if !doc.duplicates.is_empty() {
+            flags |= DUPLICATES;
+        }
+        out.write_u32::<LittleEndian>(flags)?;
+        out.write_u64::<LittleEndian>(doc.size)?;
+        out.write_u64::<LittleEndian>(doc.mtime)?;
+        out.write_u64::<LittleEndian>(doc.hash)?;
+        write_string(out, &doc.path.to_string_lossy())?;
+        if !doc.duplicates.is_empty() {
+            out.write_u32::<LittleEndian>(doc.duplicates.len() as u32)?;
+            for dup in &doc.duplicates {
+                out.write_u64::<LittleEndian>(dup.size)?;
+                out.write_u64::<LittleEndian>(dup.mtime)?;
+                out.write_u64::<LittleEndian>(dup.hash)?;
+                write_string(out, &dup.path.to_string_lossy())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(s.len() as u32)?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(f: &mut R) -> io::Result<String> {
+    let len = f.read_u32::<LittleEndian>()? as usize;
+    let mut bytes = vec![0; len];
+    f.read_exact(&mut bytes)?;
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(io::Error::other("unicode fail"))
+    }
+}
+
+/// Read a document table written by `write_documents`.
+pub fn read_documents<R: Read>(f: &mut R) -> io::Result<DocumentTable> {
+    let tokenizer = read_string(f)?;
+    let encoding = read_string(f)?.parse()
+        .map_err(|msg: String| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+    let total_words = f.read_u64::<LittleEndian>()?;
+    let count = f.read_u32::<LittleEndian>()?;
+    let mut documents = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let id = f.read_u32::<LittleEndian>()?;
+        let length = f.read_u32::<LittleEndian>()?;
+        let flags = f.read_u32::<LittleEndian>()?;
+        let size = f.read_u64::<LittleEndian>()?;
+        let mtime = f.read_u64::<LittleEndian>()?;
+        let hash = f.read_u64::<LittleEndian>()?;
+        let path = PathBuf::from(read_string(f)?);
+        let deleted = flags & DELETED != 0;
+        let mut duplicates = vec![];
+        if flags & DUPLICATES != 0 {
+            let count = f.read_u32::<LittleEndian>()?;
+            for _ in 0..count {
+                let size = f.read_u64::<LittleEndian>()?;
+                let mtime = f.read_u64::<LittleEndian>()?;
+                let hash = f.read_u64::<LittleEndian>()?;
+                let path = PathBuf::from(read_string(f)?);
+                duplicates.push(Duplicate { path, size, mtime, hash });
+            }
+        }
+        documents.push(DocumentInfo { id, length, path, size, mtime, hash, deleted, duplicates });
+    }
+    Ok(DocumentTable { tokenizer, encoding, total_words, documents })
+}
+
+/// Find a document by id in a document table sorted by id.
+pub fn find_document(documents: &[DocumentInfo], doc_id: u32) -> Option<&DocumentInfo> {
+    documents
+        .binary_search_by_key(&doc_id, |d| d.id)
+        .ok()
+        .map(|i| &documents[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn document_table_round_trip() {
+        let documents = vec![
+            DocumentInfo { id: 0, length: 12, path: PathBuf::from("a.txt"),
+                           size: 70, mtime: 1_600_000_000, hash: content_hash(b"a"),
+                           deleted: false,
+                           duplicates: vec![
+                               Duplicate { path: PathBuf::from("mirror/a.txt"), size: 70,
+                                           mtime: 1_650_000_000, hash: content_hash(b"a") }
+                           ] },
+            DocumentInfo { id: 4, length: 3, path: PathBuf::from("dir/b.txt"),
+                           size: 15, mtime: 1_700_000_000, hash: content_hash(b"b"),
+                           deleted: false, duplicates: vec![] },
+            DocumentInfo { id: 5, length: 8, path: PathBuf::from("gone.txt"),
+                           size: 40, mtime: 1_700_000_000, hash: content_hash(b"c"),
+                           deleted: true, duplicates: vec![] },
+        ];
+        let mut buf = vec![];
+        write_documents(&mut buf, "unicode,stem", Encoding::Latin1, &documents).unwrap();
+        let table = read_documents(&mut &buf[..]).unwrap();
+        assert_eq!(table.tokenizer, "unicode,stem");
+        assert_eq!(table.encoding, Encoding::Latin1);
+        assert_eq!(table.total_words, 15);
+        assert_eq!(table.documents, documents);
+        let read_back = table.documents;
+        assert_eq!(find_document(&read_back, 4).unwrap().path, Path::new("dir/b.txt"));
+        assert!(find_document(&read_back, 1).is_none());
+    }
+
+    #[test]
+    fn decoding() {
+        let bytes = b"caf\xe9 au lait".to_vec();
+        assert!(Encoding::Utf8.decode(bytes.clone()).is_err());
+        assert_eq!(Encoding::Utf8Lossy.decode(bytes.clone()).unwrap(), "caf\u{fffd} au lait");
+        assert_eq!(Encoding::Latin1.decode(bytes).unwrap(), "café au lait");
+        assert_eq!(Encoding::Utf8.decode("café".as_bytes().to_vec()).unwrap(), "café");
+        assert_eq!("UTF-8-lossy".parse(), Ok(Encoding::Utf8Lossy));
+        for encoding in [Encoding::Utf8, Encoding::Utf8Lossy, Encoding::Latin1] {
+            assert_eq!(encoding.name().parse(), Ok(encoding));
+        }
+        assert!("ebcdic".parse::<Encoding>().is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Errors from building an index.
+//!
+//! Indexing reads thousands of files on several threads. An `io::Error` on
+//! its own, such as "stream did not contain valid UTF-8", doesn't say which
+//! file it was about or what the program was doing at the time. An
+//! `IndexError` carries both, and also reports threads that panicked, so the
+//! pipeline in `main.rs` never has to `unwrap` a `JoinHandle`.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What the indexer was doing when something went wrong.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// Choosing the tokenizer, or opening an existing index to update it.
+    Setup,
+
+    /// Looking for files to index.
+    Walk,
+
+    /// Loading a document into memory.
+    Read,
+
+    /// Breaking a document into terms.
+    Index,
+
+    /// Merging in-memory indexes.
+    Merge,
+
+    /// Saving an in-memory index to a temporary file.
+    Write,
+
+    /// Merging index files on disk.
+    FileMerge
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Stage::Setup => "setting up",
+            Stage::Walk => "looking for files",
+            Stage::Read => "reading",
+            Stage::Index => "indexing",
+            Stage::Merge => "merging in memory",
+            Stage::Write => "writing a temporary file",
+            Stage::FileMerge => "merging index files"
+        })
+    }
+}
+
+/// An error that stopped (or, with `--keep-going`, skipped) part of the work.
+#[derive(Debug)]
+pub enum IndexError {
+    /// An I/O error, or a file that couldn't be decoded. `path` is the
+    /// document being worked on, if the error is about a single document.
+    Io { stage: Stage, path: Option<PathBuf>, error: io::Error },
+
+    /// A thread panicked.
+    Panic { stage: Stage, path: Option<PathBuf>, message: String }
+}
+
+impl IndexError {
+    /// Make an error out of the payload of a panic, as returned by
+    /// `JoinHandle::join` or `panic::catch_unwind`.
+    pub fn panic(stage: Stage, path: Option<PathBuf>, payload: Box<dyn Any + Send>) -> IndexError {
+        let message = match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(s) => s.to_string(),
+                Err(_) => "unknown panic".to_string()
+            }
+        };
+        IndexError::Panic { stage, path, message }
+    }
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Io { stage, path: Some(path), error } =>
+                write!(f, "{} {}: {}", stage, path.display(), error),
+            IndexError::Io { stage, path: None, error } =>
+                write!(f, "{}: {}", stage, error),
+            IndexError::Panic { stage, path: Some(path), message } =>
+                write!(f, "{} {}: panicked: {}", stage, path.display(), message),
+            IndexError::Panic { stage, path: None, message } =>
+                write!(f, "{}: panicked: {}", stage, message)
+        }
+    }
+}
+
+impl Error for IndexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IndexError::Io { error, .. } => Some(error),
+            IndexError::Panic { .. } => None
+        }
+    }
+}
+
+/// Attach a stage, and maybe a path, to an `io::Result`.
+pub trait Context<T> {
+    fn context(self, stage: Stage) -> Result<T, IndexError>;
+    fn file_context(self, stage: Stage, path: &Path) -> Result<T, IndexError>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, stage: Stage) -> Result<T, IndexError> {
+        self.map_err(|error| IndexError::Io { stage, path: None, error })
+    }
+
+    fn file_context(self, stage: Stage, path: &Path) -> Result<T, IndexError> {
+        self.map_err(|error| IndexError::Io { stage, path: Some(path.to_owned()), error })
+    }
+}
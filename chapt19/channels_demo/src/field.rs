@@ -0,0 +1,172 @@
+//! Fields: which part of a document a word came from.
+//!
+//! Each document is indexed as four fields: its text (the body), its title,
+//! its file name, and the directories in its path. They're all broken into
+//! terms by the same tokenizer and indexed together, but every hit records
+//! which field each occurrence is in, so that a query can ask for a word in
+//! a particular field (`title:gapbuffer`, `path:chapt19`), and ranking can
+//! count a word in the title for more than one in the body.
+//!
+//! A query word that doesn't name a field looks only in the body and the
+//! title. Words like `txt` are in nearly every file's name, so matching the
+//! name and path too would make them match nearly everything.
+//!
+//! The body comes first, at positions starting from 0, so that positions in
+//! the body line up with the text of the file (see `snippet.rs`). Each other
+//! field starts `FIELD_GAP` positions after the end of the one before, so that
+//! a phrase can't match across two fields.
+
+use std::path::Path;
+use std::str::FromStr;
+
+/// A part of a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+    /// The text of the document.
+    Body = 0,
+
+    /// The first heading in the document, for the kinds of files that have
+    /// headings (see `loader.rs`).
+    Title = 1,
+
+    /// The file's name.
+    Name = 2,
+
+    /// The directories in the file's path.
+    Path = 3
+}
+
+/// Every field, in the order they're indexed, which is also the order of
+/// their numbers.
+pub const FIELDS: [Field; 4] = [Field::Body, Field::Title, Field::Name, Field::Path];
+
+/// How many bits a field's number takes in a hit (see `index::Hit`).
+pub const FIELD_BITS: u32 = 2;
+
+/// The greatest position a word can be at: a position shares a u32 with the
+/// field's number (see `index::Hit`).
+pub const MAX_POSITION: u32 = u32::MAX >> FIELD_BITS;
+
+/// How many positions are left empty between two fields.
+const FIELD_GAP: u32 = 100;
+
+impl Field {
+    /// The name of this field in queries.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Body => "body",
+            Field::Title => "title",
+            Field::Name => "name",
+            Field::Path => "path"
+        }
+    }
+
+    /// The field with the given number. Only the low `FIELD_BITS` bits are
+    /// used, so every number means some field.
+    pub fn from_bits(bits: u32) -> Field {
+        FIELDS[(bits & ((1 << FIELD_BITS) - 1)) as usize]
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Field, String> {
+        FIELDS.iter()
+            .copied()
+            .find(|field| field.name() == s)
+            .ok_or_else(|| format!("unknown field `{}` (use body, title, name, or path)", s))
+    }
+}
+
+/// The text of each field of a document: the file's `text` and `title`, as
+/// loaded, and its `path`. Fields with no text are left out.
+pub fn document_fields(path: &Path, text: String, title: Option<String>) -> Vec<(Field, String)> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+    let dirs = path.parent().map(|dirs| dirs.to_string_lossy().into_owned());
+    [(Field::Body, Some(text)), (Field::Title, title), (Field::Name, name), (Field::Path, dirs)]
+        .into_iter()
+        .filter_map(|(field, text)| Some((field, text?)))
+        .filter(|(_, text)| !text.is_empty())
+        .collect()
+}
+
+/// True if a query word looks for occurrences in `field`. `scope` is the
+/// field named in the query, if any (see the module documentation).
+pub fn in_scope(scope: Option<Field>, field: Field) -> bool {
+    match scope {
+        Some(scope) => field == scope,
+        None => matches!(field, Field::Body | Field::Title)
+    }
+}
+
+/// The position where the field after one whose last word was at position
+/// `last` should start.
+pub fn next_field_start(last: u32) -> u32 {
+    last.saturating_add(FIELD_GAP + 1)
+}
+
+/// How much a word in each field counts toward a document's score,
+/// indexed by field number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Boosts(pub [f64; FIELDS.len()]);
+
+impl Default for Boosts {
+    fn default() -> Boosts {
+        // Body, title, name, path.
+        Boosts([1.0, 3.0, 2.0, 1.5])
+    }
+}
+
+impl Boosts {
+    pub fn get(&self, field: Field) -> f64 {
+        self.0[field as usize]
+    }
+
+    /// Apply a setting from the command line, like `title=5`.
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let (name, weight) = setting.split_once('=')
+            .ok_or_else(|| format!("bad boost `{}` (expected FIELD=WEIGHT)", setting))?;
+        let field: Field = name.parse()?;
+        let weight: f64 = weight.parse()
+            .ok()
+            .filter(|w: &f64| w.is_finite() && *w >= 0.0)
+            .ok_or_else(|| format!("bad weight in boost `{}`", setting))?;
+        self.0[field as usize] = weight;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        assert_eq!(document_fields(Path::new("src/chapt19/notes.md"), "hello".to_string(),
+                                   Some("Notes".to_string())),
+                   vec![(Field::Body, "hello".to_string()), (Field::Title, "Notes".to_string()),
+                        (Field::Name, "notes.md".to_string()),
+                        (Field::Path, "src/chapt19".to_string())]);
+        assert_eq!(document_fields(Path::new("notes.txt"), String::new(), None),
+                   vec![(Field::Name, "notes.txt".to_string())]);
+
+        for field in FIELDS {
+            assert_eq!(Field::from_bits(field as u32), field);
+            assert_eq!(field.name().parse(), Ok(field));
+        }
+        assert!("author".parse::<Field>().is_err());
+
+        assert!(in_scope(None, Field::Title));
+        assert!(!in_scope(None, Field::Path));
+        assert!(in_scope(Some(Field::Path), Field::Path));
+        assert!(!in_scope(Some(Field::Path), Field::Body));
+
+        let mut boosts = Boosts::default();
+        boosts.set("path=0.5").unwrap();
+        assert_eq!(boosts.get(Field::Path), 0.5);
+        assert!(boosts.set("path").is_err());
+        assert!(boosts.set("path=-1").is_err());
+        assert!(boosts.set("author=1").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! The index file header, and detecting damaged index files.
+//!
+//! Every index file starts with a fixed-size header:
+//!
+//! ```text
+//! offset  size  field
+//!      0     8  magic number, b"FNGRTIPS"
+//!      8     4  format version (currently 4)
+//!     12     4  flags; the low byte is the postings format
+//!     16     8  offset of the document table
+//!     24     8  offset of the table of contents
+//!     32     8  total length of the file
+//!     40     4  CRC-32 of the main section (from the end of the header to
+//!               the document table)
+//!     44     4  CRC-32 of the document table
+//!     48     4  CRC-32 of the table of contents
+//!     52     4  CRC-32 of bytes 0..52 of the header
+//! ```
+//!
+//! All numbers are little-endian. The checksums let readers tell a damaged or
+//! truncated file from a good one, rather than reading garbage.
+//!
+//! Only files of the current version can be read. Each version changed what
+//! the sections hold, and files from before there was a header (which start
+//! with the bare offset of the table of contents) don't have a document
+//! table at all, so there's no reading them short of rebuilding the index.
+//! Readers report them as `FormatError::NotAnIndex` or `UnsupportedVersion`,
+//! and the messages say to rebuild.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crc32fast::Hasher;
+use crate::postings::PostingsFormat;
+
+const MAGIC: &[u8; 8] = b"FNGRTIPS";
+
+/// The version of the file format written by this code.
+pub const VERSION: u32 = 4;
+
+/// The size of the header, in bytes.
+pub const HEADER_SIZE: u64 = 56;
+
+/// One of the checksummed parts of an index file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+    Header,
+    Main,
+    Documents,
+    Contents
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Section::Header => "header",
+            Section::Main => "index data",
+            Section::Documents => "document table",
+            Section::Contents => "table of contents"
+        })
+    }
+}
+
+/// The ways an index file can be malformed.
+///
+/// Functions in this crate that read index files return `io::Result`. When
+/// the problem is the file's contents rather than the I/O, the `io::Error`
+/// has kind `InvalidData` and wraps one of these; use `FormatError::of` to
+/// get it back out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormatError {
+    /// The file doesn't start with the magic number, so it isn't an index
+    /// file, or it's one written by a version of this program from before
+    /// index files had headers.
+    NotAnIndex,
+
+    /// The file was written by a newer version of this program, or by an
+    /// older one whose format this version can't read.
+    UnsupportedVersion(u32),
+
+    /// The header names a postings format we don't know about.
+    UnknownPostingsFormat(u8),
+
+    /// The file isn't the length the header says it should be; typically it
+    /// was truncated.
+    WrongLength { expected: u64, actual: u64 },
+
+    /// The header's section offsets are out of order or out of bounds.
+    BadOffsets,
+
+    /// A section's contents don't match its checksum.
+    ChecksumMismatch(Section),
+
+    /// The hits for a term couldn't be decoded.
+    BadPostings,
+
+    /// The terms in the table of contents aren't in sorted order.
+    UnsortedTerms
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::NotAnIndex =>
+                write!(f, "not a fingertips index file \
+                           (or one made by an older version; rebuild the index)"),
+            FormatError::UnsupportedVersion(v) if v < VERSION =>
+                write!(f, "index file format version {} is too old; rebuild the index", v),
+            FormatError::UnsupportedVersion(v) =>
+                write!(f, "index file format version {} is not supported", v),
+            FormatError::UnknownPostingsFormat(flag) =>
+                write!(f, "unknown postings format {}", flag),
+            FormatError::WrongLength { expected, actual } =>
+                write!(f, "index file should be {} bytes long, but it's {} bytes (truncated?)",
+                       expected, actual),
+            FormatError::BadOffsets =>
+                write!(f, "index file header has bad section offsets"),
+            FormatError::ChecksumMismatch(section) =>
+                write!(f, "checksum mismatch in index file {}", section),
+            FormatError::BadPostings =>
+                write!(f, "index file contains malformed hit data"),
+            FormatError::UnsortedTerms =>
+                write!(f, "index file's table of contents is out of order")
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+impl From<FormatError> for io::Error {
+    fn from(err: FormatError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl FormatError {
+    /// If `err` was caused by a malformed index file, return the details.
+    pub fn of(err: &io::Error) -> Option<&FormatError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<FormatError>())
+    }
+}
+
+/// The information in the header of an index file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// How the hits in the main part of the file are encoded.
+    pub format: PostingsFormat,
+
+    /// Offset of the document table from the beginning of the file.
+    pub documents_offset: u64,
+
+    /// Offset of the table of contents from the beginning of the file.
+    pub contents_offset: u64,
+
+    /// Total size of the file, in bytes.
+    pub file_len: u64,
+
+    /// Checksums of the three sections that follow the header.
+    pub main_crc: u32,
+    pub documents_crc: u32,
+    pub contents_crc: u32
+}
+
+impl Header {
+    /// Encode this header.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+        buf.extend_from_slice(MAGIC);
+        buf.write_u32::<LittleEndian>(VERSION)?;
+        buf.write_u32::<LittleEndian>(self.format.flag() as u32)?;
+        buf.write_u64::<LittleEndian>(self.documents_offset)?;
+        buf.write_u64::<LittleEndian>(self.contents_offset)?;
+        buf.write_u64::<LittleEndian>(self.file_len)?;
+        buf.write_u32::<LittleEndian>(self.main_crc)?;
+        buf.write_u32::<LittleEndian>(self.documents_crc)?;
+        buf.write_u32::<LittleEndian>(self.contents_crc)?;
+        let crc = crc32fast::hash(&buf);
+        buf.write_u32::<LittleEndian>(crc)?;
+        out.write_all(&buf)
+    }
+
+    /// Read and check the header of an index file.
+    ///
+    /// This checks the header's own checksum and that its offsets make sense,
+    /// but not the checksums of the other sections, since that would mean
+    /// reading the whole file.
+    pub fn read_from<R: Read>(f: &mut R) -> io::Result<Header> {
+        let mut buf = [0; HEADER_SIZE as usize];
+        match f.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof =>
+                return Err(FormatError::NotAnIndex.into()),
+            Err(err) => return Err(err)
+        }
+        if &buf[0..8] != MAGIC {
+            return Err(FormatError::NotAnIndex.into());
+        }
+        if crc32fast::hash(&buf[0..52]) != LittleEndian::read_u32(&buf[52..56]) {
+            return Err(FormatError::ChecksumMismatch(Section::Header).into());
+        }
+        let version = LittleEndian::read_u32(&buf[8..12]);
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version).into());
+        }
+        let flags = LittleEndian::read_u32(&buf[12..16]);
+        let header = Header {
+            format: PostingsFormat::from_flag(flags as u8)?,
+            documents_offset: LittleEndian::read_u64(&buf[16..24]),
+            contents_offset: LittleEndian::read_u64(&buf[24..32]),
+            file_len: LittleEndian::read_u64(&buf[32..40]),
+            main_crc: LittleEndian::read_u32(&buf[40..44]),
+            documents_crc: LittleEndian::read_u32(&buf[44..48]),
+            contents_crc: LittleEndian::read_u32(&buf[48..52])
+        };
+        if !(HEADER_SIZE <= header.documents_offset
+             && header.documents_offset <= header.contents_offset
+             && header.contents_offset <= header.file_len) {
+            return Err(FormatError::BadOffsets.into());
+        }
+        Ok(header)
+    }
+
+    /// Check that the file this header came from is `actual_len` bytes long.
+    pub fn check_len(&self, actual_len: u64) -> io::Result<()> {
+        if actual_len != self.file_len {
+            return Err(FormatError::WrongLength { expected: self.file_len, actual: actual_len }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Check that `data`, the whole of the given section, has the checksum
+/// `expected`.
+pub fn check_crc(data: &[u8], expected: u32, section: Section) -> io::Result<()> {
+    if crc32fast::hash(data) != expected {
+        return Err(FormatError::ChecksumMismatch(section).into());
+    }
+    Ok(())
+}
+
+/// A reader that computes a CRC-32 of all the data read through it.
+pub struct ChecksumReader<R> {
+    inner: R,
+    hasher: Hasher
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> ChecksumReader<R> {
+        ChecksumReader { inner, hasher: Hasher::new() }
+    }
+
+    /// Check that the data read since this reader was created, or since the
+    /// last call to `check`, has the checksum `expected`. Then start over
+    /// with a new checksum.
+    pub fn check(&mut self, expected: u32, section: Section) -> io::Result<()> {
+        let hasher = std::mem::take(&mut self.hasher);
+        if hasher.finalize() != expected {
+            return Err(FormatError::ChecksumMismatch(section).into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Header {
+        Header {
+            format: PostingsFormat::Compressed,
+            documents_offset: 100,
+            contents_offset: 150,
+            file_len: 200,
+            main_crc: 1,
+            documents_crc: 2,
+            contents_crc: 3
+        }
+    }
+
+    fn error_of(result: io::Result<Header>) -> FormatError {
+        FormatError::of(&result.unwrap_err()).unwrap().clone()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut buf = vec![];
+        sample().write_to(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, HEADER_SIZE);
+        assert_eq!(Header::read_from(&mut &buf[..]).unwrap(), sample());
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut buf = vec![];
+        sample().write_to(&mut buf).unwrap();
+
+        assert_eq!(error_of(Header::read_from(&mut &buf[..20])), FormatError::NotAnIndex);
+        assert_eq!(error_of(Header::read_from(&mut &b"this is just a text file, not an index at all........"[..])),
+                   FormatError::NotAnIndex);
+
+        let mut damaged = buf.clone();
+        damaged[30] ^= 1;
+        assert_eq!(error_of(Header::read_from(&mut &damaged[..])),
+                   FormatError::ChecksumMismatch(Section::Header));
+
+        let mut backwards = sample();
+        backwards.contents_offset = 50;
+        let mut buf = vec![];
+        backwards.write_to(&mut buf).unwrap();
+        assert_eq!(error_of(Header::read_from(&mut &buf[..])), FormatError::BadOffsets);
+
+        assert_eq!(FormatError::of(&sample().check_len(150).unwrap_err()),
+                   Some(&FormatError::WrongLength { expected: 200, actual: 150 }));
+    }
+
+    #[test]
+    fn old_files() {
+        // Before there was a header, files started with the offset of the
+        // table of contents, then the hits.
+        let mut old = vec![];
+        old.write_u64::<LittleEndian>(72).unwrap();
+        old.extend_from_slice(&[0; 64]);
+        let err = Header::read_from(&mut &old[..]).unwrap_err();
+        assert_eq!(FormatError::of(&err), Some(&FormatError::NotAnIndex));
+        assert!(err.to_string().contains("rebuild the index"));
+
+        let mut buf = vec![];
+        sample().write_to(&mut buf).unwrap();
+        LittleEndian::write_u32(&mut buf[8..12], 2);
+        let crc = crc32fast::hash(&buf[0..52]);
+        LittleEndian::write_u32(&mut buf[52..56], crc);
+        let err = Header::read_from(&mut &buf[..]).unwrap_err();
+        assert_eq!(FormatError::of(&err), Some(&FormatError::UnsupportedVersion(2)));
+        assert!(err.to_string().contains("rebuild the index"));
+    }
+
+    #[test]
+    fn checksum_reader() {
+        let data = b"hello, world";
+        let mut reader = ChecksumReader::new(&data[..]);
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf).unwrap();
+        reader.check(crc32fast::hash(b"hello"), Section::Main).unwrap();
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(reader.check(crc32fast::hash(b"hello, world"), Section::Documents).is_err());
+    }
+}
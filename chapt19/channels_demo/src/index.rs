@@ -5,14 +5,13 @@
 //! memory.
 
 use std::collections::HashMap;
-use byteorder::{LittleEndian, WriteBytesExt};
-
-/// Break a string into words.
-fn tokenize(text: &str) -> Vec<&str> {
-    text.split(|ch: char| !ch.is_alphanumeric())
-        .filter(|word| !word.is_empty())
-        .collect()
-}
+use std::collections::hash_map::Entry;
+use std::io;
+use std::mem::size_of;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crate::docs::DocumentInfo;
+use crate::field::{in_scope, next_field_start, Field, FIELD_BITS, MAX_POSITION};
+use crate::tokenize::Tokenizer;
 
 /// An in-memory index.
 ///
@@ -34,15 +33,29 @@ pub struct InMemoryIndex {
     /// document id in increasing order. This is handy for some algorithms you 
     /// might want to run on the index, so we preserve this property wherever
     /// possible.
-    pub map: HashMap<String, Vec<Hit>>
+    pub map: HashMap<String, Vec<Hit>>,
+
+    /// Information about each document in the index, sorted by document id
+    /// if the index is sorted by document id.
+    pub documents: Vec<DocumentInfo>,
+
+    /// Roughly how much heap memory `map` and `documents` are using, in bytes.
+    /// See `heap_size`.
+    heap_size: usize
 }
 
 /// A `Hit` indicates that a particular document contains some term, how many
 /// times it appears, and at what offsets (that is, the word count, from the
-/// beginning of the document, of each place where the term appears).
+/// beginning of the document, of each place where the term appears) and in
+/// which fields (see `field.rs`).
 ///
 /// The buffer contains all the hit data in binary form, little-endian. The
-/// first u32 of the data is the document id. The remaining [u32] are offsets.
+/// first u32 of the data is the document id. The second u32 is the number of
+/// offsets that follow, so that a reader can tell where one `Hit` ends and the
+/// next begins when they're stored back-to-back in an index file. The
+/// remaining [u32] are offsets, each shifted left `FIELD_BITS` bits, with the
+/// field's number in the low bits. (So a document can have at most 2^30
+/// words, counting the gaps between fields; see `field::MAX_POSITION`.)
 pub type Hit = Vec<u8>;
 
 impl InMemoryIndex {
@@ -50,38 +63,75 @@ impl InMemoryIndex {
     pub fn new() -> InMemoryIndex {
         InMemoryIndex {
             word_count: 0,
-            map: HashMap::new()
+            map: HashMap::new(),
+            documents: vec![],
+            heap_size: 0
         }
     }
 
     /// Index a single document.
     ///
+    /// `info` describes the file that the document came from; this fills in
+    /// its `id` and `length` and adds it to the new index's document table.
+    /// `fields` is the text of each field of the document, in the order of
+    /// `field::FIELDS` (see `field::document_fields`). `tokenizer` breaks the
+    /// text into terms.
+    ///
     /// The resulting index contains exactly one `Hit` per term.
-    pub fn from_single_document(document_id: usize, text: String) -> InMemoryIndex {
+    ///
+    /// A document too long for its word positions to fit in a `Hit` is an
+    /// error of kind `InvalidData`.
+    pub fn from_single_document(document_id: usize, mut info: DocumentInfo,
+                                fields: Vec<(Field, String)>, tokenizer: &dyn Tokenizer)
+        -> io::Result<InMemoryIndex>
+    {
         let document_id = document_id as u32;
         let mut index = InMemoryIndex::new();
+        let mut bytes = 0;
+        let mut length = 0;
 
-        let text = text.to_lowercase();
-        let tokens = tokenize(&text);
-        for (i, token) in tokens.iter().enumerate() {
-            let hits =
-                index.map
-                .entry(token.to_string())
-                .or_insert_with(|| {
-                    let mut hits = Vec::with_capacity(4 + 4);
-                    hits.write_u32::<LittleEndian>(document_id).unwrap();
-                    vec![hits]
-                });
-            hits[0].write_u32::<LittleEndian>(i as u32).unwrap();
-            index.word_count += 1;
+        let mut start: u32 = 0;
+        for (field, text) in fields {
+            bytes += text.len();
+            let counts_toward_length = in_scope(None, field);
+            let mut last = None;
+            for token in tokenizer.tokenize(&text) {
+                let position = match start.checked_add(token.position) {
+                    Some(position) if position <= MAX_POSITION => position,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "document is too long to index (more than {} words)", MAX_POSITION)))
+                };
+                let hits =
+                    index.map
+                    .entry(token.text)
+                    .or_insert_with(|| {
+                        let mut hits = Vec::with_capacity(4 + 4 + 4);
+                        hits.write_u32::<LittleEndian>(document_id).unwrap();
+                        hits.write_u32::<LittleEndian>(0).unwrap();
+                        vec![hits]
+                    });
+                let count = LittleEndian::read_u32(&hits[0][4..8]);
+                LittleEndian::write_u32(&mut hits[0][4..8], count + 1);
+                hits[0].write_u32::<LittleEndian>(position << FIELD_BITS | field as u32).unwrap();
+                index.word_count += 1;
+                length += counts_toward_length as u32;
+                last = Some(position);
+            }
+            if let Some(last) = last {
+                start = next_field_start(last);
+            }
         }
+        info.id = document_id;
+        info.length = length;
+        index.documents.push(info);
+        index.heap_size = index.measure_heap_size();
 
-        if document_id % 100 == 0 {
+        if document_id.is_multiple_of(100) {
             println!("indexed document {}, {} bytes, {} words", 
-                document_id, text.len(), index.word_count);
+                document_id, bytes, index.word_count);
         }
 
-        index
+        Ok(index)
     }
 
     /// Add all search hits from `other` to this index.
@@ -90,11 +140,35 @@ impl InMemoryIndex {
     /// ids in `other` are greater than every document id in `*self`, then
     /// `*self` remains sorted by document id after merging.
     pub fn merge(&mut self, other: InMemoryIndex) {
+        // Rather than measuring the whole index again, which would take time
+        // proportional to its size, keep track of how much each step adds.
+        let table_before = table_bytes(self.map.capacity());
         for (term, hits) in other.map {
-            self.map.entry(term)
-                .or_insert_with(|| vec![])
-                .extend(hits)
+            let hit_bytes: usize = hits.iter().map(|h| h.capacity()).sum();
+            match self.map.entry(term) {
+                Entry::Occupied(mut entry) => {
+                    let list = entry.get_mut();
+                    let capacity_before = list.capacity();
+                    list.extend(hits);
+                    self.heap_size += (list.capacity() - capacity_before) * size_of::<Hit>()
+                        + hit_bytes;
+                }
+                Entry::Vacant(entry) => {
+                    self.heap_size += entry.key().capacity()
+                        + hits.capacity() * size_of::<Hit>()
+                        + hit_bytes;
+                    entry.insert(hits);
+                }
+            }
         }
+        self.heap_size += table_bytes(self.map.capacity()) - table_before;
+
+        let documents_before = self.documents.capacity();
+        let path_bytes: usize = other.documents.iter().map(|d| d.path.capacity()).sum();
+        self.documents.extend(other.documents);
+        self.heap_size += (self.documents.capacity() - documents_before) * size_of::<DocumentInfo>()
+            + path_bytes;
+
         self.word_count += other.word_count;
     }
 
@@ -103,12 +177,102 @@ impl InMemoryIndex {
         self.word_count == 0
     }
 
-    /// True if this index is large enough that we should dump it to disk rather
-    /// than keep adding more data to it.
-    pub fn is_large(&self) -> bool {
-        // This depends on how much memory your computer has, of course.
-        const REASONABLE_SIZE: usize = 100_000_000;
-        self.word_count > REASONABLE_SIZE
+    /// Roughly how much heap memory this index is using, in bytes: the term
+    /// strings, the lists of hits and the hits themselves, the hash table,
+    /// and the document table. This doesn't count the allocator's own
+    /// overhead, so the real figure is somewhat higher.
+    ///
+    /// When this gets too big, it's time to dump the index to disk rather than
+    /// keep adding more data to it.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    /// Work out `heap_size` from scratch.
+    fn measure_heap_size(&self) -> usize {
+        let entries: usize = self.map.iter()
+            .map(|(term, hits)| {
+                term.capacity()
+                    + hits.capacity() * size_of::<Hit>()
+                    + hits.iter().map(|h| h.capacity()).sum::<usize>()
+            })
+            .sum();
+        let documents: usize = self.documents.iter().map(|d| d.path.capacity()).sum();
+        table_bytes(self.map.capacity())
+            + entries
+            + self.documents.capacity() * size_of::<DocumentInfo>()
+            + documents
+    }
+}
+
+/// The approximate size of the table behind a `HashMap<String, Vec<Hit>>`
+/// with the given capacity. The standard `HashMap` keeps its table at most
+/// 7/8 full, and stores one control byte per slot alongside the entries.
+fn table_bytes(capacity: usize) -> usize {
+    capacity * 8 / 7 * (size_of::<(String, Vec<Hit>)>() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+    use crate::tokenize::{Token, UnicodeTokenizer};
+
+    #[test]
+    fn heap_size_tracks_merges() {
+        let doc = |id, text: &str| {
+            InMemoryIndex::from_single_document(id, DocumentInfo::default(),
+                                                vec![(Field::Body, text.to_string())],
+                                                &UnicodeTokenizer).unwrap()
+        };
+        let mut index = InMemoryIndex::new();
+        assert_eq!(index.heap_size(), 0);
+        let mut last = 0;
+        for (id, text) in ["one fish two fish", "red fish blue fish", "one two three"]
+            .iter().enumerate()
+        {
+            index.merge(doc(id, text));
+            assert_eq!(index.heap_size(), index.measure_heap_size());
+            assert!(index.heap_size() > last);
+            last = index.heap_size();
+        }
+    }
+
+    /// Numbers the words of a text as if they were a long way apart.
+    struct Spread(u32);
+
+    impl Tokenizer for Spread {
+        fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+            UnicodeTokenizer.tokenize_spans(text).into_iter()
+                .map(|(token, span)| {
+                    (Token { position: token.position * self.0, ..token }, span)
+                })
+                .collect()
+        }
+
+        fn spec(&self) -> String {
+            "spread".to_string()
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let fields = |body: &str, title: &str| {
+            vec![(Field::Body, body.to_string()), (Field::Title, title.to_string())]
+        };
+        let index = |tokenizer: &dyn Tokenizer, fields| {
+            InMemoryIndex::from_single_document(0, DocumentInfo::default(), fields, tokenizer)
+        };
+
+        // The last word fits exactly.
+        let spread = Spread(MAX_POSITION / 2);
+        let hits = index(&spread, fields("a b c", "")).unwrap().map.remove("c").unwrap();
+        assert_eq!(LittleEndian::read_u32(&hits[0][8..]) >> FIELD_BITS, MAX_POSITION - 1);
+
+        let err = index(&spread, fields("a b c d", "")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(index(&spread, fields("a b c", "title")).is_err());
+        assert!(index(&Spread(u32::MAX / 2), fields("a b c", "")).is_err());
     }
 }
 
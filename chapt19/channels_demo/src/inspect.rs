@@ -0,0 +1,305 @@
+//! Looking inside index files: `fingertips inspect` and `fingertips verify`.
+//!
+//! `inspect` summarizes an index file: what's in the header, how many
+//! documents and terms it has, which terms are most common, and how much
+//! space each section takes.
+//!
+//! `verify` reads the whole file and checks everything the rest of this
+//! program takes for granted: the checksums, that every term's hits are in
+//! bounds and stored back-to-back in table-of-contents order (as
+//! `IndexFileReader` expects), that the terms are strictly sorted (as
+//! `IndexSearcher` expects), and that every hit decodes and refers to a
+//! document in the document table. Rather than stopping at the first problem,
+//! it reports as many as it can find.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use memmap2::Mmap;
+use crate::docs::{find_document, read_documents, DocumentTable};
+use crate::header::{check_crc, Header, Section, HEADER_SIZE, VERSION};
+use crate::postings::{Postings, PostingsFormat};
+use crate::read::IndexFileReader;
+
+/// The most problems `verify` reports before giving up on a file.
+const MAX_PROBLEMS: usize = 100;
+
+/// Map an index file into memory and read its header.
+fn map_index(filename: &Path) -> io::Result<(Mmap, Header)> {
+    let file = File::open(filename)?;
+
+    // Safety: as in `IndexSearcher::open`, this program never modifies an index
+    // file in place.
+    let map = unsafe { Mmap::map(&file)? };
+    let header = Header::read_from(&mut &map[..])?;
+    header.check_len(map.len() as u64)?;
+    Ok((map, header))
+}
+
+/// What `fingertips inspect` prints about an index file.
+pub struct Summary {
+    header: Header,
+    table: DocumentTable,
+
+    /// The number of terms in the table of contents.
+    terms: usize,
+
+    /// The total number of hits: the sum of every term's document frequency.
+    postings: u64,
+
+    /// The terms that appear in the most documents, most first, with the
+    /// number of documents.
+    top_terms: Vec<(String, u32)>
+}
+
+/// Summarize the index file `filename`, listing the `top` most common terms.
+///
+/// This checks the checksums of the document table and the table of
+/// contents, which it reads, but not of the main part of the file.
+pub fn inspect(filename: &Path, top: usize) -> io::Result<Summary> {
+    let (map, header) = map_index(filename)?;
+    let documents_buf = &map[header.documents_offset as usize..header.contents_offset as usize];
+    let mut contents_buf = &map[header.contents_offset as usize..];
+    check_crc(documents_buf, header.documents_crc, Section::Documents)?;
+    check_crc(contents_buf, header.contents_crc, Section::Contents)?;
+    let table = read_documents(&mut &documents_buf[..])?;
+
+    // Keep the `top` best (df, term) pairs seen so far in a min-heap, so
+    // that the worst of them is the one to drop. Ties go to the term that
+    // sorts first.
+    let mut best = BinaryHeap::new();
+    let mut terms = 0;
+    let mut postings = 0;
+    while let Some(entry) = IndexFileReader::read_entry(&mut contents_buf)? {
+        terms += 1;
+        postings += entry.df as u64;
+        best.push(Reverse((entry.df, Reverse(entry.term))));
+        if best.len() > top {
+            best.pop();
+        }
+    }
+    let top_terms = best.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((df, Reverse(term)))| (term, df))
+        .collect();
+
+    Ok(Summary { header, table, terms, postings, top_terms })
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        let format = match header.format {
+            PostingsFormat::Raw => "raw",
+            PostingsFormat::Compressed => "compressed"
+        };
+        let deleted = self.table.documents.iter().filter(|d| d.deleted).count();
+        let duplicates: usize = self.table.documents.iter().map(|d| d.duplicates.len()).sum();
+        writeln!(f, "format version:  {} ({} hits)", VERSION, format)?;
+        writeln!(f, "tokenizer:       {}", self.table.tokenizer)?;
+        writeln!(f, "encoding:        {}", self.table.encoding.name())?;
+        writeln!(f, "documents:       {} ({} deleted)", self.table.documents.len() - deleted, deleted)?;
+        writeln!(f, "duplicate files: {}", duplicates)?;
+        writeln!(f, "words:           {}", self.table.total_words)?;
+        writeln!(f, "terms:           {}", self.terms)?;
+        writeln!(f, "postings:        {}", self.postings)?;
+
+        writeln!(f, "size:")?;
+        let sections = [
+            ("header", HEADER_SIZE),
+            ("hits", header.documents_offset - HEADER_SIZE),
+            ("document table", header.contents_offset - header.documents_offset),
+            ("table of contents", header.file_len - header.contents_offset),
+            ("total", header.file_len)
+        ];
+        for (name, size) in sections {
+            let percent = if header.file_len == 0 { 0.0 } else {
+                size as f64 * 100.0 / header.file_len as f64
+            };
+            writeln!(f, "    {:<18} {:>14} bytes {:>6.1}%", name, size, percent)?;
+        }
+
+        if !self.top_terms.is_empty() {
+            writeln!(f, "most common terms:")?;
+            for (term, df) in &self.top_terms {
+                writeln!(f, "    {:<24} {:>10} documents", term, df)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the index file `filename` thoroughly, and return a description of
+/// each problem found. An empty list means the file is fine.
+///
+/// Returns an error only if the file can't be read, or its header is too
+/// damaged to find the rest of the file.
+pub fn verify(filename: &Path) -> io::Result<Vec<String>> {
+    let (map, header) = map_index(filename)?;
+    let mut problems = vec![];
+
+    let main_buf = &map[HEADER_SIZE as usize..header.documents_offset as usize];
+    let documents_buf = &map[header.documents_offset as usize..header.contents_offset as usize];
+    let mut contents_buf = &map[header.contents_offset as usize..];
+    let sections = [
+        (main_buf, header.main_crc, Section::Main),
+        (documents_buf, header.documents_crc, Section::Documents),
+        (contents_buf, header.contents_crc, Section::Contents)
+    ];
+    for (buf, crc, section) in sections {
+        if let Err(err) = check_crc(buf, crc, section) {
+            problems.push(err.to_string());
+        }
+    }
+
+    let documents = match read_documents(&mut &documents_buf[..]) {
+        Ok(table) => table.documents,
+        Err(err) => {
+            problems.push(format!("can't read the document table: {}", err));
+            vec![]
+        }
+    };
+    for pair in documents.windows(2) {
+        if pair[0].id >= pair[1].id {
+            problems.push(format!("document table is out of order at document {}", pair[1].id));
+        }
+    }
+
+    let mut expected_offset = HEADER_SIZE;
+    let mut previous: Option<String> = None;
+    loop {
+        if problems.len() >= MAX_PROBLEMS {
+            problems.push("too many problems; stopped checking".to_string());
+            return Ok(problems);
+        }
+        let entry = match IndexFileReader::read_entry(&mut contents_buf) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(err) => {
+                problems.push(format!("can't read the table of contents: {}", err));
+                return Ok(problems);
+            }
+        };
+        let term = &entry.term;
+
+        if let Some(previous) = &previous {
+            if term <= previous {
+                problems.push(format!("term {:?} comes after {:?} in the table of contents",
+                                      term, previous));
+            }
+        }
+
+        let end = entry.offset.saturating_add(entry.nbytes);
+        if entry.offset < HEADER_SIZE || end > header.documents_offset {
+            problems.push(format!("term {:?}: hits at bytes {}..{} are out of bounds",
+                                  term, entry.offset, end));
+            previous = Some(entry.term);
+            continue;
+        }
+        if entry.offset != expected_offset {
+            problems.push(format!("term {:?}: hits start at byte {}, not right after \
+                                   the previous term's at byte {}",
+                                  term, entry.offset, expected_offset));
+        }
+        expected_offset = end;
+
+        let hits = &map[entry.offset as usize..end as usize];
+        let mut count = 0;
+        let mut last_doc_id = None;
+        for posting in Postings::new(header.format, hits) {
+            let posting = match posting {
+                Ok(posting) => posting,
+                Err(err) => {
+                    problems.push(format!("term {:?}: {}", term, err));
+                    break;
+                }
+            };
+            count += 1;
+            if last_doc_id.is_some_and(|last| posting.doc_id <= last) {
+                problems.push(format!("term {:?}: hits are out of order at document {}",
+                                      term, posting.doc_id));
+            }
+            last_doc_id = Some(posting.doc_id);
+            if find_document(&documents, posting.doc_id).is_none() {
+                problems.push(format!("term {:?}: hit for document {}, which isn't in the \
+                                       document table", term, posting.doc_id));
+            }
+            if posting.offsets.windows(2).any(|pair| pair[0] >= pair[1]) {
+                problems.push(format!("term {:?}: offsets in document {} are out of order",
+                                      term, posting.doc_id));
+            }
+        }
+        if count != entry.df {
+            problems.push(format!("term {:?}: table of contents says {} documents, \
+                                   but there are hits for {}", term, entry.df, count));
+        }
+        previous = Some(entry.term);
+    }
+
+    if expected_offset != header.documents_offset {
+        problems.push(format!("{} bytes of hit data don't belong to any term",
+                              header.documents_offset.saturating_sub(expected_offset)));
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::docs::{DocumentInfo, Encoding};
+    use crate::postings::{Posting, PostingsEncoder};
+    use crate::tmp::TmpDir;
+    use crate::write::IndexFileWriter;
+
+    /// Write an index file with documents 0 and 1 and the given terms, each
+    /// with hits in the given documents. The table of contents is written
+    /// as given, even if it's out of order.
+    fn write_file(tmp_dir: &TmpDir, terms: &[(&str, &[u32])]) -> std::path::PathBuf {
+        let (filename, f) = tmp_dir.create().unwrap();
+        let mut writer = IndexFileWriter::new(f).unwrap();
+        let documents: Vec<DocumentInfo> = (0..2)
+            .map(|id| DocumentInfo { id, length: 5, ..DocumentInfo::default() })
+            .collect();
+        writer.write_documents("unicode", Encoding::Utf8, &documents);
+        for &(term, doc_ids) in terms {
+            let mut encoder = PostingsEncoder::new(writer.format());
+            for &doc_id in doc_ids {
+                encoder.push(&Posting::body(doc_id, vec![0, 3]));
+            }
+            let start = writer.offset();
+            writer.write_main(&encoder.finish()).unwrap();
+            writer.write_contents_entry(term.to_string(), doc_ids.len() as u32,
+                                        start, writer.offset() - start);
+        }
+        writer.finish().unwrap();
+        filename
+    }
+
+    #[test]
+    fn inspect_and_verify() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+
+        let good = write_file(&tmp_dir, &[("a", &[0, 1]), ("b", &[1]), ("c", &[0, 1])]);
+        let summary = inspect(&good, 2).unwrap();
+        assert_eq!(summary.terms, 3);
+        assert_eq!(summary.postings, 5);
+        assert_eq!(summary.top_terms, vec![("a".to_string(), 2), ("c".to_string(), 2)]);
+        assert_eq!(verify(&good).unwrap(), Vec::<String>::new());
+
+        let bad = write_file(&tmp_dir, &[("b", &[0]), ("a", &[0, 7])]);
+        let problems = verify(&bad).unwrap();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("comes after"));
+        assert!(problems[1].contains("document 7"));
+
+        let mut damaged = fs::read(&good).unwrap();
+        damaged[HEADER_SIZE as usize] ^= 1;
+        fs::write(&good, damaged).unwrap();
+        let problems = verify(&good).unwrap();
+        assert!(problems[0].contains("checksum mismatch in index file index data"));
+    }
+}
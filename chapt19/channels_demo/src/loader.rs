@@ -0,0 +1,381 @@
+//! Turning the bytes of a file into the text to index.
+//!
+//! Most files are plain text, and only need decoding (see `Encoding`). Some
+//! need more work first, so loading is pluggable: anything that implements
+//! `Loader` can claim a file, by its name or by the first few bytes of its
+//! contents, and produce its text. This module provides:
+//!
+//! * `gzip`, for files ending in `.gz` or starting with the gzip magic
+//!   number. It decompresses the file and then loads what's inside as if it
+//!   were a file named without the `.gz`, so `notes.md.gz` is Markdown.
+//!   What's inside may be gzipped again, but only once more, and no layer
+//!   may decompress to more than `MAX_DECOMPRESSED` bytes: a small file can
+//!   decompress to something enormous, or even to itself.
+//!
+//! * `html`, for `.html` and `.htm` files, and files that start with
+//!   `<!DOCTYPE html` or `<html`. It drops tags, comments, scripts, and
+//!   style sheets, and decodes character references like `&amp;`.
+//!
+//! * `markdown`, for `.md` and `.markdown` files. It keeps the text and
+//!   drops the markup: `**bold**` is indexed as `bold`, and a link as its
+//!   text, not its URL.
+//!
+//! Files that no loader claims are loaded as plain text.
+//!
+//! Besides the text, HTML and Markdown files have a title: the `<title>` or
+//! first heading of an HTML page, or the first heading of a Markdown file.
+//! It's indexed as a separate field (see `field.rs`). Plain text has no
+//! title.
+//!
+//! The same loaders run again when a search shows a snippet of a file (see
+//! `snippet.rs`), so word positions line up with the text that was indexed.
+
+use std::io::{self, Read};
+use std::path::Path;
+use flate2::read::MultiGzDecoder;
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use crate::docs::Encoding;
+
+/// The text of a file, ready to index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadedText {
+    pub text: String,
+
+    /// The document's title, if it has one, with runs of whitespace turned
+    /// into single spaces.
+    pub title: Option<String>
+}
+
+impl LoadedText {
+    /// Text with no title.
+    fn plain(text: String) -> LoadedText {
+        LoadedText { text, title: None }
+    }
+}
+
+/// Tidy up the text of a title, and return it if it's not empty.
+fn make_title(text: &str) -> Option<String> {
+    let title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+/// A way of turning one kind of file into text.
+pub trait Loader: Send + Sync {
+    /// A short name for this kind of file, for error messages.
+    fn name(&self) -> &'static str;
+
+    /// True if this loader should handle the file at `path`, whose contents
+    /// start with `head`.
+    fn detect(&self, path: &Path, head: &[u8]) -> bool;
+
+    /// Turn the contents of the file at `path` into text.
+    fn load(&self, path: &Path, bytes: Vec<u8>, encoding: Encoding) -> io::Result<LoadedText>;
+}
+
+/// All the loaders, in the order they're tried.
+const LOADERS: &[&dyn Loader] = &[&Gzip, &Html, &Markdown];
+
+/// Turn `bytes`, the contents of the file at `path`, into text, using the
+/// first loader that claims the file, or just decoding it as `encoding` if
+/// none does.
+pub fn load_text(path: &Path, bytes: Vec<u8>, encoding: Encoding) -> io::Result<LoadedText> {
+    match LOADERS.iter().find(|loader| loader.detect(path, &bytes)) {
+        Some(loader) => loader.load(path, bytes, encoding).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {}", loader.name(), err))
+        }),
+        None => encoding.decode(bytes).map(LoadedText::plain)
+    }
+}
+
+/// True if `path` has one of the given extensions, ignoring case.
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// The most that one layer of a gzipped file may decompress to, in bytes.
+const MAX_DECOMPRESSED: u64 = 1 << 30;
+
+/// How many times over a file may be gzipped.
+const MAX_GZIP_LAYERS: usize = 2;
+
+/// Gzip-compressed files.
+pub struct Gzip;
+
+impl Loader for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn detect(&self, path: &Path, head: &[u8]) -> bool {
+        has_extension(path, &["gz"]) || head.starts_with(&[0x1f, 0x8b])
+    }
+
+    fn load(&self, path: &Path, mut bytes: Vec<u8>, encoding: Encoding) -> io::Result<LoadedText> {
+        let mut path = path.to_owned();
+        for _ in 0..MAX_GZIP_LAYERS {
+            bytes = gunzip(&bytes, MAX_DECOMPRESSED)?;
+            if has_extension(&path, &["gz"]) {
+                path = path.with_extension("");
+            }
+            if !self.detect(&path, &bytes) {
+                return load_text(&path, bytes, encoding);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData,
+                           format!("gzipped more than {} times over", MAX_GZIP_LAYERS)))
+    }
+}
+
+/// Decompress `bytes`, unless that would make more than `limit` bytes.
+fn gunzip(bytes: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    MultiGzDecoder::new(bytes).take(limit + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("decompresses to more than {} bytes", limit)));
+    }
+    Ok(out)
+}
+
+/// HTML documents.
+pub struct Html;
+
+impl Loader for Html {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
+    fn detect(&self, path: &Path, head: &[u8]) -> bool {
+        if has_extension(path, &["html", "htm"]) {
+            return true;
+        }
+        let head = head.strip_prefix("\u{feff}".as_bytes()).unwrap_or(head);
+        let start = head.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(head.len());
+        let head = &head[start..];
+        [&b"<!doctype html"[..], b"<html"].iter().any(|prefix| {
+            head.len() >= prefix.len() && head[..prefix.len()].eq_ignore_ascii_case(prefix)
+        })
+    }
+
+    fn load(&self, _path: &Path, bytes: Vec<u8>, encoding: Encoding) -> io::Result<LoadedText> {
+        Ok(html_to_text(&encoding.decode(bytes)?))
+    }
+}
+
+/// Tags that don't separate words: `<b>bold</b>face` is one word.
+const INLINE_TAGS: &[&str] = &[
+    "a", "abbr", "b", "cite", "code", "em", "i", "kbd", "mark", "q", "s",
+    "samp", "small", "span", "strong", "sub", "sup", "u", "var"
+];
+
+/// Tags whose text can be a document's title.
+const TITLE_TAGS: &[&str] = &["title", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// The text of an HTML document. Other tags are replaced with a space, so
+/// that words in separate paragraphs or table cells don't run together. The
+/// title is the text of the first `TITLE_TAGS` element that has any.
+fn html_to_text(html: &str) -> LoadedText {
+    let mut out = String::with_capacity(html.len());
+    let mut title = None;
+    let mut in_title: Option<(String, usize)> = None;
+    let mut rest = html;
+    while let Some(i) = rest.find(['<', '&']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+        } else if rest.starts_with('&') {
+            let (ch, len) = decode_reference(rest).unwrap_or(('&', 1));
+            out.push(ch);
+            rest = &rest[len..];
+        } else if rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || "/!?".contains(c)) {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[1..end];
+            rest = &rest[end..];
+            let name: String = tag.trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .map(|c| c.to_ascii_lowercase())
+                .collect();
+            if !INLINE_TAGS.contains(&name.as_str()) {
+                out.push(' ');
+            }
+            if title.is_none() && TITLE_TAGS.contains(&name.as_str()) {
+                if !tag.starts_with('/') {
+                    in_title = Some((name.clone(), out.len()));
+                } else if let Some((_, start)) = in_title.take_if(|(open, _)| *open == name) {
+                    title = make_title(&out[start..]);
+                }
+            }
+            if !tag.starts_with('/') && (name == "script" || name == "style") {
+                let close = format!("</{}", name);
+                let end = rest.as_bytes()
+                    .windows(close.len())
+                    .position(|w| w.eq_ignore_ascii_case(close.as_bytes()))
+                    .unwrap_or(rest.len());
+                rest = &rest[end..];
+            }
+        } else {
+            // A `<` that doesn't start a tag, as in `a < b`.
+            out.push('<');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    LoadedText { text: out, title }
+}
+
+/// Decode the character reference at the start of `text`, like `&amp;` or
+/// `&#233;`. Returns the character and the length of the reference, or
+/// `None` if it isn't one we know.
+fn decode_reference(text: &str) -> Option<(char, usize)> {
+    let end = text.bytes().take(12).position(|b| b == b';')?;
+    let name = &text[1..end];
+    let ch = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((ch, end + 1))
+}
+
+/// Markdown documents.
+pub struct Markdown;
+
+impl Loader for Markdown {
+    fn name(&self) -> &'static str {
+        "markdown"
+    }
+
+    fn detect(&self, path: &Path, _head: &[u8]) -> bool {
+        has_extension(path, &["md", "markdown"])
+    }
+
+    fn load(&self, _path: &Path, bytes: Vec<u8>, encoding: Encoding) -> io::Result<LoadedText> {
+        Ok(markdown_to_text(&encoding.decode(bytes)?))
+    }
+}
+
+/// The text of a Markdown document, without the markup. Raw HTML in the
+/// document is stripped as in `html_to_text`. The title is the text of the
+/// first heading.
+fn markdown_to_text(markdown: &str) -> LoadedText {
+    let mut out = String::with_capacity(markdown.len());
+    let mut title = None;
+    let mut title_start = None;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Heading { .. }) if title.is_none() => title_start = Some(out.len()),
+            Event::End(TagEnd::Heading(_)) if title_start.is_some() => {
+                title = title_start.take().and_then(|start| make_title(&out[start..]));
+                out.push('\n');
+            }
+            Event::Text(text) | Event::Code(text) => out.push_str(&text),
+            Event::Html(html) | Event::InlineHtml(html) => out.push_str(&html_to_text(&html).text),
+            Event::SoftBreak | Event::HardBreak | Event::Rule => out.push('\n'),
+            Event::End(TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough
+                       | TagEnd::Link | TagEnd::Image) => {}
+            Event::End(_) => out.push('\n'),
+            _ => {}
+        }
+    }
+    LoadedText { text: out, title }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn load(path: &str, bytes: &[u8]) -> io::Result<String> {
+        Ok(load_text(Path::new(path), bytes.to_vec(), Encoding::Utf8)?.text)
+    }
+
+    fn title(path: &str, bytes: &[u8]) -> Option<String> {
+        load_text(Path::new(path), bytes.to_vec(), Encoding::Utf8).unwrap().title
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn words(text: &str) -> Vec<&str> {
+        text.split_whitespace().collect()
+    }
+
+    #[test]
+    fn html() {
+        let page = "<!DOCTYPE html>\n<title>Caf&eacute; &amp; <b>B</b>ar</title>\
+                    <script>var x = \"<p>hidden</p>\";</SCRIPT><style>p { }</style>\
+                    <p>one<p>two &lt; three&#x21; <!-- not <p>this --> 4 < 5</p>";
+        assert_eq!(words(&load("page.txt", page.as_bytes()).unwrap()),
+                   vec!["Caf&eacute;", "&", "Bar", "one", "two", "<", "three!", "4", "<", "5"]);
+        assert_eq!(load("notes.txt", b"a <b>plain</b> file").unwrap(), "a <b>plain</b> file");
+        assert_eq!(words(&load("notes.HTM", b"a <b>plain</b> file").unwrap()),
+                   vec!["a", "plain", "file"]);
+
+        assert_eq!(title("page.txt", page.as_bytes()).as_deref(), Some("Caf&eacute; & Bar"));
+        assert_eq!(title("notes.txt", b"<h1>not HTML</h1>"), None);
+        assert_eq!(title("page.html", b"<title> </title><p>x<H2>The\n<i>Gap</i>Buffer</h2>\
+                                        <h1>later</h1>").as_deref(),
+                   Some("The GapBuffer"));
+    }
+
+    #[test]
+    fn markdown() {
+        let doc = "# Borrow *and* BorrowMut\n\nSee [the docs](http://example.com/) \
+                   and `Borrow<T>`.\n\n* one\n* two<br>three\n";
+        assert_eq!(words(&load("notes.md", doc.as_bytes()).unwrap()),
+                   vec!["Borrow", "and", "BorrowMut", "See", "the", "docs", "and", "Borrow<T>.",
+                        "one", "two", "three"]);
+        assert_eq!(title("notes.md", doc.as_bytes()).as_deref(), Some("Borrow and BorrowMut"));
+        assert_eq!(title("notes.md", b"Intro\n\n## `GapBuffer`\n\n# Later").as_deref(),
+                   Some("GapBuffer"));
+        assert_eq!(title("notes.md", b"no headings"), None);
+    }
+
+    #[test]
+    fn gzipped() {
+        assert_eq!(load("log.gz", &gzip(b"hello\nworld\n")).unwrap(), "hello\nworld\n");
+        assert_eq!(words(&load("notes.md.gz", &gzip(b"**bold**")).unwrap()), vec!["bold"]);
+        assert_eq!(title("notes.md.gz", &gzip(b"# Title")).as_deref(), Some("Title"));
+        assert_eq!(words(&load("sniffed", &gzip(b"<html><p>hi")).unwrap()), vec!["hi"]);
+
+        let mut two = gzip(b"first ");
+        two.extend(gzip(b"second"));
+        assert_eq!(load("two.gz", &two).unwrap(), "first second");
+
+        let err = load("bad.gz", b"not gzip").unwrap_err();
+        assert!(err.to_string().starts_with("gzip: "), "{}", err);
+        assert!(load("latin1.gz", &gzip(b"caf\xe9")).is_err());
+    }
+
+    #[test]
+    fn gzip_limits() {
+        assert_eq!(load("twice.gz", &gzip(&gzip(b"hello"))).unwrap(), "hello");
+        assert_eq!(load("twice.txt.gz.gz", &gzip(&gzip(b"hello"))).unwrap(), "hello");
+        let err = load("thrice.gz", &gzip(&gzip(&gzip(b"hello")))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let zeros = gzip(&[0; 1 << 16]);
+        assert_eq!(gunzip(&zeros, 1 << 16).unwrap().len(), 1 << 16);
+        let err = gunzip(&zeros, 1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
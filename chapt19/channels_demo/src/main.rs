@@ -8,61 +8,132 @@
 ///     the most straightforward possible way.
 ///
 /// * Then, we break the work into a five-stage pipeline so that we can run
-///   it on multiple CPUs. `run_pipeline` puts the five stages together.
+///   it on multiple CPUs. `run_pipeline` puts the five stages together. The
+///   slowest stage, breaking documents into terms, runs on several threads.
 ///
 /// The `main` function at the end handles command-line arguments. It calls one
-/// of the two functions above to do the work.
-
+/// of the two functions above to do the work; or, for `fingertips search`,
+/// looks terms up in an index that was built earlier (see the `query` and
+/// `rank` modules); or, for `fingertips merge`, combines index files that
+/// were built separately.
 mod index;
 mod read;
 mod write;
 mod merge;
 mod tmp;
+mod mmap;
+mod query;
+mod docs;
+mod postings;
+mod rank;
+mod update;
+mod header;
+mod walk;
+mod tokenize;
+mod size;
+mod reorder;
+mod channel;
+mod error;
+mod terms;
+mod snippet;
+mod inspect;
+mod search;
+mod serve;
+mod loader;
+mod dedup;
+mod field;
 
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+use std::mem::size_of;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
-use std::thread::{spawn, JoinHandle};
-use argparse::{ArgumentParser, StoreTrue, Collect};
+use std::process::exit;
+use std::sync::Arc;
+use std::thread::{self, spawn, JoinHandle};
+use argparse::{ArgumentParser, StoreTrue, StoreFalse, Store, StoreOption, Collect};
 
+use crate::docs::{DocumentInfo, Encoding};
+use crate::field::document_fields;
+use crate::loader::LoadedText;
 use crate::index::InMemoryIndex;
 use crate::write::write_index_to_tmp_file;
-use crate::merge::FileMerge;
+use crate::merge::{combine, FileMerge, DEFAULT_FAN_IN, MERGED_FILENAME};
 use crate::tmp::TmpDir;
+use crate::mmap::IndexSearcher;
+use crate::rank::Bm25;
+use crate::snippet::Highlight;
+use crate::search::search;
+use crate::serve::serve;
+use crate::inspect::{inspect, verify};
+use crate::update::plan_update;
+use crate::dedup::Deduplicator;
+use crate::walk::{expand_paths, Pattern, WalkOptions};
+use crate::tokenize::{Tokenizer, DEFAULT_SPEC};
+use crate::size::ByteSize;
+use crate::reorder::{InOrder, Window};
+use crate::channel::{bounded, ChannelStats, Receiver, Weight};
+use crate::error::{Context, IndexError, Stage};
 
-/// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
-fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf)
-    -> io::Result<()>
+/// Create an inverted index for the given list of `documents`.
+///
+/// The documents are numbered starting at `first_doc_id`, and broken into
+/// terms using `tokenizer`. `merge` is where the index files go; normally
+/// it's a new `FileMerge`, but when updating an existing index, it already
+/// contains the old index. Temporary files go in `tmp_dir`.
+///
+/// Documents that `dedup` finds are copies of earlier ones aren't indexed
+/// or numbered; they're listed in the document table as duplicates.
+///
+/// Returns the number of documents skipped because of errors, which is
+/// always 0 unless `options.keep_going` is set.
+fn run_single_threaded(documents: Vec<PathBuf>, first_doc_id: usize,
+                       tokenizer: Arc<dyn Tokenizer>, mut dedup: Deduplicator,
+                       mut merge: FileMerge, tmp_dir: Arc<TmpDir>, options: &IndexOptions)
+    -> Result<usize, IndexError>
 {
-    // If all the documents fit comfortably in memory, we'll create the whole
-    // index in memory.
+    // If all the documents fit comfortably in `options.memory_limit`, we'll
+    // create the whole index in memory.
     let mut accumulated_index = InMemoryIndex::new();
     
     // If not, then as memory fills up, we'll write largeish temporary index
     // files to disk, saving the temporary filenames in `merge` so that later we
     // can merge them all into a single huge file.
-    let mut merge = FileMerge::new(&output_dir);
-
-    // A tool for generating temporary filenames.
-    let mut tmp_dir = TmpDir::new(&output_dir);
 
     // For each document in the set...
-    for (doc_id, filename) in documents.into_iter().enumerate() {
+    let mut doc_id = first_doc_id;
+    let mut skipped = 0;
+    for filename in documents {
         // ...load it into memory...
-        let mut f = File::open(filename)?;
-        let mut text = String::new();
-        f.read_to_string(&mut text)?;
+        let (info, text) = match load_document(filename, options.encoding()) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                skip(err, options.keep_going)?;
+                skipped += 1;
+                continue;
+            }
+        };
+        if dedup.is_duplicate(doc_id, &info, &text.text, &*tokenizer) {
+            continue;
+        }
 
         // ...and add its contents to the in-memory `accumulated_index`.
-        let index = InMemoryIndex::from_single_document(doc_id, text);
-        accumulated_index.merge(index);
-        if accumulated_index.is_large() {
-            // To avoid running out of memory, dump `accumulated_index` to disk.
-            let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-            merge.add_file(file)?;
+        match index_document(doc_id, info, text, &*tokenizer) {
+            Ok(index) => accumulated_index.merge(index),
+            Err(err) => {
+                skip(err, options.keep_going)?;
+                skipped += 1;
+            }
+        }
+        doc_id += 1;
+        if accumulated_index.heap_size() >= options.memory_limit {
+            // To stay within the budget, dump `accumulated_index` to disk.
+            let file = write_index_to_tmp_file(accumulated_index, &tokenizer.spec(),
+                                               options.encoding(), &tmp_dir)
+                .context(Stage::Write)?;
+            merge.add_file(file).context(Stage::FileMerge)?;
             accumulated_index = InMemoryIndex::new();
         }
     }
@@ -70,89 +141,256 @@ fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf)
     // Done reading documents! Save the last data set to disk, then merge the 
     // temporary index files if there are more than one.
     if !accumulated_index.is_empty() {
-        let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-        merge.add_file(file)?;
+        let file = write_index_to_tmp_file(accumulated_index, &tokenizer.spec(),
+                                           options.encoding(), &tmp_dir)
+            .context(Stage::Write)?;
+        merge.add_file(file).context(Stage::FileMerge)?;
+    }
+    add_duplicates(dedup, &tokenizer.spec(), options.encoding(), &tmp_dir, &mut merge)?;
+    merge.finish().context(Stage::FileMerge)?;
+    Ok(skipped)
+}
+
+/// Save the duplicates that `dedup` found to a temporary file and add it to
+/// `merge`, so that they end up in the document table.
+fn add_duplicates(dedup: Deduplicator, tokenizer_spec: &str, encoding: Encoding,
+                  tmp_dir: &TmpDir, merge: &mut FileMerge) -> Result<(), IndexError> {
+    let count = dedup.count();
+    if count > 0 {
+        println!("{} files are copies of other files and were not indexed separately", count);
+        add_records(dedup.into_records(), tokenizer_spec, encoding, tmp_dir, merge)?;
+    }
+    Ok(())
+}
+
+/// Add a temporary file to `merge` that has the document records
+/// `documents` and no hits. This is how tombstones and duplicates get into
+/// the document table.
+fn add_records(documents: Vec<DocumentInfo>, tokenizer_spec: &str, encoding: Encoding,
+               tmp_dir: &TmpDir, merge: &mut FileMerge) -> Result<(), IndexError> {
+    let mut index = InMemoryIndex::new();
+    index.documents = documents;
+    let file = write_index_to_tmp_file(index, tokenizer_spec, encoding, tmp_dir)
+        .context(Stage::Write)?;
+    merge.add_file(file).context(Stage::FileMerge)
+}
+
+/// Load the document at `path` into memory.
+fn load_document(path: PathBuf, encoding: Encoding)
+    -> Result<(DocumentInfo, LoadedText), IndexError>
+{
+    DocumentInfo::load(path.clone(), encoding).file_context(Stage::Read, &path)
+}
+
+/// Make an in-memory index of a single document, with its fields (see
+/// `field.rs`). A bug in a tokenizer shouldn't take down the whole run
+/// without saying which document set it off, so this catches panics and
+/// turns them into errors.
+fn index_document(doc_id: usize, info: DocumentInfo, text: LoadedText, tokenizer: &dyn Tokenizer)
+    -> Result<InMemoryIndex, IndexError>
+{
+    let path = info.path.clone();
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let fields = document_fields(&info.path, text.text, text.title);
+        InMemoryIndex::from_single_document(doc_id, info, fields, tokenizer)
+    })).map_err(|payload| IndexError::panic(Stage::Index, Some(path.clone()), payload))?
+        .file_context(Stage::Index, &path)
+}
+
+/// Deal with an error in a single document: with `--keep-going`, report it
+/// and carry on; otherwise, give up.
+fn skip(err: IndexError, keep_going: bool) -> Result<(), IndexError> {
+    if keep_going {
+        eprintln!("skipping: {}", err);
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Wait for a pipeline thread to exit, turning a panic into an error.
+fn join<T>(handle: JoinHandle<T>, stage: Stage) -> Result<T, IndexError> {
+    handle.join().map_err(|payload| IndexError::panic(stage, None, payload))
+}
+
+/// A document loaded into memory: its document id, a description of the
+/// file, and the text.
+type LoadedDocument = (usize, DocumentInfo, LoadedText);
+
+impl Weight for LoadedDocument {
+    fn weight(&self) -> usize {
+        size_of::<LoadedDocument>() + self.1.path.capacity() + self.2.text.capacity()
+            + self.2.title.as_ref().map_or(0, |title| title.capacity())
+    }
+}
+
+impl Weight for InMemoryIndex {
+    fn weight(&self) -> usize {
+        size_of::<InMemoryIndex>() + self.heap_size()
+    }
+}
+
+/// The index of a single document, with its document id.
+type IndexedDocument = (usize, InMemoryIndex);
+
+impl Weight for IndexedDocument {
+    fn weight(&self) -> usize {
+        self.1.weight()
+    }
+}
+
+impl Weight for PathBuf {
+    fn weight(&self) -> usize {
+        size_of::<PathBuf>() + self.capacity()
     }
-    merge.finish()
 }
 
+/// What the file reader thread returns: the number of files skipped because
+/// of errors, and the `Deduplicator` with the copies it found.
+type ReaderResult = Result<(usize, Deduplicator), IndexError>;
+
 /// Start a thread that loads documents from the filesystem into memory.
 ///
-/// `documents` is a list of filenames to load.
+/// `documents` is a list of filenames to load, decoding them as `encoding`.
+/// The documents are numbered in order, starting at `first_doc_id`. With
+/// `keep_going`, files that can't be loaded are reported and skipped, and
+/// don't get a number. Files that `dedup` finds are copies of earlier ones
+/// (using `tokenizer`, when looking for near-duplicates) are skipped too.
 ///
 /// This returns a pair of values: a receiver that receives the documents, as
-/// Strings; and a `JoinHandle` that can be used to wait for this thread to
-/// exit and to get the `io::Error` value if anything goes wrong.
-fn start_file_reader_thread(documents: Vec<PathBuf>)
-    -> (Receiver<String>, JoinHandle<io::Result<()>>)
+/// Strings, each paired with its number and a `DocumentInfo` describing the
+/// file it came from; and a `JoinHandle` that can be used to wait for this
+/// thread to exit and to get the number of files skipped and `dedup`, with
+/// the copies it found, or the error if anything goes wrong. The channel
+/// holds up to `queue_size` bytes of documents; when it's full, this thread
+/// waits for the indexing threads to catch up.
+fn start_file_reader_thread(documents: Vec<PathBuf>, first_doc_id: usize,
+                            mut dedup: Deduplicator, tokenizer: Arc<dyn Tokenizer>,
+                            encoding: Encoding, keep_going: bool, queue_size: usize)
+    -> (Receiver<LoadedDocument>, JoinHandle<ReaderResult>)
 {
-    let (sender, receiver) = channel();
+    let (sender, receiver) = bounded("documents", queue_size);
 
     let handle = spawn(move || {
+        let mut doc_id = first_doc_id;
+        let mut skipped = 0;
         for filename in documents {
-            let mut f = File::open(filename)?;
-            let mut text = String::new();
-            f.read_to_string(&mut text)?;
+            let (info, text) = match load_document(filename, encoding) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    skip(err, keep_going)?;
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if dedup.is_duplicate(doc_id, &info, &text.text, &*tokenizer) {
+                continue;
+            }
 
-            if sender.send(text).is_err() {
+            if sender.send((doc_id, info, text)).is_err() {
                 break;
             }
+            doc_id += 1;
         }
-        Ok(())
+        Ok((skipped, dedup))
     });
 
     (receiver, handle)
 }
 
-/// Start a thread that tokenizes each text and converts it into an in-memory
-/// index. (We assume that every document fits comfortably in memory.)
+/// Start `jobs` threads that tokenize each text and convert it into an
+/// in-memory index. (We assume that every document fits comfortably in
+/// memory.)
 ///
-/// `texts` is the stream of documents from the file reader thread.
+/// `texts` is the stream of documents from the file reader thread. Each
+/// thread takes the next document from the stream when it's ready for more
+/// work, so the indexes come out roughly in order by document id, but not
+/// exactly; each is sent along with its document id, so that the next stage
+/// can put them back in order. So that one slow document can't make the
+/// indexes after it pile up there, each thread waits for room in `window`
+/// before starting on a document.
 ///
-/// This assigns each document a number. It returns a pair of values: a
-/// receiver, the sequence of in-memory indexes; and a `JoinHandle` that can be
-/// used to wait for this thread to exit. This stage of the pipeline is
-/// infallible (it performs no I/O, so there are no possible errors).
-fn start_file_indexing_thread(texts: Receiver<String>)
-    -> (Receiver<InMemoryIndex>, JoinHandle<()>)
+/// This returns a pair of values: a receiver, the sequence of in-memory
+/// indexes; and a `JoinHandle` for each thread, which can be used to wait
+/// for it to exit. This stage performs no I/O, so the only thing that can go
+/// wrong is a panic in the tokenizer. With `keep_going`, a document that
+/// causes one is reported and skipped, and the thread returns the number of
+/// documents skipped; otherwise the thread stops and returns the error.
+fn start_file_indexing_threads(texts: Receiver<LoadedDocument>,
+                               tokenizer: Arc<dyn Tokenizer>, jobs: usize,
+                               window: Arc<Window>, keep_going: bool, queue_size: usize)
+    -> (Receiver<IndexedDocument>, Vec<JoinHandle<Result<usize, IndexError>>>)
 {
-    let (sender, receiver) = channel();
+    let (sender, receiver) = bounded("file indexes", queue_size);
 
-    let handle = spawn(move || {
-        for (doc_id, text) in texts.into_iter().enumerate() {
-            let index = InMemoryIndex::from_single_document(doc_id, text);
-            if sender.send(index).is_err() {
-                break;
+    let handles = (0..jobs).map(|_| {
+        let texts = texts.clone();
+        let sender = sender.clone();
+        let tokenizer = tokenizer.clone();
+        let window = window.clone();
+        spawn(move || {
+            let mut skipped = 0;
+            for (doc_id, info, text) in texts {
+                window.wait_for_room(doc_id);
+
+                // A skipped document still sends an (empty) index, so that
+                // the next stage isn't left waiting for this document id.
+                let index = match index_document(doc_id, info, text, &*tokenizer) {
+                    Ok(index) => index,
+                    Err(err) => {
+                        if let Err(err) = skip(err, keep_going) {
+                            // The next stage will never get this document,
+                            // so there's no point holding anyone back for it.
+                            window.close();
+                            return Err(err);
+                        }
+                        skipped += 1;
+                        InMemoryIndex::new()
+                    }
+                };
+                if sender.send((doc_id, index)).is_err() {
+                    break;
+                }
             }
-        }
-    });
+            Ok(skipped)
+        })
+    }).collect();
 
-    (receiver, handle)
+    (receiver, handles)
 }
 
 /// Start a thread that merges in-memory indexes.
 ///
-/// `file_indexes` receives a stream of indexes from the file indexing thread.
-/// These indexes typically vary a lot in size, since the input documents will
-/// typically be all different sizes.
+/// `file_indexes` receives a stream of indexes from the file indexing
+/// threads, each paired with its document id. These indexes typically vary a
+/// lot in size, since the input documents will typically be all different
+/// sizes. They may also arrive a little out of order; this thread merges them
+/// in order by document id, starting at `first_doc_id`, so that the merged
+/// indexes are sorted by document id, exactly as if one thread had done all
+/// the work. It keeps `window` up to date, so that the indexing threads
+/// don't get too far ahead.
 ///
 /// The thread created by this function merges those indexes into "large"
-/// indexes and passes these large indexes on to a new channel.
+/// indexes, using about `memory_limit` bytes each, and passes these large
+/// indexes on to a new channel, which holds up to `queue_size` bytes of
+/// indexes (but always at least one).
 ///
 /// This returns a pair: a receiver, the sequence of large indexes produced by
 /// merging the input indexes; and a `JoinHandle` that can be used to wait for
 /// this thread to exit. This stage of the pipeline is infallible (it performs
 /// no I/O).
-fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>)
+fn start_in_memory_merge_thread(file_indexes: Receiver<IndexedDocument>,
+                                first_doc_id: usize, window: Arc<Window>,
+                                memory_limit: usize, queue_size: usize)
     -> (Receiver<InMemoryIndex>, JoinHandle<()>)
 {
-    let (sender, receiver) = channel();
+    let (sender, receiver) = bounded("big indexes", queue_size);
 
     let handle = spawn(move || {
         let mut accumulated_index = InMemoryIndex::new();
-        for fi in file_indexes {
+        for fi in InOrder::new(file_indexes, first_doc_id).with_window(window) {
             accumulated_index.merge(fi);
-            if accumulated_index.is_large() {
+            if accumulated_index.heap_size() >= memory_limit {
                 if sender.send(accumulated_index).is_err() {
                     return;
                 }
@@ -169,22 +407,24 @@ fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>)
 
 /// Start a thread that saves large indexes to temporary files.
 ///
-/// This thread generates a meaningless unique filename for each index in
-/// `big_indexes`, saves the data, and passes the filename on to a new channel.
+/// This thread generates a meaningless unique filename in `tmp_dir` for each
+/// index in `big_indexes`, saves the data, and passes the filename on to a
+/// new channel.
 ///
 /// This returns a pair: a receiver that receives the filenames; and a
 /// `JoinHandle` that can be used to wait for this thread to exit and receive
-/// any I/O errors it encountered.
-fn start_index_writer_thread(big_indexes: Receiver<InMemoryIndex>,
-                             output_dir: &Path)
-    -> (Receiver<PathBuf>, JoinHandle<io::Result<()>>)
+/// any I/O errors it encountered. Like the other stages, the channel holds up
+/// to `queue_size` bytes of filenames.
+fn start_index_writer_thread(big_indexes: Receiver<InMemoryIndex>, tokenizer_spec: String,
+                             encoding: Encoding, tmp_dir: Arc<TmpDir>, queue_size: usize)
+    -> (Receiver<PathBuf>, JoinHandle<Result<(), IndexError>>)
 {
-    let (sender, receiver) = channel();
+    let (sender, receiver) = bounded("index files", queue_size);
 
-    let mut tmp_dir = TmpDir::new(output_dir);
     let handle = spawn(move || {
         for index in big_indexes {
-            let file = write_index_to_tmp_file(index, &mut tmp_dir)?;
+            let file = write_index_to_tmp_file(index, &tokenizer_spec, encoding, &tmp_dir)
+                .context(Stage::Write)?;
             if sender.send(file).is_err() {
                 break;
             }
@@ -195,106 +435,724 @@ fn start_index_writer_thread(big_indexes: Receiver<InMemoryIndex>,
     (receiver, handle)
 }
 
-/// Given a sequence of filenames of index data files, merge all the files
-/// into a single index data file.
-fn merge_index_files(files: Receiver<PathBuf>, output_dir: &Path)
-    -> io::Result<()>
+/// Given a sequence of filenames of index data files, add them all to
+/// `merge`, which merges them as they come in. The caller finishes the
+/// merge, once it knows the other stages succeeded.
+fn merge_index_files(files: Receiver<PathBuf>, merge: &mut FileMerge)
+    -> Result<(), IndexError>
 {
-    let mut merge = FileMerge::new(output_dir);
     for file in files {
-        merge.add_file(file)?;
+        merge.add_file(file).context(Stage::FileMerge)?;
     }
-    merge.finish()
+    Ok(())
 }
 
-/// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
+/// Create an inverted index for the given list of `documents`.
+///
+/// On success this does exactly the same thing as `run_single_threaded`,
+/// producing the very same index file, but faster since it uses multiple
+/// CPUs and keeps them busy while I/O is happening. `options.jobs` is the
+/// number of threads that tokenize documents, which is usually the
+/// bottleneck.
 ///
-/// On success this does exactly the same thing as `run_single_threaded`, but
-/// faster since it uses multiple CPUs and keeps them busy while I/O is
-/// happening.
-fn run_pipeline(documents: Vec<PathBuf>, output_dir: PathBuf)
-    -> io::Result<()>
+/// When it's done, this prints statistics about each of the channels
+/// between the stages, to show where the time went.
+fn run_pipeline(documents: Vec<PathBuf>, first_doc_id: usize, tokenizer: Arc<dyn Tokenizer>,
+                dedup: Deduplicator, mut merge: FileMerge, tmp_dir: Arc<TmpDir>,
+                options: &IndexOptions)
+    -> Result<usize, IndexError>
 {
     // Launch all five stages of the pipeline.
-    let (texts,   h1) = start_file_reader_thread(documents);
-    let (pints,   h2) = start_file_indexing_thread(texts);
-    let (gallons, h3) = start_in_memory_merge_thread(pints);
-    let (files,   h4) = start_index_writer_thread(gallons, &output_dir);
-    let result = merge_index_files(files, &output_dir);
-
-    // Wait for threads to finish, holding on to any errors that they encounter.
-    let r1 = h1.join().unwrap();
-    h2.join().unwrap();
-    h3.join().unwrap();
-    let r4 = h4.join().unwrap();
-
-    // Return the first error encountered, if any.
-    // (As it happens, h2 and h3 can't fail: those threads
-    // are pure in-memory data processing.)
-    r1?;
+    let queue_size = options.queue_size;
+    let keep_going = options.keep_going;
+    let spec = tokenizer.spec();
+    let (texts,   h1) = start_file_reader_thread(documents, first_doc_id, dedup,
+                                                 tokenizer.clone(), options.encoding(),
+                                                 keep_going, queue_size);
+    let mut stats = vec![texts.stats()];
+
+    // Indexes that come out of order wait in the merge stage for the ones
+    // before them; this limits how many bytes of them there can be.
+    let window = Arc::new(Window::new(first_doc_id, queue_size));
+    let (pints,   h2) = start_file_indexing_threads(texts, tokenizer, options.jobs,
+                                                    window.clone(), keep_going, queue_size);
+    stats.push(pints.stats());
+    let (gallons, h3) = start_in_memory_merge_thread(pints, first_doc_id, window,
+                                                     options.memory_limit, queue_size);
+    stats.push(gallons.stats());
+    let (files,   h4) = start_index_writer_thread(gallons, spec.clone(), options.encoding(),
+                                                    tmp_dir.clone(), queue_size);
+    stats.push(files.stats());
+    let result = merge_index_files(files, &mut merge);
+
+    // Wait for threads to finish, holding on to any errors that they
+    // encounter, including panics.
+    let r1 = join(h1, Stage::Read).and_then(|r| r);
+    let r2: Vec<Result<usize, IndexError>> = h2.into_iter()
+        .map(|h| join(h, Stage::Index).and_then(|r| r))
+        .collect();
+    let r3 = join(h3, Stage::Merge);
+    let r4 = join(h4, Stage::Write).and_then(|r| r);
+
+    // Return the first error encountered, if any. An earlier stage that
+    // failed would have stopped sending data, so what `merge` has is
+    // incomplete; in that case the existing index must be left alone.
+    let (mut skipped, dedup) = r1?;
+    for r in r2 {
+        skipped += r?;
+    }
+    r3?;
     r4?;
-    result
+    result?;
+    add_duplicates(dedup, &spec, options.encoding(), &tmp_dir, &mut merge)?;
+    merge.finish().context(Stage::FileMerge)?;
+
+    // A channel that's usually full, where senders spend a lot of time
+    // waiting, leads to a stage that's slower than the one before it.
+    println!("{}", ChannelStats::HEADING);
+    for s in stats {
+        println!("{}", s);
+    }
+    Ok(skipped)
 }
 
-/// Given some paths, generate the complete list of text files to index. We check
-/// on disk whether the path is the name of a file or a directory; for
-/// directories, all .txt files immediately under the directory are indexed.
-/// Relative paths are fine.
-///
-/// It's an error if any of the `args` is not a valid path to an existing file
-/// or directory.
-fn expand_filename_arguments(args: Vec<String>) -> io::Result<Vec<PathBuf>> {
-    let mut filenames = vec![];
+/// Turn the filename arguments into paths. An argument of `-` means to read
+/// a list of paths from standard input, one per line, so that the output of
+/// `find` or `git ls-files` can be piped in.
+fn path_arguments(args: Vec<String>) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
     for arg in args {
-        let path = PathBuf::from(arg);
-        if path.metadata()?.is_dir() {
-            for entry in path.read_dir()? {
-                let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    filenames.push(entry.path());
+        if arg == "-" {
+            for line in io::stdin().lock().lines() {
+                let line = line?;
+                if !line.is_empty() {
+                    paths.push(PathBuf::from(line));
                 }
             }
         } else {
-            filenames.push(path);
+            paths.push(PathBuf::from(arg));
         }
     }
-    Ok(filenames)
+    Ok(paths)
+}
+
+/// Settings for building an index, from the command line.
+struct IndexOptions {
+    /// The directory where the index goes.
+    output: PathBuf,
+
+    /// Where to put temporary files, or `None` to put them in `output`.
+    tmp_dir: Option<PathBuf>,
+
+    /// Which files to index in the directories named on the command line.
+    walk: WalkOptions,
+
+    /// How to break documents into terms (see the `tokenize` module), or
+    /// `None` for the default.
+    tokenizer: Option<String>,
+
+    /// Roughly how many bytes each in-memory index may use before it's
+    /// written to a temporary file.
+    memory_limit: usize,
+
+    /// The number of indexing threads, or 0 to do all the work on one thread.
+    jobs: usize,
+
+    /// How many bytes of data each channel in the pipeline can hold.
+    queue_size: usize,
+
+    /// How many temporary files to merge at a time, at least 2.
+    fan_in: usize,
+
+    /// How to decode the text of each file, or `None` for the default.
+    encoding: Option<Encoding>,
+
+    /// If true, files that can't be read or indexed are reported and
+    /// skipped, instead of stopping the whole run.
+    keep_going: bool,
+
+    /// If true, documents that are nearly the same as an earlier one are
+    /// collapsed into it, not just exact copies (see `dedup.rs`).
+    near_duplicates: bool,
+
+    /// If true, and there's already an index in the output directory, index
+    /// only the files that are new or have changed since then, and merge
+    /// them into the existing index.
+    update: bool
+}
+
+impl IndexOptions {
+    /// How to decode the text of each file: UTF-8, unless told otherwise.
+    fn encoding(&self) -> Encoding {
+        self.encoding.unwrap_or(Encoding::Utf8)
+    }
 }
 
 /// Generate an index for a bunch of text files.
-fn run(filenames: Vec<String>, single_threaded: bool) -> io::Result<()> {
-    let output_dir = PathBuf::from(".");
-    let documents = expand_filename_arguments(filenames)?;
+///
+/// When updating an index, the tokenizer and encoding it was made with are
+/// used again.
+fn run(filenames: Vec<String>, mut options: IndexOptions) -> Result<(), IndexError> {
+    let output_dir = &options.output.clone();
+    let paths = path_arguments(filenames).context(Stage::Walk)?;
+    let mut documents = expand_paths(paths, &options.walk).context(Stage::Walk)?;
+    let mut first_doc_id = 0;
 
-    if single_threaded {
-        run_single_threaded(documents, output_dir)
+    // All temporary files go in `tmp_dir`, which is deleted when the last
+    // reference to it is dropped, whether or not indexing succeeds.
+    fs::create_dir_all(output_dir).file_context(Stage::Setup, output_dir)?;
+    let tmp_parent = options.tmp_dir.as_deref().unwrap_or(output_dir);
+    let tmp_dir = Arc::new(TmpDir::new(tmp_parent).file_context(Stage::Setup, tmp_parent)?);
+    let mut merge = FileMerge::new(output_dir, tmp_dir.clone(), options.fan_in);
+    let mut tokenizer = make_tokenizer(options.tokenizer.as_deref().unwrap_or(DEFAULT_SPEC))
+        .context(Stage::Setup)?;
+    let mut dedup = Deduplicator::new(options.encoding(), options.near_duplicates);
+
+    let index_file = output_dir.join(MERGED_FILENAME);
+    if options.update && index_file.exists() {
+        let plan = {
+            let existing = IndexSearcher::open(&index_file)
+                .file_context(Stage::Setup, &index_file)?;
+            if options.tokenizer.is_some() && tokenizer.spec() != existing.tokenizer() {
+                let msg = format!("index was made with tokenizer `{}`; \
+                                   rebuild it to change tokenizers",
+                                  existing.tokenizer());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                    .file_context(Stage::Setup, &index_file);
+            }
+            if options.encoding.is_some_and(|e| e != existing.encoding()) {
+                let msg = format!("index was made with encoding `{}`; \
+                                   rebuild it to change encodings",
+                                  existing.encoding().name());
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                    .file_context(Stage::Setup, &index_file);
+            }
+            tokenizer = make_tokenizer(existing.tokenizer()).context(Stage::Setup)?;
+            options.encoding = Some(existing.encoding());
+            dedup = Deduplicator::new(options.encoding(), options.near_duplicates);
+            let plan = plan_update(existing.documents(), documents).context(Stage::Setup)?;
+
+            // New files may be copies of documents that are staying.
+            let removed: HashSet<u32> = plan.tombstones.iter().map(|d| d.id).collect();
+            for doc in existing.documents() {
+                if !doc.deleted && !removed.contains(&doc.id) {
+                    dedup.add_existing(doc);
+                }
+            }
+            plan
+        };
+        if plan.is_empty() {
+            println!("{} is up to date", index_file.display());
+            return Ok(());
+        }
+        println!("updating {}: {} files to index, {} to remove",
+                 index_file.display(), plan.files.len(), plan.tombstones.len());
+
+        merge.add_existing_file(&index_file).context(Stage::FileMerge)?;
+        if !plan.tombstones.is_empty() {
+            add_records(plan.tombstones, &tokenizer.spec(), options.encoding(), &tmp_dir,
+                        &mut merge)?;
+        }
+        documents = plan.files;
+        first_doc_id = plan.first_doc_id;
+    }
+
+    let skipped = if options.jobs == 0 {
+        run_single_threaded(documents, first_doc_id, tokenizer, dedup, merge, tmp_dir, &options)?
     } else {
-        run_pipeline(documents, output_dir)
+        run_pipeline(documents, first_doc_id, tokenizer, dedup, merge, tmp_dir, &options)?
+    };
+    if skipped > 0 {
+        eprintln!("skipped {} files because of errors", skipped);
+    }
+    Ok(())
+}
+
+/// Build the tokenizer with the given spec.
+fn make_tokenizer(spec: &str) -> io::Result<Arc<dyn Tokenizer>> {
+    match tokenize::from_spec(spec) {
+        Ok(tokenizer) => Ok(Arc::from(tokenizer)),
+        Err(msg) => Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+}
+
+/// Run a query against the index file `index_file` and print the `limit`
+/// documents that match best. With `snippets`, also show an excerpt of each
+/// document, highlighted that way.
+fn search_file(index_file: PathBuf, query: &str, bm25: Bm25, limit: usize,
+               snippets: Option<Highlight>) -> io::Result<()> {
+    let index = IndexSearcher::open(index_file)?;
+    let tokenizer = make_tokenizer(index.tokenizer())?;
+    let results = search(&index, &*tokenizer, query, bm25, limit, snippets)?;
+    println!("{} documents", results.total_matches);
+    for hit in results.hits {
+        match hit.path {
+            Some(path) => println!("    {} (score {:.3})", path.display(), hit.score),
+            None => println!("    document {} (score {:.3})", hit.doc_id, hit.score)
+        }
+        for path in hit.duplicates {
+            println!("      = {}", path.display());
+        }
+        if let Some(snippet) = hit.snippet {
+            println!("        {}", snippet);
+        }
+    }
+    Ok(())
+}
+
+/// Handle `fingertips search ...`. `args[0]` is the command name.
+fn search_main(args: Vec<String>) {
+    let mut index_file = PathBuf::from("index.dat");
+    let mut terms: Vec<String> = vec![];
+    let mut bm25 = Bm25::default();
+    let mut boosts: Vec<String> = vec![];
+    let mut limit = 10;
+    let mut snippets = true;
+    let mut highlight = None;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Search an index made by fingertips.");
+        ap.refer(&mut index_file)
+            .add_option(&["-i", "--index"], Store,
+                "Index file to search (default: index.dat).");
+        ap.refer(&mut limit)
+            .add_option(&["-n", "--top"], Store,
+                "Number of results to show, best first (default: 10).");
+        ap.refer(&mut bm25.k1)
+            .add_option(&["--k1"], Store,
+                "BM25 term frequency saturation (default: 1.2).");
+        ap.refer(&mut bm25.b)
+            .add_option(&["--b"], Store,
+                "BM25 document length normalization, 0 to 1 (default: 0.75).");
+        ap.refer(&mut boosts)
+            .add_option(&["--boost"], Collect,
+                "How much a match in a field counts, as FIELD=WEIGHT, for \
+                example `--boost title=5` (defaults: body=1, title=3, \
+                name=2, path=1.5).");
+        ap.refer(&mut snippets)
+            .add_option(&["--no-snippets"], StoreFalse,
+                "Don't show an excerpt of each document. (Showing them means \
+                reading the files again, so it only works if they're still \
+                where they were when they were indexed.)");
+        ap.refer(&mut highlight)
+            .add_option(&["--highlight"], StoreOption,
+                "How to mark matching words in excerpts: `ansi` (bold red), \
+                `html` (<mark> tags), or `none` (default: ansi if the output \
+                is a terminal, otherwise none).");
+        ap.refer(&mut terms)
+            .add_argument("query", Collect,
+                "Words to look up, combined with AND, OR, NOT and \
+                parentheses. Words with no operator between them must \
+                all appear. Use \"quotes\" to search for a phrase and \
+                `a NEAR/k b` for words at most k words apart. \
+                `thread*`, `chan?el`, and `colour~` (or `colour~1`) match \
+                every indexed term that starts with `thread`, fits the \
+                wildcard, or starts with `c` and is spelled within 2 (or 1) \
+                edits of `colour`. \
+                `title:gapbuffer` matches only in the document's first \
+                heading; the other fields are `name:`, `path:` (the \
+                directories), and `body:`. Words with no field are looked \
+                for in the body and title.")
+            .required();
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            exit(code);
+        }
+    }
+
+    for boost in &boosts {
+        if let Err(msg) = bm25.boosts.set(boost) {
+            println!("error: {}", msg);
+            exit(1);
+        }
+    }
+
+    let highlight = highlight.unwrap_or(if io::stdout().is_terminal() {
+        Highlight::Ansi
+    } else {
+        Highlight::Plain
+    });
+    match search_file(index_file, &terms.join(" "), bm25, limit, snippets.then_some(highlight)) {
+        Ok(()) => {}
+        Err(err) => println!("error: {}", err)
+    }
+}
+
+/// Merge the index files `inputs` into one, `output`.
+fn merge(inputs: Vec<PathBuf>, output: PathBuf) -> io::Result<()> {
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    let tmp_dir = TmpDir::new(dir)?;
+    combine(&inputs, &output, &tmp_dir)
+}
+
+/// Handle `fingertips merge ...`. `args[0]` is the command name.
+fn merge_main(args: Vec<String>) {
+    let mut inputs: Vec<PathBuf> = vec![];
+    let mut output = PathBuf::from(MERGED_FILENAME);
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Merge index files made by fingertips, for example on \
+            different machines, into one. Documents are renumbered so that \
+            their ids don't collide. The input files are not changed.");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+                "Index file to write (default: index.dat). It may be one of \
+                the inputs; it's replaced only once the merge succeeds.");
+        ap.refer(&mut inputs)
+            .add_argument("inputs", Collect,
+                "Index files to merge.")
+            .required();
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            exit(code);
+        }
+    }
+
+    match merge(inputs, output) {
+        Ok(()) => {}
+        Err(err) => println!("error: {}", err)
+    }
+}
+
+/// Handle `fingertips serve ...`. `args[0]` is the command name.
+fn serve_main(args: Vec<String>) {
+    let mut index_file = PathBuf::from("index.dat");
+    let mut host = "127.0.0.1".to_string();
+    let mut port: u16 = 8080;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Serve searches of an index made by fingertips over \
+            HTTP. Open http://localhost:8080/ in a browser, or get \
+            /search?q=QUERY for results as JSON. If the index file is \
+            rebuilt, the new one is picked up automatically.");
+        ap.refer(&mut index_file)
+            .add_option(&["-i", "--index"], Store,
+                "Index file to search (default: index.dat).");
+        ap.refer(&mut host)
+            .add_option(&["--host"], Store,
+                "Address to listen on (default: 127.0.0.1, so only this \
+                machine can connect).");
+        ap.refer(&mut port)
+            .add_option(&["-p", "--port"], Store,
+                "Port to listen on (default: 8080).");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            exit(code);
+        }
+    }
+
+    match serve(index_file, &host, port, Bm25::default()) {
+        Ok(()) => {}
+        Err(err) => println!("error: {}", err)
+    }
+}
+
+/// Handle `fingertips inspect ...`. `args[0]` is the command name.
+fn inspect_main(args: Vec<String>) {
+    let mut index_file = PathBuf::from("index.dat");
+    let mut top = 10;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Show what's in an index file made by fingertips: \
+            its header, how many documents and terms it has, the most common \
+            terms, and how much space each part of the file takes.");
+        ap.refer(&mut top)
+            .add_option(&["-n", "--top"], Store,
+                "Number of most common terms to list (default: 10).");
+        ap.refer(&mut index_file)
+            .add_argument("index", Store,
+                "Index file to inspect (default: index.dat).");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            exit(code);
+        }
+    }
+
+    match inspect(&index_file, top) {
+        Ok(summary) => print!("{}", summary),
+        Err(err) => println!("error: {}", err)
+    }
+}
+
+/// Handle `fingertips verify ...`. `args[0]` is the command name.
+fn verify_main(args: Vec<String>) {
+    let mut index_files: Vec<PathBuf> = vec![];
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Check index files made by fingertips for damage: \
+            bad checksums, hits out of bounds or out of order, terms out of \
+            order, and hits for documents that aren't in the index. Exits \
+            with status 1 if any problems are found.");
+        ap.refer(&mut index_files)
+            .add_argument("index", Collect,
+                "Index files to check (default: index.dat).");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            exit(code);
+        }
+    }
+    if index_files.is_empty() {
+        index_files.push(PathBuf::from("index.dat"));
+    }
+
+    let mut ok = true;
+    for index_file in index_files {
+        match verify(&index_file) {
+            Ok(problems) if problems.is_empty() => println!("{}: ok", index_file.display()),
+            Ok(problems) => {
+                for problem in &problems {
+                    println!("{}: {}", index_file.display(), problem);
+                }
+                ok = false;
+            }
+            Err(err) => {
+                println!("{}: error: {}", index_file.display(), err);
+                ok = false;
+            }
+        }
+    }
+    if !ok {
+        exit(1);
     }
 }
 
 fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let subcommand: Option<fn(Vec<String>)> = match args.get(1).map(|s| s.as_str()) {
+        Some("search") => Some(search_main),
+        Some("merge") => Some(merge_main),
+        Some("serve") => Some(serve_main),
+        Some("inspect") => Some(inspect_main),
+        Some("verify") => Some(verify_main),
+        _ => None
+    };
+    if let Some(subcommand) = subcommand {
+        let command = args.remove(1);
+        args[0] = format!("{} {}", args[0], command);
+        return subcommand(args);
+    }
+
     let mut single_threaded = false;
+    let mut update = false;
     let mut filenames = vec![];
+    let mut include: Vec<String> = vec![];
+    let mut exclude: Vec<String> = vec![];
+    let mut hidden = false;
+    let mut tokenizer = None;
+    let mut memory_limit = ByteSize(512 << 20);
+    let mut queue_size = ByteSize(64 << 20);
+    let mut fan_in = DEFAULT_FAN_IN;
+    let mut encoding = None;
+    let mut keep_going = false;
+    let mut near_duplicates = false;
+    let mut output = PathBuf::from(".");
+    let mut tmp_dir = None;
+    let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
 
     {
         let mut ap = ArgumentParser::new();
-        ap.set_description("Make an inverted index for searching documents.");
+        ap.set_description("Make an inverted index for searching documents. \
+            To query an index, use `fingertips search QUERY` or \
+            `fingertips serve`; to combine \
+            indexes, use `fingertips merge FILE...`; to examine or check an \
+            index file, use `fingertips inspect` or `fingertips verify`.");
         ap.refer(&mut single_threaded)
             .add_option(&["-1", "--single_threaded"], StoreTrue,
                 "Do all the work on a single thread.");
+        ap.refer(&mut jobs)
+            .add_option(&["-j", "--jobs"], Store,
+                "Number of threads to use for breaking documents into terms \
+                (default: the number of CPUs).");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store,
+                "Directory to put the index in (default: the current \
+                directory). It's created if necessary.");
+        ap.refer(&mut tmp_dir)
+            .add_option(&["--tmp-dir"], StoreOption,
+                "Directory for temporary files (default: the --output \
+                directory). They're deleted when indexing finishes, even if \
+                it fails.");
+        ap.refer(&mut update)
+            .add_option(&["-u", "--update"], StoreTrue,
+                "Update the existing index.dat in the --output directory \
+                instead of starting over: index new and changed files, and \
+                remove files that are no longer among the files/directories \
+                given.");
+        ap.refer(&mut include)
+            .add_option(&["--include"], Collect,
+                "When searching directories, index only files matching this \
+                glob pattern, such as '*.log'. May be given more than once. \
+                The default is text, Markdown, and HTML files ('*.txt', \
+                '*.md', '*.markdown', '*.html', '*.htm'), plain or gzipped \
+                ('*.txt.gz' and so on). Markdown and HTML are indexed as \
+                text, without markup; any gzipped file is decompressed.");
+        ap.refer(&mut exclude)
+            .add_option(&["--exclude"], Collect,
+                "When searching directories, skip files and directories \
+                matching this glob pattern, such as 'target/**'. May be given \
+                more than once.");
+        ap.refer(&mut hidden)
+            .add_option(&["--hidden"], StoreTrue,
+                "Also search hidden files and directories, whose names start \
+                with '.'.");
+        ap.refer(&mut tokenizer)
+            .add_option(&["-t", "--tokenizer"], StoreOption,
+                "How to break documents into terms: 'simple' or 'unicode' \
+                (the default), optionally followed by filters: 'stop' to \
+                drop common English words, 'stem' to reduce English words \
+                to their stems, 'ngram:N' to index N-character pieces of \
+                words. For example, 'unicode,stop,stem'.");
+        ap.refer(&mut memory_limit)
+            .add_option(&["-m", "--memory-limit"], Store,
+                "Roughly how much memory to use for each in-memory index \
+                before writing it to a temporary file, such as '512M' (the \
+                default) or '2G'. When not running single-threaded, up to \
+                three such indexes can be in memory at once.");
+        ap.refer(&mut queue_size)
+            .add_option(&["--queue-size"], Store,
+                "How much data can wait between stages of the pipeline \
+                (default: 64M). When a queue is full, the stage before it \
+                waits for the next stage to catch up.");
+        ap.refer(&mut fan_in)
+            .add_option(&["--fan-in"], Store,
+                "How many temporary files to merge at a time (default: 64). \
+                Each file being merged needs two file descriptors.");
+        ap.refer(&mut encoding)
+            .add_option(&["--encoding"], StoreOption,
+                "How to decode files: 'utf8' (the default) fails on files \
+                that aren't valid UTF-8; 'utf8-lossy' replaces invalid bytes \
+                with U+FFFD; 'latin1' reads each byte as one character.");
+        ap.refer(&mut keep_going)
+            .add_option(&["-k", "--keep-going"], StoreTrue,
+                "When a file can't be read or indexed, report the problem and \
+                skip the file, instead of stopping.");
+        ap.refer(&mut near_duplicates)
+            .add_option(&["--near-duplicates"], StoreTrue,
+                "Treat files that are nearly the same as an earlier file \
+                (about 90% of their runs of four words in common) as copies \
+                of it, so words only in the later file won't find it. Exact \
+                copies are always indexed only once, with every copy's path \
+                listed in the index.");
         ap.refer(&mut filenames)
             .add_argument("filenames", Collect,
                 "Names of files/directories to index. \
-                For directories, all .txt files immediately \
-                under the directory are indexed.");
+                Directories are searched recursively for files matching \
+                the --include patterns. Use '-' to read a list of names \
+                from standard input.");
         ap.parse_args_or_exit();
     }
 
-    match run(filenames, single_threaded) {
+    let mut walk = WalkOptions::default();
+    if !include.is_empty() {
+        walk.include = include.iter().map(|p| Pattern::new(p)).collect();
+    }
+    walk.exclude = exclude.iter().map(|p| Pattern::new(p)).collect();
+    walk.hidden = hidden;
+
+    if single_threaded {
+        jobs = 0;
+    } else if jobs == 0 {
+        println!("error: -j must be at least 1");
+        exit(1);
+    }
+    if fan_in < 2 {
+        println!("error: --fan-in must be at least 2");
+        exit(1);
+    }
+
+    let options = IndexOptions {
+        output,
+        tmp_dir,
+        walk,
+        tokenizer,
+        memory_limit: memory_limit.0,
+        jobs,
+        queue_size: queue_size.0,
+        fan_in,
+        encoding,
+        keep_going,
+        near_duplicates,
+        update
+    };
+    match run(filenames, options) {
         Ok(()) => {}
         Err(err) => println!("error: {}", err)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(output: PathBuf, jobs: usize, memory_limit: usize, queue_size: usize,
+               fan_in: usize) -> IndexOptions {
+        IndexOptions {
+            output,
+            tmp_dir: None,
+            walk: WalkOptions::default(),
+            tokenizer: None,
+            memory_limit,
+            jobs,
+            queue_size,
+            fan_in,
+            encoding: None,
+            keep_going: false,
+            near_duplicates: false,
+            update: false
+        }
+    }
+
+    /// However the work is split up, the index comes out the same, byte for
+    /// byte.
+    #[test]
+    fn threads_make_identical_indexes() {
+        let dir = env::temp_dir().join(format!("fingertips-threads-{}", std::process::id()));
+        let corpus = dir.join("corpus");
+        fs::create_dir_all(&corpus).unwrap();
+        for i in 0..60 {
+            let words: Vec<String> = (0..(i * 37 % 500 + 1))
+                .map(|j| format!("w{}", (i * 7919 + j * 104_729) % 997))
+                .collect();
+            fs::write(corpus.join(format!("{:02}.txt", i)), words.join(" ")).unwrap();
+        }
+        fs::write(corpus.join("copy.txt"), fs::read(corpus.join("07.txt")).unwrap()).unwrap();
+
+        let configs = [(0, 512 << 20, 64 << 20, 64), (4, 512 << 20, 64 << 20, 64),
+                       (4, 10 << 10, 4 << 10, 2), (8, 2 << 10, 1, 3)];
+        let mut indexes = vec![];
+        for (n, &(jobs, memory_limit, queue_size, fan_in)) in configs.iter().enumerate() {
+            let output = dir.join(format!("out{}", n));
+            let options = options(output.clone(), jobs, memory_limit, queue_size, fan_in);
+            run(vec![corpus.to_string_lossy().into_owned()], options).unwrap();
+            indexes.push(fs::read(output.join(MERGED_FILENAME)).unwrap());
+        }
+        for (config, index) in configs.iter().zip(&indexes).skip(1) {
+            assert!(*index == indexes[0], "different index with {:?}", config);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A run that fails leaves no temporary files, and no index.
+    #[test]
+    fn failed_run_cleans_up() {
+        let dir = env::temp_dir().join(format!("fingertips-failed-{}", std::process::id()));
+        let corpus = dir.join("corpus");
+        fs::create_dir_all(&corpus).unwrap();
+        for i in 0..20 {
+            fs::write(corpus.join(format!("{:02}.txt", i)), format!("document {}", i)).unwrap();
+        }
+        fs::write(corpus.join("10.txt"), b"not \xff UTF-8").unwrap();
+
+        for jobs in [0, 4] {
+            let output = dir.join(format!("out{}", jobs));
+            let options = options(output.clone(), jobs, 1 << 10, 64 << 20, 2);
+            assert!(run(vec![corpus.to_string_lossy().into_owned()], options).is_err());
+            assert_eq!(fs::read_dir(&output).unwrap().count(), 0);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
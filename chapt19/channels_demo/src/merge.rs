@@ -1,29 +1,43 @@
-use std::fs::{self, File};
-use std::io::{self, BufWriter};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::tmp::TmpDir;
+use crate::tmp::{publish, TmpDir};
+use crate::postings::PostingsEncoder;
 use crate::read::IndexFileReader;
 use crate::write::IndexFileWriter;
 
 pub struct FileMerge {
     output_dir: PathBuf,
-    tmp_dir: TmpDir,
-    stacks: Vec<Vec<PathBuf>>
+    tmp_dir: Arc<TmpDir>,
+    stacks: Vec<Vec<PathBuf>>,
+
+    /// How many files to merge at a time, at most.
+    fan_in: usize
 }
 
-// How many files to merge at a time, at most.
-const NSTREAMS: usize = 8;
+/// The default for how many files to merge at a time. Merging more files
+/// at once means fewer passes over the data, but each open file needs a
+/// buffer and two file descriptors.
+pub const DEFAULT_FAN_IN: usize = 64;
 
-const MERGED_FILENAME: &'static str = "index.dat";
+/// The name of the finished index file.
+pub const MERGED_FILENAME: &str = "index.dat";
 
 impl FileMerge {
-    pub fn new(output_dir: &Path) -> FileMerge {
+    /// Prepare to merge files into an index in `output_dir`, `fan_in` files
+    /// at a time. Intermediate files go in `tmp_dir`.
+    pub fn new(output_dir: &Path, tmp_dir: Arc<TmpDir>, fan_in: usize) -> FileMerge {
+        assert!(fan_in >= 2);
         FileMerge {
             output_dir: output_dir.to_owned(),
-            tmp_dir: TmpDir::new(output_dir.to_owned()),
-            stacks: vec![]
+            tmp_dir,
+            stacks: vec![],
+            fan_in
         }
     }
 
@@ -34,99 +48,484 @@ impl FileMerge {
                 self.stacks.push(vec![]);
             }
             self.stacks[level].push(file);
-            if self.stacks[level].len() < NSTREAMS {
+            if self.stacks[level].len() < self.fan_in {
                 break;
             }
             let (filename, out) = self.tmp_dir.create()?;
             let mut to_merge = vec![];
             mem::swap(&mut self.stacks[level], &mut to_merge);
-            merge_streams(to_merge, out)?;
+            merge_files(to_merge, out, false)?;
             file = filename;
             level += 1;
         }
         Ok(())
     }
 
-    pub fn finish(mut self) -> io::Result<()> {
-        let mut tmp = Vec::with_capacity(NSTREAMS);
+    /// Add a copy of an index file that wasn't made by this `FileMerge`, such
+    /// as the output of an earlier run. The original file is left alone.
+    ///
+    /// Like files passed to `add_file`, the documents in this file must all
+    /// have lower ids than the documents in any file added after it.
+    pub fn add_existing_file(&mut self, file: &Path) -> io::Result<()> {
+        let (filename, mut out) = self.tmp_dir.create()?;
+        io::copy(&mut File::open(file)?, &mut out)?;
+        out.flush()?;
+        self.add_file(filename)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        let mut tmp = Vec::with_capacity(self.fan_in);
         for stack in self.stacks {
             for file in stack.into_iter().rev() {
                 tmp.push(file);
-                if tmp.len() == NSTREAMS {
-                    merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+                if tmp.len() == self.fan_in {
+                    merge_reversed(&mut tmp, &self.tmp_dir, false)?;
                 }
             }
         }
 
-        if tmp.len() > 1 {
-            merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+        // This is the last merge, so any tombstones have done their job. Even
+        // if there's only one file left, it can still have tombstones in it,
+        // from a merge above, so it goes through a merge of its own.
+        if !tmp.is_empty() {
+            merge_reversed(&mut tmp, &self.tmp_dir, true)?;
         }
         assert!(tmp.len() <= 1);
         match tmp.pop() {
             Some(last_file) => 
-                fs::rename(last_file, self.output_dir.join(MERGED_FILENAME)),
+                publish(&last_file, &self.output_dir.join(MERGED_FILENAME)),
             None => 
-                Err(io::Error::new(io::ErrorKind::Other,
-                                   "no documents were parsed or none contained any words"))
+                Err(io::Error::other("no documents were parsed or none contained any words"))
+        }
+    }
+}
+
+/// Merge several index files that were built separately, perhaps on
+/// different machines, into a single index at `output`. The inputs are left
+/// alone.
+///
+/// Each input's documents are renumbered to come after all the documents in
+/// the inputs before it, so that their ids don't collide. The output is
+/// written to a temporary file in `tmp_dir` and then published (see
+/// `tmp::publish`), so `output` can be one of the inputs.
+pub fn combine(inputs: &[PathBuf], output: &Path, tmp_dir: &TmpDir) -> io::Result<()> {
+    let mut streams = Vec::with_capacity(inputs.len());
+    let mut next_doc_id: u64 = 0;
+    for input in inputs {
+        let mut stream = IndexFileReader::open(input)?;
+        if next_doc_id + stream.doc_id_limit() > u32::MAX as u64 {
+            return Err(io::Error::other("too many documents to merge into one index"));
         }
+        stream.renumber(next_doc_id as u32);
+        next_doc_id = stream.doc_id_limit().max(next_doc_id);
+        streams.push(stream);
+    }
+    if streams.is_empty() {
+        return Err(io::Error::other("no index files to merge"));
     }
+
+    let (filename, out) = tmp_dir.create()?;
+    merge_streams(streams, out, true)?;
+    publish(&filename, output)
 }
 
-fn merge_streams(files: Vec<PathBuf>, out: BufWriter<File>)
+/// Merge several index files into one, deleting the inputs.
+fn merge_files(files: Vec<PathBuf>, out: BufWriter<File>, drop_tombstones: bool)
     -> io::Result<()>
 {
-    let mut streams: Vec<IndexFileReader> =
+    let streams: Vec<IndexFileReader> =
         files.into_iter()
             .map(IndexFileReader::open_and_delete)
             .collect::<io::Result<_>>()?;
+    merge_streams(streams, out, drop_tombstones)
+}
+
+/// A stream in the middle of being merged, ordered by the next term in it.
+///
+/// Streams at the same term are ordered by their position in the list of
+/// streams being merged, `index`, so that their hits are combined in order
+/// by document id. The order is reversed, so that `BinaryHeap`, which pops
+/// the greatest element first, pops the stream with the least term.
+struct Head {
+    // Boxed, because the heap moves its elements around a lot, and an
+    // `IndexFileReader` is a few hundred bytes.
+    stream: Box<IndexFileReader>,
+    index: usize
+}
+
+impl Head {
+    fn term(&self) -> &str {
+        &self.stream.peek().expect("finished stream left in the heap").term
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        (other.term(), other.index).cmp(&(self.term(), self.index))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
 
+/// Merge several index files into one. The documents in each stream must
+/// all have lower ids than the documents in the streams after it.
+///
+/// This is a k-way merge: the streams are kept in a heap by their next
+/// term, so finding the streams to take the next term from costs
+/// O(log k), however many streams there are.
+///
+/// Hits for documents that have a tombstone in any of the input files are
+/// left out of the output. The tombstones themselves are kept, since the
+/// hits they're meant to remove might be in some other file that will be
+/// merged later, unless `drop_tombstones` is true.
+fn merge_streams(mut streams: Vec<IndexFileReader>, out: BufWriter<File>, drop_tombstones: bool)
+    -> io::Result<()>
+{
     let mut output = IndexFileWriter::new(out)?;
 
-    let mut point: u64 = 0;
-    let mut count = streams.iter().filter(|s| s.peek().is_some()).count();
-    while count > 0 {
-        let mut term = None;
-        let mut nbytes = 0;
-        let mut df = 0;
-        for s in &streams {
-            match s.peek() {
-                None => {}
-                Some(entry) => {
-                    if term.is_none() || entry.term < *term.as_ref().unwrap() {
-                        term = Some(entry.term.clone()); // XXX LAME clone
-                        nbytes = entry.nbytes;
-                        df = entry.df;
-                    } else if entry.term == *term.as_ref().unwrap() {
-                        nbytes += entry.nbytes;
-                        df += entry.df;
-                    }
-                }
-            }
+    // Terms from indexes made with different tokenizers can't be mixed, and
+    // the document table can only say how all the documents were decoded.
+    let tokenizer = streams[0].tokenizer().to_string();
+    if streams.iter().any(|s| s.tokenizer() != tokenizer) {
+        return Err(io::Error::other("can't merge index files made with different tokenizers"));
+    }
+    let encoding = streams[0].encoding();
+    if streams.iter().any(|s| s.encoding() != encoding) {
+        return Err(io::Error::other("can't merge index files made with different encodings"));
+    }
+
+    // Each input file covers a different set of documents, so the merged
+    // document table is just all of them together -- except that a tombstone
+    // replaces the record for the document it deletes, and a record that
+    // only lists duplicates is folded into the document's own record.
+    let mut documents: Vec<_> = streams.iter_mut().flat_map(|s| s.take_documents()).collect();
+    let deleted: HashSet<u32> =
+        documents.iter().filter(|d| d.deleted).map(|d| d.id).collect();
+    documents.retain(|d| {
+        !deleted.contains(&d.id) || (d.deleted && !drop_tombstones)
+    });
+    documents.sort_by_key(|d| d.id);
+    documents.dedup_by(|later, earlier| {
+        if later.id != earlier.id {
+            return false;
+        }
+        if earlier.is_duplicates_only() {
+            mem::swap(earlier, later);
+        }
+        earlier.duplicates.append(&mut later.duplicates);
+        true
+    });
+
+    // Duplicates of a document that was never indexed (because it couldn't
+    // be, with `--keep-going`) have nothing to be folded into.
+    if drop_tombstones {
+        documents.retain(|d| !d.is_duplicates_only());
+    }
+    output.write_documents(&tokenizer, encoding, &documents);
+
+    let mut heap: BinaryHeap<Head> = streams.into_iter()
+        .enumerate()
+        .filter(|(_, stream)| stream.peek().is_some())
+        .map(|(index, stream)| Head { stream: Box::new(stream), index })
+        .collect();
+    while let Some(first) = heap.pop() {
+        // Take every stream whose next term is the same, in order.
+        let mut at_term = vec![first];
+        while heap.peek().is_some_and(|h| h.term() == at_term[0].term()) {
+            at_term.push(heap.pop().unwrap());
         }
-        let term = term.expect("bug in algorithm!");
 
-        for s in &mut streams {
-            if s.is_at(&term) {
-                s.move_entry_to(&mut output)?;
-                if s.peek().is_none() {
-                    count -= 1;
+        // If only one stream has this term, and it uses the same format as
+        // the output, we can copy its data verbatim. Otherwise we decode and
+        // re-encode the hits: compressed hits are delta-coded, so even
+        // concatenating two lists of hits means changing the first one in
+        // the second list.
+        let point = output.offset();
+        let (term, df) =
+            if at_term.len() == 1 && at_term[0].stream.can_copy_to(output.format())
+                && deleted.is_empty()
+            {
+                let entry = at_term[0].stream.move_entry_to(&mut output)?;
+                (entry.term, entry.df)
+            } else {
+                let mut encoder = PostingsEncoder::new(output.format());
+                let mut term = None;
+                let mut df = 0;
+                for h in &mut at_term {
+                    let (entry, count) =
+                        h.stream.move_postings_to(&mut encoder, |id| !deleted.contains(&id))?;
+                    term.get_or_insert(entry.term);
+                    df += count;
                 }
-            }
+                output.write_main(&encoder.finish())?;
+                (term.unwrap(), df)
+            };
+
+        // If every hit for this term was in a deleted document, the term is
+        // gone.
+        if df > 0 {
+            output.write_contents_entry(term, df, point, output.offset() - point);
         }
-        output.write_contents_entry(term, df, point, nbytes as u64);
-        point += nbytes as u64;
+
+        heap.extend(at_term.into_iter().filter(|h| h.stream.peek().is_some()));
     }
 
-    assert!(streams.iter().all(|s| s.peek().is_none()));
     output.finish()
 }
 
-fn merge_reversed(filenames: &mut Vec<PathBuf>, tmp_dir: &mut TmpDir) -> io::Result<()> {
+fn merge_reversed(filenames: &mut Vec<PathBuf>, tmp_dir: &TmpDir, drop_tombstones: bool)
+    -> io::Result<()>
+{
     filenames.reverse();
     let (merged_filename, out) = tmp_dir.create()?;
-    let mut to_merge = Vec::with_capacity(NSTREAMS);
+    let mut to_merge = Vec::with_capacity(filenames.len());
     mem::swap(filenames, &mut to_merge);
-    merge_streams(to_merge, out)?;
+    merge_files(to_merge, out, drop_tombstones)?;
     filenames.push(merged_filename);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+    use crate::docs::{DocumentInfo, Encoding};
+    use crate::field::Field;
+    use crate::index::InMemoryIndex;
+    use crate::mmap::IndexSearcher;
+    use crate::tokenize::SimpleTokenizer;
+    use crate::write::write_index_to_tmp_file;
+
+    /// Write `files` temporary index files, each with `docs_per_file`
+    /// documents of random words with a skewed distribution, like real text.
+    fn synthetic_files(tmp_dir: &TmpDir, files: usize, docs_per_file: usize) -> Vec<PathBuf> {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        let mut doc_id = 0;
+        (0..files).map(|_| {
+            let mut index = InMemoryIndex::new();
+            for _ in 0..docs_per_file {
+                let text: Vec<String> = (0..300)
+                    .map(|_| format!("w{}", random() % 1000 * (random() % 1000)))
+                    .collect();
+                let info = DocumentInfo { path: PathBuf::from(format!("{}.txt", doc_id)),
+                                          ..DocumentInfo::default() };
+                index.merge(InMemoryIndex::from_single_document(
+                    doc_id, info, vec![(Field::Body, text.join(" "))], &SimpleTokenizer).unwrap());
+                doc_id += 1;
+            }
+            write_index_to_tmp_file(index, "simple", Encoding::Utf8, tmp_dir).unwrap()
+        }).collect()
+    }
+
+    /// Write an index file of documents with the given ids and text.
+    fn document_file(tmp_dir: &TmpDir, docs: &[(usize, &str)]) -> PathBuf {
+        let mut index = InMemoryIndex::new();
+        for &(doc_id, text) in docs {
+            let info = DocumentInfo { path: PathBuf::from(format!("{}.txt", doc_id)),
+                                      ..DocumentInfo::default() };
+            index.merge(InMemoryIndex::from_single_document(
+                doc_id, info, vec![(Field::Body, text.to_string())], &SimpleTokenizer).unwrap());
+        }
+        write_index_to_tmp_file(index, "simple", Encoding::Utf8, tmp_dir).unwrap()
+    }
+
+    /// An update that deletes a file: the existing index, then a file with
+    /// the tombstone, then the new documents. However the merges fall out,
+    /// the published index has neither the tombstone nor the deleted
+    /// document's hits.
+    #[test]
+    fn update_drops_tombstones() {
+        let output_dir = env::temp_dir().join(format!("fingertips-update-{}", std::process::id()));
+        for fan_in in [2, 3, 64] {
+            for new_docs in [false, true] {
+                let tmp_dir = Arc::new(TmpDir::new(&output_dir).unwrap());
+                let existing = document_file(&tmp_dir, &[(0, "apple"), (1, "banana"), (2, "cherry")]);
+                let mut merge = FileMerge::new(&output_dir, tmp_dir.clone(), fan_in);
+                merge.add_existing_file(&existing).unwrap();
+
+                let mut index = InMemoryIndex::new();
+                index.documents = vec![DocumentInfo { id: 1, deleted: true, ..DocumentInfo::default() }];
+                merge.add_file(write_index_to_tmp_file(index, "simple", Encoding::Utf8, &tmp_dir).unwrap()).unwrap();
+                if new_docs {
+                    merge.add_file(document_file(&tmp_dir, &[(3, "banana split")])).unwrap();
+                }
+                merge.finish().unwrap();
+
+                let index = IndexSearcher::open(output_dir.join(MERGED_FILENAME))
+                    .unwrap();
+                let ids: Vec<u32> = index.documents().iter().map(|d| d.id).collect();
+                assert_eq!(ids, if new_docs { vec![0, 2, 3] } else { vec![0, 2] });
+                assert!(index.documents().iter().all(|d| !d.deleted));
+                assert_eq!(doc_ids(&index, "banana"), if new_docs { vec![3] } else { vec![] });
+            }
+        }
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    /// The ids of the documents in `index` that contain `term`.
+    fn doc_ids(index: &IndexSearcher, term: &str) -> Vec<u32> {
+        index.postings(term).unwrap().map(|p| p.unwrap().doc_id).collect()
+    }
+
+    #[test]
+    fn merge_streams_combines_terms() {
+        let tmp_dir = TmpDir::new(env::temp_dir()).unwrap();
+        let mut tombstones = InMemoryIndex::new();
+        tombstones.documents = vec![DocumentInfo { id: 1, deleted: true, ..DocumentInfo::default() }];
+        let files = [
+            document_file(&tmp_dir, &[(0, "apple banana"), (1, "banana cherry")]),
+            document_file(&tmp_dir, &[(2, "banana date"), (3, "apple")]),
+            write_index_to_tmp_file(tombstones, "simple", Encoding::Utf8, &tmp_dir).unwrap(),
+            document_file(&tmp_dir, &[(4, "banana cherry")])
+        ];
+        let merge = |files: &[PathBuf], drop_tombstones: bool| {
+            let streams = files.iter().map(|f| IndexFileReader::open(f).unwrap()).collect();
+            let (filename, out) = tmp_dir.create().unwrap();
+            merge_streams(streams, out, drop_tombstones).unwrap();
+            IndexSearcher::open(filename).unwrap()
+        };
+
+        // Streams that share a term are combined in order, and the deleted
+        // document's hits are left out.
+        let index = merge(&files, true);
+        assert_eq!(doc_ids(&index, "apple"), vec![0, 3]);
+        assert_eq!(doc_ids(&index, "banana"), vec![0, 2, 4]);
+        assert_eq!(doc_ids(&index, "cherry"), vec![4]);
+        assert_eq!(doc_ids(&index, "date"), vec![2]);
+        let ids: Vec<u32> = index.documents().iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![0, 2, 3, 4]);
+
+        // Unless told to drop it, the tombstone takes the document's place.
+        let index = merge(&files, false);
+        let deleted: Vec<(u32, bool)> = index.documents().iter().map(|d| (d.id, d.deleted)).collect();
+        assert_eq!(deleted, vec![(0, false), (1, true), (2, false), (3, false), (4, false)]);
+        assert_eq!(doc_ids(&index, "banana"), vec![0, 2, 4]);
+
+        // With nothing deleted, terms in just one stream are copied as is.
+        let index = merge(&files[..2], true);
+        assert_eq!(doc_ids(&index, "banana"), vec![0, 1, 2]);
+        assert_eq!(doc_ids(&index, "cherry"), vec![1]);
+        assert_eq!(doc_ids(&index, "date"), vec![2]);
+    }
+
+    #[test]
+    fn combine_renumbers() {
+        let tmp_dir = TmpDir::new(env::temp_dir()).unwrap();
+        // Both files number their documents from 0.
+        let a = document_file(&tmp_dir, &[(0, "apple banana"), (1, "banana")]);
+        let b = document_file(&tmp_dir, &[(0, "banana cherry"), (1, "apple"), (2, "cherry")]);
+        let output = tmp_dir.create().unwrap().0;
+        combine(&[a.clone(), b.clone()], &output, &tmp_dir).unwrap();
+        assert!(a.exists() && b.exists());
+
+        let index = IndexSearcher::open(&output).unwrap();
+        let docs: Vec<(u32, String)> = index.documents().iter()
+            .map(|d| (d.id, d.path.to_string_lossy().into_owned()))
+            .collect();
+        assert_eq!(docs, vec![(0, "0.txt".to_string()), (1, "1.txt".to_string()),
+                              (2, "0.txt".to_string()), (3, "1.txt".to_string()),
+                              (4, "2.txt".to_string())]);
+        assert_eq!(doc_ids(&index, "apple"), vec![0, 3]);
+        assert_eq!(doc_ids(&index, "banana"), vec![0, 1, 2]);
+        assert_eq!(doc_ids(&index, "cherry"), vec![2, 4]);
+        let offsets: Vec<Vec<u32>> =
+            index.postings("cherry").unwrap().map(|p| p.unwrap().offsets).collect();
+        assert_eq!(offsets, vec![vec![1], vec![0]]);
+
+        // The output can be one of the inputs.
+        combine(&[output.clone(), b], &output, &tmp_dir).unwrap();
+        let index = IndexSearcher::open(&output).unwrap();
+        assert_eq!(index.document_count(), 8);
+        assert_eq!(doc_ids(&index, "cherry"), vec![2, 4, 5, 7]);
+    }
+
+    /// The merge as it was before it used a heap, to compare against in
+    /// `bench_merge`: for each term, scan every stream to find the least
+    /// one. (No tombstones, since the benchmark has none.)
+    fn merge_streams_linear(mut streams: Vec<IndexFileReader>, out: BufWriter<File>)
+        -> io::Result<()>
+    {
+        let mut output = IndexFileWriter::new(out)?;
+        let tokenizer = streams[0].tokenizer().to_string();
+        let documents: Vec<_> = streams.iter_mut().flat_map(|s| s.take_documents()).collect();
+        output.write_documents(&tokenizer, streams[0].encoding(), &documents);
+
+        while let Some(term) = streams.iter().filter_map(|s| s.peek()).map(|e| &e.term).min() {
+            let term = term.clone();
+            let point = output.offset();
+            let mut at_term: Vec<&mut IndexFileReader> = streams.iter_mut()
+                .filter(|s| s.peek().is_some_and(|e| e.term == term))
+                .collect();
+            let df = if at_term.len() == 1 && at_term[0].can_copy_to(output.format()) {
+                at_term[0].move_entry_to(&mut output)?.df
+            } else {
+                let mut encoder = PostingsEncoder::new(output.format());
+                let mut df = 0;
+                for s in &mut at_term {
+                    df += s.move_postings_to(&mut encoder, |_| true)?.1;
+                }
+                output.write_main(&encoder.finish())?;
+                df
+            };
+            output.write_contents_entry(term, df, point, output.offset() - point);
+        }
+        output.finish()
+    }
+
+    /// Merge `files` into one, `fan_in` at a time, using `merge`.
+    fn merge_all<M>(tmp_dir: &TmpDir, mut files: Vec<PathBuf>, fan_in: usize, merge: M)
+        where M: Fn(Vec<IndexFileReader>, BufWriter<File>) -> io::Result<()>
+    {
+        while files.len() > 1 {
+            files = files.chunks(fan_in).map(|chunk| {
+                let streams = chunk.iter()
+                    .map(|f| IndexFileReader::open_and_delete(f).unwrap())
+                    .collect();
+                let (filename, out) = tmp_dir.create().unwrap();
+                merge(streams, out).unwrap();
+                filename
+            }).collect();
+        }
+    }
+
+    /// Time merging a few hundred files at different fan-ins, with the heap
+    /// and with the old linear scan. Run with
+    /// `cargo test --release bench_merge -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_merge() {
+        let output_dir = env::temp_dir().join(format!("fingertips-bench-{}", std::process::id()));
+        for &fan_in in &[8, 64, 512] {
+            let tmp_dir = TmpDir::new(&output_dir).unwrap();
+            let files = synthetic_files(&tmp_dir, 512, 10);
+            let start = Instant::now();
+            merge_all(&tmp_dir, files, fan_in, |streams, out| merge_streams(streams, out, false));
+            let heap = start.elapsed();
+
+            let files = synthetic_files(&tmp_dir, 512, 10);
+            let start = Instant::now();
+            merge_all(&tmp_dir, files, fan_in, merge_streams_linear);
+            let linear = start.elapsed();
+            eprintln!("fan-in {}: heap {:.2?}, linear scan {:.2?}", fan_in, heap, linear);
+        }
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
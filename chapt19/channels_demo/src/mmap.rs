@@ -0,0 +1,176 @@
+//! Looking up terms in an index file on disk.
+//!
+//! An `IndexSearcher` maps an index file into memory, read-only, and parses
+//! its table of contents into a term dictionary. After that, looking up a term is
+//! a binary search, and the hits for the term are a slice of the mapped file:
+//! nothing is copied, and the operating system takes care of caching the
+//! parts of the file that are used a lot. (Compare `IndexFileReader`, which
+//! reads a file from beginning to end, for merging.)
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use memmap2::Mmap;
+use crate::docs::{find_document, read_documents, DocumentInfo, Encoding};
+use crate::field::Field;
+use crate::header::{check_crc, FormatError, Header, Section, HEADER_SIZE};
+use crate::postings::{Posting, Postings, PostingsFormat};
+use crate::read::IndexFileReader;
+use crate::terms::{TermDictionary, TermInfo, TermPattern};
+
+/// Random access to the terms of an index file: `open` an index file, then
+/// look up terms with `postings`.
+pub struct IndexSearcher {
+    /// The whole index file, mapped into memory.
+    map: Mmap,
+
+    /// How the hits in this file are encoded.
+    format: PostingsFormat,
+
+    /// The spec of the tokenizer used to make this index.
+    tokenizer: String,
+
+    /// How the documents were decoded.
+    encoding: Encoding,
+
+    /// The whole table of contents, sorted by term. Every entry's data is
+    /// within the main part of `map`; `open` checks.
+    terms: TermDictionary,
+
+    /// The document table, sorted by document id. This can include
+    /// tombstones.
+    documents: Vec<DocumentInfo>,
+
+    /// The number of documents that aren't tombstones.
+    live_documents: usize,
+
+    /// The total number of words in all documents.
+    total_words: u64
+}
+
+impl IndexSearcher {
+    /// Map an index file into memory and load its table of contents.
+    ///
+    /// This checks the checksums of the document table and the table of
+    /// contents, but not the main part of the file, since that would mean
+    /// reading all of it.
+    pub fn open<P: AsRef<Path>>(filename: P) -> io::Result<IndexSearcher> {
+        let file = File::open(filename)?;
+
+        // Safety: the map is only valid as long as nobody changes the file.
+        // This program never modifies an index file in place; a finished
+        // index is moved into place with `fs::rename`, which leaves any file
+        // already mapped under that name alone.
+        let map = unsafe { Mmap::map(&file)? };
+
+        let header = Header::read_from(&mut &map[..])?;
+        header.check_len(map.len() as u64)?;
+
+        let documents_buf = &map[header.documents_offset as usize..header.contents_offset as usize];
+        let contents_buf = &map[header.contents_offset as usize..];
+        check_crc(documents_buf, header.documents_crc, Section::Documents)?;
+        check_crc(contents_buf, header.contents_crc, Section::Contents)?;
+
+        let table = read_documents(&mut &documents_buf[..])?;
+        let mut reader = contents_buf;
+        let mut terms = TermDictionary::new();
+        while let Some(entry) = IndexFileReader::read_entry(&mut reader)? {
+            let in_bounds = entry.offset >= HEADER_SIZE
+                && entry.offset.checked_add(entry.nbytes)
+                    .is_some_and(|end| end <= header.documents_offset);
+            if !in_bounds {
+                return Err(FormatError::BadOffsets.into());
+            }
+            let info = TermInfo { df: entry.df, offset: entry.offset, nbytes: entry.nbytes };
+            terms.push(&entry.term, info)?;
+        }
+
+        let live_documents = table.documents.iter().filter(|d| !d.deleted).count();
+        Ok(IndexSearcher {
+            map,
+            format: header.format,
+            tokenizer: table.tokenizer,
+            encoding: table.encoding,
+            terms,
+            documents: table.documents,
+            live_documents,
+            total_words: table.total_words
+        })
+    }
+
+    /// The spec of the tokenizer used to make this index. Queries must be
+    /// broken into terms the same way.
+    pub fn tokenizer(&self) -> &str {
+        &self.tokenizer
+    }
+
+    /// How the documents were decoded. Snippets must decode them the same
+    /// way, or the word positions won't line up.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The number of documents in the index.
+    pub fn document_count(&self) -> usize {
+        self.live_documents
+    }
+
+    /// The average number of words per document.
+    pub fn average_document_length(&self) -> f64 {
+        if self.live_documents == 0 {
+            0.0
+        } else {
+            self.total_words as f64 / self.live_documents as f64
+        }
+    }
+
+    /// The whole document table, sorted by document id, including any
+    /// tombstones.
+    pub fn documents(&self) -> &[DocumentInfo] {
+        &self.documents
+    }
+
+    /// Look up a document in the document table. Returns `None` if there's
+    /// no such document or it has been deleted.
+    pub fn document(&self, doc_id: u32) -> Option<&DocumentInfo> {
+        find_document(&self.documents, doc_id).filter(|d| !d.deleted)
+    }
+
+    /// Find all the terms in the index that match `pattern`, in sorted
+    /// order.
+    pub fn expand(&self, pattern: &TermPattern) -> io::Result<Vec<String>> {
+        self.terms.expand(pattern)
+    }
+
+    /// The raw hit data for `term`: all its `Hit`s, back-to-back, sorted by
+    /// document id, straight out of the mapped file, in this file's
+    /// `PostingsFormat`.
+    ///
+    /// Returns an empty slice if the term doesn't appear in the index.
+    fn hits(&self, term: &str) -> io::Result<&[u8]> {
+        Ok(match self.terms.get(term)? {
+            Some(e) => &self.map[e.offset as usize..(e.offset + e.nbytes) as usize],
+            None => &[]
+        })
+    }
+
+    /// Decode the hits for `term`, in the order they're stored in the index
+    /// (that is, sorted by document id).
+    ///
+    /// The hits are decoded one at a time, as they're asked for, so however
+    /// common the term is, only one is in memory at once. If the term doesn't
+    /// appear in the index, there are none.
+    pub fn postings(&self, term: &str) -> io::Result<Postings<'_>> {
+        Ok(Postings::new(self.format, self.hits(term)?))
+    }
+
+    /// Decode the hits for `term`, keeping only the occurrences a query word
+    /// with `scope` looks for (see `field::in_scope`), and only the documents
+    /// that have any.
+    pub fn postings_in(&self, term: &str, scope: Option<Field>)
+        -> io::Result<impl Iterator<Item = io::Result<Posting>> + '_>
+    {
+        Ok(self.postings(term)?
+           .filter_map(move |p| p.map(|p| p.within(scope)).transpose()))
+    }
+}
@@ -0,0 +1,302 @@
+//! Encoding and decoding the hits stored in index files.
+//!
+//! In memory, each `Hit` is a buffer of little-endian u32s (see the `index`
+//! module). Index files can store hits in that same raw form, but most of the
+//! numbers involved are small, so that wastes a lot of space. The compact
+//! form stores each hit as:
+//!
+//! * the difference between its document id and the previous hit's document
+//!   id (or the document id itself, for the first hit for a term);
+//! * the number of offsets;
+//! * the offsets, each stored as the difference from the previous offset,
+//!   shifted left to make room for the field's number in the low bits, as
+//!   in a `Hit`.
+//!
+//! Each of these numbers is written as an LEB128 varint: 7 bits per byte,
+//! low bits first, with the high bit of each byte set if more bytes follow.
+//! Since hits are sorted by document id, and offsets are sorted too, the
+//! differences are usually small enough to fit in a single byte.
+//!
+//! The header of each index file says which form it uses. This code writes
+//! only the compact form, but reads either. Files written before index files
+//! had headers always used the raw form, but they can't be read any more
+//! (see the `header` module).
+
+use std::io;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use crate::field::{in_scope, Field, FIELD_BITS};
+use crate::header::FormatError;
+
+/// One decoded `Hit`: a document that contains a term, and the word offsets
+/// within that document where the term appears.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Posting {
+    pub doc_id: u32,
+    pub offsets: Vec<u32>,
+
+    /// The field each occurrence is in, in the same order as `offsets`.
+    pub fields: Vec<Field>
+}
+
+impl Posting {
+    /// A posting with every occurrence in the body.
+    #[cfg(test)]
+    pub fn body(doc_id: u32, offsets: Vec<u32>) -> Posting {
+        let fields = vec![Field::Body; offsets.len()];
+        Posting { doc_id, offsets, fields }
+    }
+
+    /// This posting, with only the occurrences that a query word with
+    /// `scope` looks for (see `field::in_scope`); or `None` if there aren't
+    /// any.
+    pub fn within(self, scope: Option<Field>) -> Option<Posting> {
+        let (offsets, fields) = self.offsets.into_iter()
+            .zip(self.fields)
+            .filter(|&(_, field)| in_scope(scope, field))
+            .unzip::<u32, Field, Vec<u32>, Vec<Field>>();
+        if offsets.is_empty() {
+            None
+        } else {
+            Some(Posting { doc_id: self.doc_id, offsets, fields })
+        }
+    }
+}
+
+/// Pack an offset and a field into one number, as in a `Hit`.
+fn pack(offset: u32, field: Field) -> u32 {
+    offset << FIELD_BITS | field as u32
+}
+
+/// How the hits in an index file are encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostingsFormat {
+    /// Each hit is stored exactly as in memory: document id, number of
+    /// offsets, and the offsets (with their fields), all as little-endian
+    /// u32s.
+    Raw,
+
+    /// Delta-coded varints, as described in the module documentation.
+    Compressed
+}
+
+impl PostingsFormat {
+    /// The number used for this format in index file headers.
+    pub fn flag(self) -> u8 {
+        match self {
+            PostingsFormat::Raw => 0,
+            PostingsFormat::Compressed => 1
+        }
+    }
+
+    /// The format with the given header flag.
+    pub fn from_flag(flag: u8) -> io::Result<PostingsFormat> {
+        match flag {
+            0 => Ok(PostingsFormat::Raw),
+            1 => Ok(PostingsFormat::Compressed),
+            _ => Err(FormatError::UnknownPostingsFormat(flag).into())
+        }
+    }
+}
+
+/// Append `value` to `out` as an LEB128 varint.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read an LEB128 varint from the front of `buf`, advancing past it. Returns
+/// `None` if `buf` ends in the middle of the number or it's too big.
+pub fn read_varint(buf: &mut &[u8]) -> Option<u32> {
+    let mut value: u32 = 0;
+    for (i, &byte) in buf.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Builds the data for one term: a sequence of hits, sorted by document id,
+/// in a given format.
+pub struct PostingsEncoder {
+    format: PostingsFormat,
+    last_doc_id: u32,
+    buf: Vec<u8>
+}
+
+impl PostingsEncoder {
+    pub fn new(format: PostingsFormat) -> PostingsEncoder {
+        PostingsEncoder { format, last_doc_id: 0, buf: vec![] }
+    }
+
+    /// Append one hit. Its document id must be greater than the id of the
+    /// previous hit, if any.
+    pub fn push(&mut self, posting: &Posting) {
+        match self.format {
+            PostingsFormat::Raw => {
+                self.buf.write_u32::<LittleEndian>(posting.doc_id).unwrap();
+                self.buf.write_u32::<LittleEndian>(posting.offsets.len() as u32).unwrap();
+                for (&offset, &field) in posting.offsets.iter().zip(&posting.fields) {
+                    self.buf.write_u32::<LittleEndian>(pack(offset, field)).unwrap();
+                }
+            }
+            PostingsFormat::Compressed => {
+                write_varint(&mut self.buf, posting.doc_id - self.last_doc_id);
+                write_varint(&mut self.buf, posting.offsets.len() as u32);
+                let mut last_offset = 0;
+                for (&offset, &field) in posting.offsets.iter().zip(&posting.fields) {
+                    write_varint(&mut self.buf, pack(offset - last_offset, field));
+                    last_offset = offset;
+                }
+            }
+        }
+        self.last_doc_id = posting.doc_id;
+    }
+
+    /// Return the encoded data.
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// An iterator that decodes the hits for one term, stored back-to-back, as
+/// they are in the main part of an index file, one `Posting` at a time.
+pub struct Postings<'a> {
+    format: PostingsFormat,
+    buf: &'a [u8],
+    last_doc_id: u32
+}
+
+impl<'a> Postings<'a> {
+    pub fn new(format: PostingsFormat, buf: &'a [u8]) -> Postings<'a> {
+        Postings { format, buf, last_doc_id: 0 }
+    }
+
+    fn next_raw(&mut self) -> Option<Posting> {
+        if self.buf.len() < 8 {
+            return None;
+        }
+        let count = LittleEndian::read_u32(&self.buf[4..8]) as usize;
+        let end = 8usize.checked_add(count.checked_mul(4)?)?;
+        if self.buf.len() < end {
+            return None;
+        }
+        let doc_id = LittleEndian::read_u32(&self.buf[0..4]);
+        let (offsets, fields) = self.buf[8..end].chunks(4)
+            .map(|chunk| {
+                let packed = LittleEndian::read_u32(chunk);
+                (packed >> FIELD_BITS, Field::from_bits(packed))
+            })
+            .unzip();
+        self.buf = &self.buf[end..];
+        Some(Posting { doc_id, offsets, fields })
+    }
+
+    fn next_compressed(&mut self) -> Option<Posting> {
+        let doc_id = self.last_doc_id.checked_add(read_varint(&mut self.buf)?)?;
+        let count = read_varint(&mut self.buf)? as usize;
+        // Every offset takes at least one byte, so this can't be right.
+        if count > self.buf.len() {
+            return None;
+        }
+        let mut offsets = Vec::with_capacity(count);
+        let mut fields = Vec::with_capacity(count);
+        let mut offset: u32 = 0;
+        for _ in 0..count {
+            let packed = read_varint(&mut self.buf)?;
+            offset = offset.checked_add(packed >> FIELD_BITS)?;
+            offsets.push(offset);
+            fields.push(Field::from_bits(packed));
+        }
+        self.last_doc_id = doc_id;
+        Some(Posting { doc_id, offsets, fields })
+    }
+}
+
+impl Iterator for Postings<'_> {
+    type Item = io::Result<Posting>;
+
+    fn next(&mut self) -> Option<io::Result<Posting>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let posting = match self.format {
+            PostingsFormat::Raw => self.next_raw(),
+            PostingsFormat::Compressed => self.next_compressed()
+        };
+        match posting {
+            Some(posting) => Some(Ok(posting)),
+            None => {
+                // Don't try to make sense of anything after a bad hit.
+                self.buf = &[];
+                Some(Err(FormatError::BadPostings.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docs::DocumentInfo;
+    use crate::index::InMemoryIndex;
+    use crate::tokenize::SimpleTokenizer;
+
+    #[test]
+    fn varints() {
+        for &n in &[0, 1, 127, 128, 300, 16_383, 16_384, u32::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, n);
+            let mut slice = &buf[..];
+            assert_eq!(read_varint(&mut slice), Some(n));
+            assert!(slice.is_empty());
+            assert_eq!(read_varint(&mut &buf[..buf.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let doc = DocumentInfo::default;
+        let fields = |body: &str, title: &str| {
+            vec![(Field::Body, body.to_string()), (Field::Title, title.to_string())]
+        };
+        let mut index = InMemoryIndex::from_single_document(3, doc(), fields("a b a", "a"),
+                                                            &SimpleTokenizer).unwrap();
+        index.merge(InMemoryIndex::from_single_document(700, doc(), fields("a", "b"),
+                                                        &SimpleTokenizer).unwrap());
+        let raw: Vec<u8> = index.map["a"].concat();
+        let postings: Vec<Posting> =
+            Postings::new(PostingsFormat::Raw, &raw).collect::<io::Result<_>>().unwrap();
+        assert_eq!(postings, vec![
+            Posting { doc_id: 3, offsets: vec![0, 2, 103],
+                      fields: vec![Field::Body, Field::Body, Field::Title] },
+            Posting::body(700, vec![0]),
+        ]);
+        assert_eq!(postings[0].clone().within(Some(Field::Title)),
+                   Some(Posting { doc_id: 3, offsets: vec![103], fields: vec![Field::Title] }));
+        assert_eq!(postings[1].clone().within(Some(Field::Title)), None);
+        assert_eq!(postings[1].clone().within(None), Some(postings[1].clone()));
+        assert!(Postings::new(PostingsFormat::Raw, &raw[..raw.len() - 1]).any(|p| p.is_err()));
+
+        for &format in &[PostingsFormat::Raw, PostingsFormat::Compressed] {
+            let mut encoder = PostingsEncoder::new(format);
+            for p in &postings {
+                encoder.push(p);
+            }
+            let buf = encoder.finish();
+            let decoded: Vec<Posting> =
+                Postings::new(format, &buf).collect::<io::Result<_>>().unwrap();
+            assert_eq!(decoded, postings);
+            if format == PostingsFormat::Raw {
+                assert_eq!(buf, raw);
+            } else {
+                assert_eq!(buf.len(), 10);
+            }
+        }
+    }
+}
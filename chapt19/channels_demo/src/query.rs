@@ -0,0 +1,732 @@
+//! Boolean queries.
+//!
+//! A query is a string like `rust AND (thread OR channel) NOT unsafe`. We
+//! parse it into a `Query` tree, then evaluate the tree against an index by
+//! looking up each term's hits and combining the resulting streams of
+//! document ids.
+//!
+//! Because every term's hits are sorted by document id (see
+//! `InMemoryIndex::map`), combining two streams is a simple linear merge, like
+//! the merge step of merge sort. Nothing is collected along the way: each
+//! term's hits are decoded as the merge asks for them, so evaluating a query
+//! takes memory in proportion to the size of the query, not the number of
+//! hits.
+//!
+//! Queries can also say where words appear relative to each other:
+//! `"memory safety"` matches the two words side by side, in that order, and
+//! `borrow NEAR/3 checker` matches the two words at most 3 words apart, in
+//! either order. These use the word offsets stored in each `Hit`.
+//!
+//! Words in a query are broken into terms by the same tokenizer that was used
+//! to build the index, so they match the terms in the index. A word that the
+//! tokenizer splits into several terms, like `don't` with the `simple`
+//! tokenizer, is searched for as a phrase.
+//!
+//! A word can also be a pattern that stands for many terms: `thread*` (any
+//! term starting with `thread`), `chan?el` or `*ing` (wildcards), or
+//! `colour~` (terms starting with `c` and spelled within two edits of
+//! `colour`; `colour~1` allows only one). Patterns are matched against the index's term dictionary, so
+//! they aren't run through the tokenizer; see `terms.rs`. A document matches
+//! a pattern if it contains any of the terms.
+//!
+//! Any of these can be limited to one field of the document (see `field.rs`)
+//! by putting the field's name and a colon in front: `title:gapbuffer`,
+//! `path:chapt19`, `name:"main.rs"`, or `title:(borrow OR lifetime*)`.
+//! Without a field, a word is looked for in the body and title only.
+
+use std::io;
+use std::iter::Peekable;
+use crate::field::Field;
+use crate::postings::Posting;
+use crate::mmap::IndexSearcher;
+use crate::terms::TermPattern;
+use crate::tokenize::{Token, Tokenizer};
+
+/// A parsed query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    /// Documents that contain this term.
+    Term(String),
+
+    /// Documents that match both subqueries.
+    And(Box<Query>, Box<Query>),
+
+    /// Documents that match either subquery, or both.
+    Or(Box<Query>, Box<Query>),
+
+    /// Documents that match the first subquery but not the second.
+    /// (`a NOT b` is short for `a AND NOT b`.)
+    AndNot(Box<Query>, Box<Query>),
+
+    /// Documents that contain these terms in this order, at these positions
+    /// relative to one another. The first term's position is 0. Usually the
+    /// terms are consecutive, but if the tokenizer dropped some words from
+    /// the phrase, there are gaps.
+    Phrase(Vec<Token>),
+
+    /// Documents where the two terms appear at most this many words apart.
+    Near(String, String, u32),
+
+    /// Documents that contain any term matching this pattern. Before a query
+    /// is evaluated, `expand` replaces each of these with a `Terms`.
+    Pattern(TermPattern),
+
+    /// Documents that contain any of these terms.
+    Terms(Vec<String>),
+
+    /// Documents that match the subquery using only the words in this
+    /// field.
+    InField(Field, Box<Query>)
+}
+
+/// Split a query string into tokens: parentheses, words, and quoted phrases.
+/// A phrase token keeps its quotation marks, so the parser can tell it apart
+/// from a word.
+fn lex(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut in_quotes = false;
+    for ch in text.chars() {
+        if ch == '"' {
+            if in_quotes {
+                word.push(ch);
+                tokens.push(std::mem::take(&mut word));
+            } else {
+                if !word.is_empty() {
+                    tokens.push(std::mem::take(&mut word));
+                }
+                word.push(ch);
+            }
+            in_quotes = !in_quotes;
+        } else if in_quotes {
+            word.push(ch);
+        } else if ch == '(' || ch == ')' || ch.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            if !ch.is_whitespace() {
+                tokens.push(ch.to_string());
+            }
+        } else {
+            word.push(ch);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// A recursive-descent parser for the query grammar:
+///
+/// ```text
+/// query     = and_expr ("OR" and_expr)*
+/// and_expr  = near_expr (["AND"] near_expr | "NOT" near_expr)*
+/// near_expr = primary ["NEAR/" k primary]
+/// primary   = [field ":"] (term | pattern | '"' term* '"' | "(" query ")")
+/// ```
+///
+/// Operators must be written in capital letters; in lowercase they're just
+/// words to search for. Two terms side by side with no operator between them
+/// means AND.
+struct Parser<'a> {
+    tokens: Vec<String>,
+    pos: usize,
+    tokenizer: &'a dyn Tokenizer
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        let token = self.tokens.get(self.pos).map(|t| t.as_str());
+        self.pos += 1;
+        token
+    }
+
+    fn parse_query(&mut self) -> Result<Query, String> {
+        let mut left = self.parse_and_expr()?;
+        while self.peek() == Some("OR") {
+            self.pos += 1;
+            let right = self.parse_and_expr()?;
+            left = Query::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and_expr(&mut self) -> Result<Query, String> {
+        let mut left = self.parse_near_expr()?;
+        loop {
+            match self.peek() {
+                None | Some(")") | Some("OR") => return Ok(left),
+                Some("NOT") => {
+                    self.pos += 1;
+                    let right = self.parse_near_expr()?;
+                    left = Query::AndNot(Box::new(left), Box::new(right));
+                }
+                Some(token) => {
+                    if token == "AND" {
+                        self.pos += 1;
+                    }
+                    let right = self.parse_near_expr()?;
+                    left = Query::And(Box::new(left), Box::new(right));
+                }
+            }
+        }
+    }
+
+    fn parse_near_expr(&mut self) -> Result<Query, String> {
+        let left = self.parse_primary()?;
+        let distance = match self.peek().and_then(|t| t.strip_prefix("NEAR/")) {
+            None => return Ok(left),
+            Some(k) => k.parse::<u32>()
+                .map_err(|_| format!("bad distance in `NEAR/{}`", k))?
+        };
+        self.pos += 1;
+        let right = self.parse_primary()?;
+        match (left, right) {
+            (Query::Term(a), Query::Term(b)) => Ok(Query::Near(a, b, distance)),
+            _ => Err("NEAR only works between two single words".to_string())
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, String> {
+        match self.next() {
+            None => Err("query ended unexpectedly".to_string()),
+            Some("(") => {
+                let query = self.parse_query()?;
+                match self.next() {
+                    Some(")") => Ok(query),
+                    _ => Err("missing `)`".to_string())
+                }
+            }
+            Some(op @ ")") | Some(op @ "AND") | Some(op @ "OR") | Some(op @ "NOT") =>
+                Err(format!("unexpected `{}`", op)),
+            Some(op) if op.starts_with("NEAR/") => Err(format!("unexpected `{}`", op)),
+            Some(phrase) if phrase.starts_with('"') => {
+                match phrase.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+                    Some(inner) => {
+                        let inner = inner.to_string();
+                        self.analyze(&inner)
+                    }
+                    None => Err("missing closing `\"`".to_string())
+                }
+            }
+            Some(word) => {
+                let word = word.to_string();
+                if let Some((name, rest)) = word.split_once(':') {
+                    if let Ok(field) = name.to_ascii_lowercase().parse::<Field>() {
+                        let query = if rest.is_empty() {
+                            self.parse_primary()?
+                        } else {
+                            self.parse_word(rest)?
+                        };
+                        return Ok(Query::InField(field, Box::new(query)));
+                    }
+                }
+                self.parse_word(&word)
+            }
+        }
+    }
+
+    /// Parse a single word from the query: a pattern, or a word to break
+    /// into terms.
+    fn parse_word(&self, word: &str) -> Result<Query, String> {
+        match TermPattern::parse(word)? {
+            Some(pattern) => Ok(Query::Pattern(pattern)),
+            None => self.analyze(word)
+        }
+    }
+
+    /// Break a word or phrase from the query into terms, and return a query
+    /// that matches them.
+    fn analyze(&self, text: &str) -> Result<Query, String> {
+        let mut tokens = self.tokenizer.tokenize(text);
+        match tokens.len() {
+            0 => Err(format!("nothing to search for in `{}` \
+                              (it's empty, or only has words too common to index)", text)),
+            1 => Ok(Query::Term(tokens.pop().unwrap().text)),
+            _ => {
+                let first = tokens[0].position;
+                for token in &mut tokens {
+                    token.position -= first;
+                }
+                Ok(Query::Phrase(tokens))
+            }
+        }
+    }
+}
+
+impl Query {
+    /// Parse a query string, using `tokenizer` to break words into terms.
+    pub fn parse(text: &str, tokenizer: &dyn Tokenizer) -> Result<Query, String> {
+        let mut parser = Parser { tokens: lex(text), pos: 0, tokenizer };
+        let query = parser.parse_query()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(token) => Err(format!("unexpected `{}`", token))
+        }
+    }
+
+    /// Replace each pattern in this query with the terms in `index` that
+    /// match it.
+    pub fn expand(&self, index: &IndexSearcher) -> io::Result<Query> {
+        Ok(match self {
+            Query::Pattern(pattern) => Query::Terms(index.expand(pattern)?),
+            Query::And(a, b) =>
+                Query::And(Box::new(a.expand(index)?), Box::new(b.expand(index)?)),
+            Query::Or(a, b) =>
+                Query::Or(Box::new(a.expand(index)?), Box::new(b.expand(index)?)),
+            Query::AndNot(a, b) =>
+                Query::AndNot(Box::new(a.expand(index)?), Box::new(b.expand(index)?)),
+            Query::InField(field, q) => Query::InField(*field, Box::new(q.expand(index)?)),
+            other => other.clone()
+        })
+    }
+
+    /// The terms that count toward a document's score when it matches this
+    /// query: every term in the query, except the ones after a `NOT`, each
+    /// with the field it's limited to, if any (see `field::in_scope`). Each
+    /// term and field is listed once. Patterns must be expanded first, or
+    /// they don't count.
+    pub fn scoring_terms(&self) -> Vec<(&str, Option<Field>)> {
+        let mut terms = vec![];
+        self.collect_scoring_terms(None, &mut terms);
+        let mut seen = std::collections::HashSet::new();
+        terms.retain(|t| seen.insert(*t));
+        terms
+    }
+
+    fn collect_scoring_terms<'a>(&'a self, scope: Option<Field>,
+                                 out: &mut Vec<(&'a str, Option<Field>)>) {
+        match *self {
+            Query::Term(ref term) => out.push((term, scope)),
+            Query::And(ref a, ref b) | Query::Or(ref a, ref b) => {
+                a.collect_scoring_terms(scope, out);
+                b.collect_scoring_terms(scope, out);
+            }
+            Query::AndNot(ref a, _) => a.collect_scoring_terms(scope, out),
+            Query::Phrase(ref terms) =>
+                out.extend(terms.iter().map(|t| (t.text.as_str(), scope))),
+            Query::Near(ref a, ref b, _) => {
+                out.push((a, scope));
+                out.push((b, scope));
+            }
+            Query::Pattern(_) => {}
+            Query::Terms(ref terms) => out.extend(terms.iter().map(|t| (t.as_str(), scope))),
+            Query::InField(field, ref q) => q.collect_scoring_terms(Some(field), out)
+        }
+    }
+
+    /// Find all documents that match this query. Returns their ids in
+    /// increasing order.
+    pub fn matching_documents(&self, index: &IndexSearcher)
+        -> io::Result<Vec<u32>>
+    {
+        self.doc_ids(index, None)?.collect()
+    }
+
+    /// The documents that match this query, looking only at the words in
+    /// `field`, if it's given, or else in the body and title (see
+    /// `field::in_scope`). The terms are looked up right away; their hits
+    /// are read as the documents are asked for.
+    fn doc_ids<'a>(&self, index: &'a IndexSearcher, field: Option<Field>)
+        -> io::Result<DocIds<'a>>
+    {
+        let merge = |op, a: &Query, b: &Query| -> io::Result<DocIds<'a>> {
+            let (a, b) = (a.doc_ids(index, field)?, b.doc_ids(index, field)?);
+            Ok(Box::new(Merge::new(op, a, b)))
+        };
+        Ok(match *self {
+            Query::Term(ref term) => term_documents(index, term, field)?,
+            Query::And(ref a, ref b) => merge(SetOp::And, a, b)?,
+            Query::Or(ref a, ref b) => merge(SetOp::Or, a, b)?,
+            Query::AndNot(ref a, ref b) => merge(SetOp::AndNot, a, b)?,
+            Query::Phrase(ref terms) => {
+                let lists = terms.iter()
+                    .map(|t| term_postings(index, &t.text, field))
+                    .collect::<io::Result<_>>()?;
+                let positions: Vec<u32> = terms.iter().map(|t| t.position).collect();
+                Box::new(PositionalMatches {
+                    lists,
+                    test: move |offsets: &[&[u32]]| is_phrase(&positions, offsets)
+                })
+            }
+            Query::Near(ref a, ref b, distance) => {
+                let lists = vec![term_postings(index, a, field)?,
+                                 term_postings(index, b, field)?];
+                Box::new(PositionalMatches {
+                    lists,
+                    test: move |offsets: &[&[u32]]| is_near(offsets[0], offsets[1], distance)
+                })
+            }
+            Query::Pattern(ref pattern) => union_all(index.expand(pattern)?.iter()
+                .map(|term| term_documents(index, term, field))
+                .collect::<io::Result<_>>()?),
+            Query::Terms(ref terms) => union_all(terms.iter()
+                .map(|term| term_documents(index, term, field))
+                .collect::<io::Result<_>>()?),
+            Query::InField(inner, ref q) => q.doc_ids(index, Some(inner))?
+        })
+    }
+}
+
+/// The ids of the documents that match some query, in increasing order,
+/// produced as they're asked for.
+type DocIds<'a> = Box<dyn Iterator<Item = io::Result<u32>> + 'a>;
+
+/// The hits for a term, decoded as they're asked for.
+type PostingStream<'a> = Peekable<Box<dyn Iterator<Item = io::Result<Posting>> + 'a>>;
+
+/// The documents that contain `term` in the fields that `field` selects.
+fn term_documents<'a>(index: &'a IndexSearcher, term: &str, field: Option<Field>)
+    -> io::Result<DocIds<'a>>
+{
+    Ok(Box::new(index.postings_in(term, field)?.map(|p| p.map(|p| p.doc_id))))
+}
+
+/// The hits for `term` in the fields that `field` selects.
+fn term_postings<'a>(index: &'a IndexSearcher, term: &str, field: Option<Field>)
+    -> io::Result<PostingStream<'a>>
+{
+    let postings: Box<dyn Iterator<Item = io::Result<Posting>> + 'a> =
+        Box::new(index.postings_in(term, field)?);
+    Ok(postings.peekable())
+}
+
+/// Look at the next item of `iter` without taking it, unless it's an error,
+/// which is taken and returned.
+fn peek<T, I>(iter: &mut Peekable<I>) -> io::Result<Option<&T>>
+    where I: Iterator<Item = io::Result<T>>
+{
+    if let Some(Err(_)) = iter.peek() {
+        return iter.next().unwrap().map(|_| None);
+    }
+    Ok(iter.peek().and_then(|item| item.as_ref().ok()))
+}
+
+/// How `Merge` combines two streams of document ids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SetOp {
+    /// The ids in both streams.
+    And,
+
+    /// The ids in either stream, or both.
+    Or,
+
+    /// The ids in the first stream but not the second.
+    AndNot
+}
+
+/// Combines two streams of document ids into one, in a single pass. Both
+/// streams, and the result, are sorted.
+struct Merge<'a> {
+    op: SetOp,
+    a: Peekable<DocIds<'a>>,
+    b: Peekable<DocIds<'a>>
+}
+
+impl<'a> Merge<'a> {
+    fn new(op: SetOp, a: DocIds<'a>, b: DocIds<'a>) -> Merge<'a> {
+        Merge { op, a: a.peekable(), b: b.peekable() }
+    }
+
+    fn next_id(&mut self) -> io::Result<Option<u32>> {
+        loop {
+            let x = peek(&mut self.a)?.copied();
+            let y = peek(&mut self.b)?.copied();
+            let id = match (x, y) {
+                // Nothing more can match.
+                (None, _) if self.op != SetOp::Or => return Ok(None),
+                (_, None) if self.op == SetOp::And => return Ok(None),
+
+                (Some(x), Some(y)) => x.min(y),
+                (Some(id), None) | (None, Some(id)) => id,
+                (None, None) => return Ok(None)
+            };
+            let (in_a, in_b) = (x == Some(id), y == Some(id));
+            if in_a {
+                self.a.next();
+            }
+            if in_b {
+                self.b.next();
+            }
+            let keep = match self.op {
+                SetOp::And => in_a && in_b,
+                SetOp::Or => true,
+                SetOp::AndNot => in_a && !in_b
+            };
+            if keep {
+                return Ok(Some(id));
+            }
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<u32>;
+
+    fn next(&mut self) -> Option<io::Result<u32>> {
+        self.next_id().transpose()
+    }
+}
+
+/// The ids in any of `streams`. Each pair of streams is merged, then each
+/// pair of those, and so on, so that a pattern that matches many terms
+/// doesn't make a long chain of merges.
+fn union_all(mut streams: Vec<DocIds>) -> DocIds {
+    match streams.len() {
+        0 => Box::new(std::iter::empty()),
+        1 => streams.pop().unwrap(),
+        n => {
+            let rest = streams.split_off(n / 2);
+            Box::new(Merge::new(SetOp::Or, union_all(streams), union_all(rest)))
+        }
+    }
+}
+
+/// The documents that appear in every one of `lists`, and for which `test`
+/// returns true. `test` is passed the offsets of each term in that document,
+/// in the same order as `lists`. There must be at least one list.
+struct PositionalMatches<'a, F> {
+    lists: Vec<PostingStream<'a>>,
+    test: F
+}
+
+impl<F: Fn(&[&[u32]]) -> bool> PositionalMatches<'_, F> {
+    fn next_id(&mut self) -> io::Result<Option<u32>> {
+        let (leader, rest) = self.lists.split_first_mut().unwrap();
+        'docs: for first in leader {
+            let first = first?;
+            let mut offsets = vec![first.offsets];
+            for list in rest.iter_mut() {
+                while peek(list)?.is_some_and(|p| p.doc_id < first.doc_id) {
+                    list.next();
+                }
+                match peek(list)?.map(|p| p.doc_id) {
+                    None => return Ok(None),
+                    Some(id) if id == first.doc_id => offsets.push(list.next().unwrap()?.offsets),
+                    Some(_) => continue 'docs
+                }
+            }
+            let offsets: Vec<&[u32]> = offsets.iter().map(Vec::as_slice).collect();
+            if (self.test)(&offsets) {
+                return Ok(Some(first.doc_id));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<F: Fn(&[&[u32]]) -> bool> Iterator for PositionalMatches<'_, F> {
+    type Item = io::Result<u32>;
+
+    fn next(&mut self) -> Option<io::Result<u32>> {
+        self.next_id().transpose()
+    }
+}
+
+/// True if there's an occurrence of the first term that's followed by the
+/// other terms at the given `positions` relative to it: normally the second
+/// term immediately after, then the third, and so on. Each list of offsets
+/// must be sorted.
+fn is_phrase(positions: &[u32], offsets: &[&[u32]]) -> bool {
+    offsets[0].iter().any(|&start| {
+        offsets[1..].iter().zip(&positions[1..]).all(|(term_offsets, &position)| {
+            term_offsets.binary_search(&(start + position)).is_ok()
+        })
+    })
+}
+
+/// True if some offset in `a` is within `distance` of some offset in `b`.
+/// Both lists must be sorted.
+fn is_near(a: &[u32], b: &[u32], distance: u32) -> bool {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].abs_diff(b[j]) <= distance {
+            return true;
+        }
+        if a[i] < b[j] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::FormatError;
+    use crate::tokenize::{from_spec, UnicodeTokenizer};
+
+    fn parse(text: &str) -> Result<Query, String> {
+        Query::parse(text, &UnicodeTokenizer)
+    }
+
+    fn token(text: &str, position: u32) -> Token {
+        Token { text: text.to_string(), position }
+    }
+
+    fn term(t: &str) -> Box<Query> {
+        Box::new(Query::Term(t.to_string()))
+    }
+
+    #[test]
+    fn parse_precedence() {
+        assert_eq!(
+            parse("rust AND (thread OR Channel) NOT unsafe").unwrap(),
+            Query::AndNot(
+                Box::new(Query::And(term("rust"),
+                                    Box::new(Query::Or(term("thread"), term("channel"))))),
+                term("unsafe")));
+        assert_eq!(parse("a b OR c").unwrap(),
+                   Query::Or(Box::new(Query::And(term("a"), term("b"))), term("c")));
+        assert_eq!(parse("\"Memory safety\" OR borrow NEAR/3 checker").unwrap(),
+                   Query::Or(
+                       Box::new(Query::Phrase(vec![token("memory", 0), token("safety", 1)])),
+                       Box::new(Query::Near("borrow".to_string(), "checker".to_string(), 3))));
+    }
+
+    #[test]
+    fn scoring_terms() {
+        let query = parse("a (b OR \"c a\") NOT d").unwrap();
+        assert_eq!(query.scoring_terms(), vec![("a", None), ("b", None), ("c", None)]);
+
+        let query = parse("a title:a path:(b OR a)").unwrap();
+        assert_eq!(query.scoring_terms(), vec![("a", None), ("a", Some(Field::Title)),
+                                               ("b", Some(Field::Path)), ("a", Some(Field::Path))]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("(a OR b").is_err());
+        assert!(parse("a OR").is_err());
+        assert!(parse("NOT a").is_err());
+        assert!(parse("a)").is_err());
+        assert!(parse("\"a b").is_err());
+        assert!(parse("a NEAR/x b").is_err());
+        assert!(parse("\"a b\" NEAR/2 c").is_err());
+        assert!(parse("\"...\"").is_err());
+        assert!(parse("a* NEAR/2 b").is_err());
+        assert!(parse("a~9").is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(parse("Thread* OR colour~1").unwrap(),
+                   Query::Or(Box::new(Query::Pattern(TermPattern::Prefix("thread".to_string()))),
+                             Box::new(Query::Pattern(TermPattern::Fuzzy("colour".to_string(), 1)))));
+        assert_eq!(parse("\"thread*\"").unwrap(), Query::Term("thread".to_string()));
+
+        let query = Query::And(term("a"), Box::new(Query::Terms(vec!["b".to_string(), "c".to_string()])));
+        assert_eq!(query.scoring_terms(), vec![("a", None), ("b", None), ("c", None)]);
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse("title:GapBuffer").unwrap(),
+                   Query::InField(Field::Title, term("gapbuffer")));
+        assert_eq!(parse("Path:(a OR b*)").unwrap(),
+                   Query::InField(Field::Path, Box::new(Query::Or(
+                       term("a"), Box::new(Query::Pattern(TermPattern::Prefix("b".to_string())))))));
+        assert_eq!(parse("name:\"main rs\"").unwrap(),
+                   Query::InField(Field::Name, Box::new(Query::Phrase(
+                       vec![token("main", 0), token("rs", 1)]))));
+        assert!(parse("title:").is_err());
+
+        // A word that only looks like it names a field is searched for as is.
+        assert_eq!(parse("std:io").unwrap(), Query::Term("std:io".to_string()));
+    }
+
+    #[test]
+    fn field_matches() {
+        use crate::docs::{DocumentInfo, Encoding};
+        use crate::index::InMemoryIndex;
+        use crate::tmp::TmpDir;
+        use crate::write::write_index_to_tmp_file;
+
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let mut index = InMemoryIndex::new();
+        let docs = [("gap buffer", "Editors", "a.txt"), ("editors use a gap buffer", "Gap Buffer", "b.txt")];
+        for (id, (body, title, name)) in docs.iter().enumerate() {
+            let fields = vec![(Field::Body, body.to_string()), (Field::Title, title.to_string()),
+                              (Field::Name, name.to_string())];
+            index.merge(InMemoryIndex::from_single_document(id, DocumentInfo::default(), fields,
+                                                            &UnicodeTokenizer).unwrap());
+        }
+        let file = write_index_to_tmp_file(index, "unicode", Encoding::Utf8, &tmp_dir).unwrap();
+        let index = IndexSearcher::open(&file).unwrap();
+        let matching = |q: &str| {
+            parse(q).unwrap().expand(&index).unwrap().matching_documents(&index).unwrap()
+        };
+
+        assert_eq!(matching("gap"), vec![0, 1]);
+        assert_eq!(matching("title:gap"), vec![1]);
+        assert_eq!(matching("title:editors"), vec![0]);
+        assert_eq!(matching("body:editors"), vec![1]);
+        assert_eq!(matching("title:\"gap buffer\""), vec![1]);
+        assert_eq!(matching("title:(gap OR edit*)"), vec![0, 1]);
+        assert_eq!(matching("title:buf*"), vec![1]);
+        // A phrase can't run from one field into the next.
+        assert_eq!(matching("\"buffer editors\""), Vec::<u32>::new());
+
+        // The name and path are only searched when asked for.
+        assert_eq!(matching("b.txt"), Vec::<u32>::new());
+        assert_eq!(matching("name:b.txt"), vec![1]);
+        assert_eq!(matching("name:*.txt"), vec![0, 1]);
+    }
+
+    #[test]
+    fn analysis() {
+        let simple = from_spec("simple").unwrap();
+        assert_eq!(Query::parse("Don't", &*simple).unwrap(),
+                   Query::Phrase(vec![token("don", 0), token("t", 1)]));
+        assert_eq!(parse("Don't").unwrap(), Query::Term("don't".to_string()));
+
+        let stemmed = from_spec("unicode,stop,stem").unwrap();
+        assert_eq!(Query::parse("\"Indexing the files\"", &*stemmed).unwrap(),
+                   Query::Phrase(vec![token("index", 0), token("file", 2)]));
+        assert!(Query::parse("the", &*stemmed).is_err());
+    }
+
+    #[test]
+    fn positions() {
+        assert!(is_phrase(&[0, 1, 2], &[&[3, 10], &[4, 20], &[5]]));
+        assert!(!is_phrase(&[0, 1, 2], &[&[3, 10], &[11], &[5]]));
+        assert!(is_phrase(&[0, 2], &[&[3, 10], &[12]]));
+        assert!(!is_phrase(&[0, 2], &[&[3, 10], &[11]]));
+        assert!(is_near(&[1, 30], &[27], 3));
+        assert!(!is_near(&[1, 30], &[26], 3));
+    }
+
+    fn ids(ids: &[u32]) -> DocIds<'_> {
+        Box::new(ids.iter().copied().map(Ok))
+    }
+
+    #[test]
+    fn merges() {
+        let (a, b) = ([1, 3, 5, 7], [2, 3, 4, 7, 9]);
+        let merge = |op| Merge::new(op, ids(&a), ids(&b)).collect::<io::Result<Vec<u32>>>().unwrap();
+        assert_eq!(merge(SetOp::And), vec![3, 7]);
+        assert_eq!(merge(SetOp::Or), vec![1, 2, 3, 4, 5, 7, 9]);
+        assert_eq!(merge(SetOp::AndNot), vec![1, 5]);
+
+        let all = union_all(vec![ids(&a), ids(&b), ids(&[0, 9, 10]), ids(&[])]);
+        assert_eq!(all.collect::<io::Result<Vec<u32>>>().unwrap(), vec![0, 1, 2, 3, 4, 5, 7, 9, 10]);
+        assert_eq!(union_all(vec![]).count(), 0);
+
+        // An error in either stream comes out of the merge.
+        let broken = || -> DocIds {
+            Box::new(vec![Ok(3), Err(FormatError::BadPostings.into())].into_iter())
+        };
+        for op in [SetOp::And, SetOp::Or, SetOp::AndNot] {
+            assert!(Merge::new(op, ids(&a), broken()).any(|id| id.is_err()));
+            assert!(Merge::new(op, broken(), ids(&b)).any(|id| id.is_err()));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Ranking search results.
+//!
+//! `Query::matching_documents` tells which documents match a query, in order
+//! by document id. To show the best matches first, we score each matching
+//! document with Okapi BM25, a refinement of TF-IDF: a term counts for more
+//! the more often it appears in a document (term frequency), the fewer
+//! documents it appears in overall (inverse document frequency), and the
+//! shorter the document is compared to the average.
+//!
+//! Occurrences in different fields count differently: by default, a word in
+//! the title counts three times as much as one in the body (see
+//! `field::Boosts`).
+
+use std::io;
+use crate::field::Boosts;
+use crate::query::Query;
+use crate::mmap::IndexSearcher;
+
+/// The tuning parameters for BM25.
+#[derive(Clone, Copy, Debug)]
+pub struct Bm25 {
+    /// How quickly repeated occurrences of a term stop adding to the score.
+    /// 0 means only whether a term appears matters, not how often.
+    pub k1: f64,
+
+    /// How much to penalize long documents, from 0 (not at all) to 1
+    /// (scores are fully normalized by document length).
+    pub b: f64,
+
+    /// How much an occurrence of a term in each field counts.
+    pub boosts: Boosts
+}
+
+impl Default for Bm25 {
+    fn default() -> Bm25 {
+        Bm25 { k1: 1.2, b: 0.75, boosts: Boosts::default() }
+    }
+}
+
+/// A document that matched a query, and how well it matched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoredDocument {
+    pub doc_id: u32,
+    pub score: f64
+}
+
+/// The result of a ranked search.
+pub struct Ranking {
+    /// The number of documents that matched the query.
+    pub total_matches: usize,
+
+    /// The best matches, highest score first.
+    pub top: Vec<ScoredDocument>
+}
+
+impl Bm25 {
+    /// The inverse document frequency of a term that appears in `df` out of
+    /// `doc_count` documents. (This is the variant used by Lucene, which is
+    /// never negative, even for terms that appear in almost every document.)
+    pub fn idf(&self, doc_count: usize, df: usize) -> f64 {
+        let n = doc_count as f64;
+        let df = df as f64;
+        (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+    }
+
+    /// The score contributed by one term that appears `tf` times in a
+    /// document of `doc_len` words. (`tf` is weighted by field, so it needn't
+    /// be a whole number.)
+    pub fn term_score(&self, idf: f64, tf: f64, doc_len: f64, avg_len: f64) -> f64 {
+        let norm = if avg_len > 0.0 { doc_len / avg_len } else { 1.0 };
+        idf * tf * (self.k1 + 1.0) / (tf + self.k1 * (1.0 - self.b + self.b * norm))
+    }
+
+    /// Find the documents that match `query` and return the `limit` best ones.
+    /// Patterns in `query` should already be expanded (see `Query::expand`);
+    /// any that aren't still match documents, but don't count toward scores.
+    pub fn rank(&self, query: &Query, index: &IndexSearcher, limit: usize)
+        -> io::Result<Ranking>
+    {
+        let matches = query.matching_documents(index)?;
+        let mut scores = vec![0.0; matches.len()];
+        let doc_count = index.document_count();
+        let avg_len = index.average_document_length();
+
+        for (term, scope) in query.scoring_terms() {
+            // Decoding the hits twice, once to count them, saves holding
+            // them all in memory.
+            let df = index.postings_in(term, scope)?.try_fold(0, |n, p| p.map(|_| n + 1))?;
+            let idf = self.idf(doc_count, df);
+
+            // Both `matches` and the hits are sorted by document id, so we
+            // can walk through them together.
+            let mut i = 0;
+            for posting in index.postings_in(term, scope)? {
+                let posting = posting?;
+                while i < matches.len() && matches[i] < posting.doc_id {
+                    i += 1;
+                }
+                if i == matches.len() {
+                    break;
+                }
+                if matches[i] == posting.doc_id {
+                    let doc_len = index.document(posting.doc_id)
+                        .map_or(avg_len, |d| d.length as f64);
+                    let tf = posting.fields.iter().map(|&field| self.boosts.get(field)).sum();
+                    scores[i] += self.term_score(idf, tf, doc_len, avg_len);
+                }
+            }
+        }
+
+        let mut top: Vec<ScoredDocument> = matches.iter().zip(scores)
+            .map(|(&doc_id, score)| ScoredDocument { doc_id, score })
+            .collect();
+        top.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.doc_id.cmp(&b.doc_id)));
+        top.truncate(limit);
+        Ok(Ranking { total_matches: matches.len(), top })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docs::{DocumentInfo, Encoding};
+    use crate::field::Field;
+    use crate::index::InMemoryIndex;
+    use crate::tmp::TmpDir;
+    use crate::tokenize::UnicodeTokenizer;
+    use crate::write::write_index_to_tmp_file;
+
+    #[test]
+    fn bm25_prefers_rare_terms_and_short_documents() {
+        let bm25 = Bm25::default();
+        assert!(bm25.idf(100, 1) > bm25.idf(100, 50));
+        assert!(bm25.idf(100, 100) > 0.0);
+
+        let idf = bm25.idf(100, 10);
+        assert!(bm25.term_score(idf, 2.0, 10.0, 20.0) > bm25.term_score(idf, 2.0, 40.0, 20.0));
+        assert!(bm25.term_score(idf, 3.0, 20.0, 20.0) > bm25.term_score(idf, 1.0, 20.0, 20.0));
+
+        let flat = Bm25 { k1: 0.0, b: 0.0, ..Bm25::default() };
+        assert_eq!(flat.term_score(idf, 1.0, 10.0, 20.0), flat.term_score(idf, 5.0, 90.0, 20.0));
+    }
+
+    #[test]
+    fn fields() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let mut index = InMemoryIndex::new();
+        let docs = [("gap gap gap", "gap", "notes.txt"), ("other words", "gap", "gap")];
+        for (id, (body, title, name)) in docs.iter().enumerate() {
+            let fields = vec![(Field::Body, body.to_string()), (Field::Title, title.to_string()),
+                              (Field::Name, name.to_string())];
+            index.merge(InMemoryIndex::from_single_document(id, DocumentInfo::default(), fields,
+                                                            &UnicodeTokenizer).unwrap());
+        }
+        let file = write_index_to_tmp_file(index, "unicode", Encoding::Utf8, &tmp_dir).unwrap();
+        let index = IndexSearcher::open(&file).unwrap();
+
+        // Only words in the body and title count toward a document's length.
+        assert_eq!(index.documents()[1].length, 3);
+
+        let bm25 = Bm25 { b: 0.0, ..Bm25::default() };
+        let scores = |q: &str| -> Vec<(u32, f64)> {
+            let query = Query::parse(q, &UnicodeTokenizer).unwrap();
+            let query = query.expand(&index).unwrap();
+            bm25.rank(&query, &index, 10).unwrap().top.iter()
+                .map(|doc| (doc.doc_id, doc.score))
+                .collect()
+        };
+
+        // Unqualified, the body counts, and the name doesn't.
+        let all = scores("gap");
+        assert_eq!(all[0].0, 0);
+        assert!(all[0].1 > all[1].1);
+
+        // Asked for the title, only the title counts.
+        let title = scores("title:gap");
+        assert_eq!(title.len(), 2);
+        assert_eq!(title[0].1, title[1].1);
+        let name = scores("name:gap");
+        assert_eq!(name.iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec![1]);
+    }
+}
@@ -6,6 +6,9 @@ use std::io::prelude::*;
 use std::io::{self, BufReader, SeekFrom};
 use std::path::Path;
 use byteorder::{LittleEndian, ReadBytesExt};
+use crate::docs::{read_documents, DocumentInfo, Encoding};
+use crate::header::{ChecksumReader, Header, Section};
+use crate::postings::{Postings, PostingsEncoder, PostingsFormat};
 use crate::write::IndexFileWriter;
 
 /// A `IndexFileReader` does a single linear pass over an index file from
@@ -20,12 +23,30 @@ pub struct IndexFileReader {
     /// We have two readers. The index data is most of the file. There's also a
     /// table of contents, stored separately at the end. We have to read them
     /// in tandem, so we open the file twice.
-    main: BufReader<File>,
-    
+    main: ChecksumReader<BufReader<File>>,
+
     /// Reader that reads the table of contents. (Since this table is stored at
     /// the end of the file, we have to begin by`seek`ing to it; see the code
-    /// in `IndexFileReader::open_and_delete`.)
-    contents: BufReader<File>,
+    /// in `IndexFileReader::open`.)
+    contents: ChecksumReader<BufReader<File>>,
+
+    /// The header, for the checksums. We check each section's checksum once
+    /// we've read all of it.
+    header: Header,
+
+    /// The spec of the tokenizer used to make this file.
+    tokenizer: String,
+
+    /// How the documents in this file were decoded.
+    encoding: Encoding,
+
+    /// The document table. It's stored just before the table of contents, so
+    /// we read it all up front, on the way to the first entry.
+    documents: Vec<DocumentInfo>,
+
+    /// A number added to every document id read from this file; see
+    /// `renumber`.
+    doc_id_offset: u32,
 
     /// The next entry in the table of contents, if any; or `None` if we've
     /// reached the end of the table. `IndexFileReader` always reads ahead one
@@ -55,45 +76,72 @@ pub struct Entry {
 }
 
 impl IndexFileReader {
-    /// Open an index file to read it from beginning to end.
+    /// Open an index file to read it from beginning to end, and delete it.
     ///
-    /// This deletes the file, which may not work properly on Windows. Patches
+    /// Deleting the file may not work properly on Windows. Patches
     /// welcome! On Unix, it works like this: file immediately disappears
     /// from its directory, but it'll still take up space on disk until the
     /// file is closed, which normally happens when the `IndexFileReader` is
     /// dropped.
     pub fn open_and_delete<P: AsRef<Path>>(filename: P) -> io::Result<IndexFileReader> {
+        let reader = IndexFileReader::open(&filename)?;
+        fs::remove_file(filename)?;  // YOLO
+        Ok(reader)
+    }
+
+    /// Open an index file to read it from beginning to end, leaving the file
+    /// alone.
+    pub fn open<P: AsRef<Path>>(filename: P) -> io::Result<IndexFileReader> {
         let filename = filename.as_ref();
         let mut main_raw = File::open(filename)?;
 
         // Read the file header.
-        let contents_offset = main_raw.read_u64::<LittleEndian>()?;
-        println!("opened {}, table of contents starts at {}", filename.display(), contents_offset);
+        let header = Header::read_from(&mut main_raw)?;
+        header.check_len(main_raw.metadata()?.len())?;
 
         // Open again so we have two read heads;
-        // move the contents read head to its starting position.
-        // Set up buffering.
+        // move the contents read head to the document table, which comes
+        // right before the table of contents. Set up buffering.
         let mut contents_raw = File::open(filename)?;
-        contents_raw.seek(SeekFrom::Start(contents_offset))?;
-        let main = BufReader::new(main_raw);
-        let mut contents = BufReader::new(contents_raw);
+        contents_raw.seek(SeekFrom::Start(header.documents_offset))?;
+        let main = ChecksumReader::new(BufReader::new(main_raw));
+        let mut contents = ChecksumReader::new(BufReader::new(contents_raw));
+        let table = read_documents(&mut contents)?;
+        contents.check(header.documents_crc, Section::Documents)?;
+
+        let mut reader = IndexFileReader {
+            main,
+            contents,
+            header,
+            tokenizer: table.tokenizer,
+            encoding: table.encoding,
+            documents: table.documents,
+            doc_id_offset: 0,
+            next: None
+        };
 
         // We always read ahead one entry, so load the first entry right away.
-        let first = IndexFileReader::read_entry(&mut contents)?;
+        reader.read_next_entry()?;
 
-        fs::remove_file(filename)?;  // YOLO
+        Ok(reader)
+    }
 
-        Ok(IndexFileReader {
-            main: main,
-            contents: contents,
-            next: first
-        })
+    /// Read the next entry from the table of contents into `self.next`. At
+    /// the end of the file, check the checksums of the table of contents and
+    /// of the main data, which has all been read by then.
+    fn read_next_entry(&mut self) -> io::Result<()> {
+        self.next = Self::read_entry(&mut self.contents)?;
+        if self.next.is_none() {
+            self.contents.check(self.header.contents_crc, Section::Contents)?;
+            self.main.check(self.header.main_crc, Section::Main)?;
+        }
+        Ok(())
     }
 
     /// Read the next entry from the table of contents.
     ///
     /// Returns `Ok(None)` if we have reached the end of the file.
-    fn read_entry(f: &mut BufReader<File>) -> io::Result<Option<Entry>> {
+    pub fn read_entry<R: Read>(f: &mut R) -> io::Result<Option<Entry>> {
         // If the first read here fails with `UnexpectedEof`,
         // that's considered a success, with no entry read.
         let offset = match f.read_u64::<LittleEndian>() {
@@ -109,56 +157,111 @@ impl IndexFileReader {
         let nbytes = f.read_u64::<LittleEndian>()?;
         let df = f.read_u32::<LittleEndian>()?;
         let term_len = f.read_u32::<LittleEndian>()? as usize;
-        let mut bytes = Vec::with_capacity(term_len);
-        bytes.resize(term_len, 0);
+        let mut bytes = vec![0; term_len];
         f.read_exact(&mut bytes)?;
         let term = match String::from_utf8(bytes) {
             Ok(s) => s,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "unicode fail"))
+            Err(_) => return Err(io::Error::other("unicode fail"))
         };
 
         Ok(Some(Entry {
-            term: term,
-            df: df,
-            offset: offset,
-            nbytes: nbytes
+            term,
+            df,
+            offset,
+            nbytes
         }))
         
     }
 
+    /// The spec of the tokenizer used to make this file.
+    pub fn tokenizer(&self) -> &str {
+        &self.tokenizer
+    }
+
+    /// How the documents in this file were decoded.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// One more than the highest document id in this file (after
+    /// renumbering), or 0 if it has no documents.
+    pub fn doc_id_limit(&self) -> u64 {
+        self.documents.last().map_or(0, |d| d.id as u64 + 1)
+    }
+
+    /// Add `offset` to every document id in this file, in both the document
+    /// table and the hits. This is for merging index files that were built
+    /// separately, whose document ids would otherwise collide.
+    ///
+    /// Call this at most once, before reading anything else from the file.
+    /// The caller must make sure the new ids fit in a u32.
+    pub fn renumber(&mut self, offset: u32) {
+        for doc in &mut self.documents {
+            doc.id += offset;
+        }
+        self.doc_id_offset = offset;
+    }
+
+    /// True if the hits in this file can be copied verbatim, with
+    /// `move_entry_to`, to a file that uses the given format.
+    pub fn can_copy_to(&self, format: PostingsFormat) -> bool {
+        self.header.format == format && self.doc_id_offset == 0
+    }
+
+    /// Take the document table out of this reader, leaving it empty.
+    pub fn take_documents(&mut self) -> Vec<DocumentInfo> {
+        std::mem::take(&mut self.documents)
+    }
+
     /// Borrow a reference to the next entry in the table of contents.
     /// (Since we always read ahead one entry, this method can't fail.)
     ///
     /// Returns `None` if we've reached the end of the file.
     pub fn peek(&self) -> Option<&Entry> { self.next.as_ref() }
 
-    /// True if the next entry is for the given term.
-    pub fn is_at(&self, term: &str) -> bool {
-        match self.next {
-            Some(ref e) => e.term == term,
-            None => false
+    /// Take the current entry and read its index data, then read the header
+    /// for the next entry.
+    fn take_entry_data(&mut self) -> io::Result<(Entry, Vec<u8>)> {
+        let e = self.next.take().expect("no entry to move");
+        if e.nbytes > usize::MAX as u64 {
+            // This can only happen on 32-bit platforms.
+            return Err(io::Error::other("computer not big enough to hold index entry"));
         }
+        let mut buf = vec![0; e.nbytes as usize];
+        self.main.read_exact(&mut buf)?;
+
+        self.read_next_entry()?;
+        Ok((e, buf))
     }
 
     /// Copy the current entry to the specified output stream, then read the
-    /// header for the next entry.
-    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<()> {
-        // This block limits the scope of borrowing `self.next` (for `e`),
-        // because after this block is over we'll want to assign to `self.next`.
-        {
-            let e = self.next.as_ref().expect("no entry to move");
-            if e.nbytes > usize::max_value() as u64 {
-                // This can only happen on 32-bit platforms.
-                return Err(io::Error::new(io::ErrorKind::Other,
-                                "computer not big enough to hold index entry"));
+    /// header for the next entry. Returns the entry that was copied.
+    pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<Entry> {
+        let (entry, buf) = self.take_entry_data()?;
+        out.write_main(&buf)?;
+        Ok(entry)
+    }
+
+    /// Decode the hits for the current entry and add the ones for documents
+    /// where `keep(doc_id)` returns true to `out`, then read the header for
+    /// the next entry. Returns the entry, and the number of hits added.
+    ///
+    /// This is slower than `move_entry_to`, but it works even if `out` uses
+    /// a different format, or already contains hits for this term.
+    pub fn move_postings_to<F>(&mut self, out: &mut PostingsEncoder, keep: F)
+        -> io::Result<(Entry, u32)>
+        where F: Fn(u32) -> bool
+    {
+        let (entry, buf) = self.take_entry_data()?;
+        let mut count = 0;
+        for posting in Postings::new(self.header.format, &buf) {
+            let mut posting = posting?;
+            posting.doc_id += self.doc_id_offset;
+            if keep(posting.doc_id) {
+                out.push(&posting);
+                count += 1;
             }
-            let mut buf = Vec::with_capacity(e.nbytes as usize);
-            buf.resize(e.nbytes as usize, 0);
-            self.main.read_exact(&mut buf)?;
-            out.write_main(&buf)?;
         }
-        
-        self.next = Self::read_entry(&mut self.contents)?;
-        Ok(())
+        Ok((entry, count))
     }
 }
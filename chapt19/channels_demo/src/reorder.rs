@@ -0,0 +1,219 @@
+//! Putting the output of parallel workers back in order.
+//!
+//! When several threads share a queue of work, the results come out in
+//! whatever order the threads happen to finish. `InOrder` takes results that
+//! are numbered consecutively and hands them back in numerical order,
+//! holding on to any that arrive early until the ones before them show up.
+//!
+//! If one item is slow, everything after it piles up in the meantime. A
+//! `Window` puts a limit on that, by holding back the threads producing the
+//! items.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use crate::channel::Weight;
+
+/// An iterator adapter that sorts `(number, item)` pairs by number.
+///
+/// The numbers must be consecutive, starting at the `first` number passed to
+/// `InOrder::new`, and each must appear exactly once. The adapter only holds
+/// the items that arrived ahead of their turn, so if the inner iterator is
+/// nearly in order already, very little is buffered.
+pub struct InOrder<I, T> {
+    inner: I,
+    next: usize,
+
+    /// The items that arrived early, with their weights.
+    early: BTreeMap<usize, (T, usize)>,
+
+    /// The total weight of the items in `early`.
+    buffered: usize,
+
+    window: Option<Arc<Window>>
+}
+
+impl<I, T: Weight> InOrder<I, T> where I: Iterator<Item = (usize, T)> {
+    pub fn new(inner: I, first: usize) -> InOrder<I, T> {
+        InOrder { inner, next: first, early: BTreeMap::new(), buffered: 0, window: None }
+    }
+
+    /// Keep `window` up to date with our progress, so that the producers
+    /// can wait for room.
+    pub fn with_window(mut self, window: Arc<Window>) -> InOrder<I, T> {
+        self.window = Some(window);
+        self
+    }
+
+    /// Move on to the item after number `n`, which is being returned.
+    fn advance(&mut self, n: usize, item: T) -> Option<T> {
+        self.next = n + 1;
+        if let Some(window) = &self.window {
+            window.update(self.next, self.buffered);
+        }
+        Some(item)
+    }
+}
+
+impl<I, T: Weight> Iterator for InOrder<I, T> where I: Iterator<Item = (usize, T)> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some((item, weight)) = self.early.remove(&self.next) {
+                self.buffered -= weight;
+                return self.advance(self.next, item);
+            }
+            match self.inner.next() {
+                Some((n, item)) if n == self.next => return self.advance(n, item),
+                Some((n, item)) => {
+                    let weight = item.weight();
+                    self.buffered += weight;
+                    self.early.insert(n, (item, weight));
+                    if let Some(window) = &self.window {
+                        window.update(self.next, self.buffered);
+                    }
+                }
+                None => {
+                    // The input ended with a gap in the numbering. That means
+                    // something upstream failed; pass along what we have.
+                    let (n, (item, weight)) = self.early.pop_first()?;
+                    self.buffered -= weight;
+                    return self.advance(n, item);
+                }
+            }
+        }
+    }
+}
+
+impl<I, T> Drop for InOrder<I, T> {
+    fn drop(&mut self) {
+        // Nobody is waiting for the producers' results anymore.
+        if let Some(window) = &self.window {
+            window.close();
+        }
+    }
+}
+
+/// Limits how far ahead of an `InOrder` the threads producing its items can
+/// get.
+///
+/// Before starting on item number `n`, a producer calls `wait_for_room(n)`,
+/// which waits while the items that arrived early add up to `capacity`
+/// bytes or more. The item the `InOrder` needs next is never held back, so
+/// there's always progress. Producers already at work when the limit is
+/// reached still finish their items, so the limit can be exceeded by up to
+/// one item per producer.
+pub struct Window {
+    state: Mutex<WindowState>,
+    changed: Condvar,
+    capacity: usize
+}
+
+struct WindowState {
+    /// The number of the item the `InOrder` needs next.
+    next: usize,
+
+    /// The total weight of the items that arrived early.
+    buffered: usize,
+
+    /// True if nobody should wait anymore: the `InOrder` is gone, or an item
+    /// will never arrive, because a producer failed.
+    closed: bool
+}
+
+impl Window {
+    pub fn new(first: usize, capacity: usize) -> Window {
+        Window {
+            state: Mutex::new(WindowState { next: first, buffered: 0, closed: false }),
+            changed: Condvar::new(),
+            capacity
+        }
+    }
+
+    /// Wait until there's room to start on item number `n`.
+    pub fn wait_for_room(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed && n > state.next && state.buffered >= self.capacity {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Stop holding back producers.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn update(&self, next: usize, buffered: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.next = next;
+        state.buffered = buffered;
+        drop(state);
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    impl Weight for char {
+        fn weight(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn reorders() {
+        let input = vec![(12, 'c'), (10, 'a'), (13, 'd'), (11, 'b'), (14, 'e')];
+        let output: String = InOrder::new(input.into_iter(), 10).collect();
+        assert_eq!(output, "abcde");
+
+        let gap = vec![(1, 'b'), (3, 'd')];
+        let output: String = InOrder::new(gap.into_iter(), 0).collect();
+        assert_eq!(output, "bd");
+    }
+
+    #[test]
+    fn window() {
+        let capacity = 3;
+        let window = Arc::new(Window::new(0, capacity));
+        // As in the indexing pipeline, the channel to the consumer is
+        // bounded too; otherwise the producers could fill it instead.
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let consumer = {
+            let window = window.clone();
+            thread::spawn(move || {
+                InOrder::new(receiver.into_iter(), 0).with_window(window).collect::<String>()
+            })
+        };
+
+        // Two producers take turns with items 1 to 9. Item 0 comes last.
+        let started = Arc::new(AtomicUsize::new(0));
+        let producers: Vec<_> = (1..=2).map(|first| {
+            let (window, sender, started) = (window.clone(), sender.clone(), started.clone());
+            thread::spawn(move || {
+                for n in (first..10).step_by(2) {
+                    window.wait_for_room(n);
+                    started.fetch_add(1, Ordering::SeqCst);
+                    sender.send((n, (b'a' + n as u8) as char)).unwrap();
+                }
+            })
+        }).collect();
+        thread::sleep(Duration::from_millis(100));
+        // Each producer can get ahead by an item that's been checked but not
+        // yet sent, and one that's been sent but not yet counted.
+        assert!(started.load(Ordering::SeqCst) <= capacity + 2 * 2);
+
+        sender.send((0, 'a')).unwrap();
+        drop(sender);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(consumer.join().unwrap(), "abcdefghij");
+    }
+}
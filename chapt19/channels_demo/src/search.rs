@@ -0,0 +1,87 @@
+//! Running a query against an index: parsing it, ranking the documents that
+//! match, and making a snippet for each of the best ones. This is shared by
+//! `fingertips search` and `fingertips serve`.
+
+use std::io;
+use std::path::PathBuf;
+use crate::mmap::IndexSearcher;
+use crate::query::Query;
+use crate::rank::Bm25;
+use crate::snippet::{document_snippet, Highlight};
+use crate::tokenize::Tokenizer;
+
+/// The results of a search.
+pub struct SearchResults {
+    /// The number of documents that matched the query.
+    pub total_matches: usize,
+
+    /// The best matches, highest score first.
+    pub hits: Vec<SearchHit>
+}
+
+/// One document in the results of a search.
+pub struct SearchHit {
+    pub doc_id: u32,
+
+    /// The document's path, or `None` if it's missing from the document
+    /// table.
+    pub path: Option<PathBuf>,
+
+    /// Other files with the same contents (see `dedup.rs`).
+    pub duplicates: Vec<PathBuf>,
+
+    pub score: f64,
+
+    /// An excerpt of the document, if snippets were asked for and the file
+    /// is still there, unchanged.
+    pub snippet: Option<String>
+}
+
+/// Run `query` against `index`, whose documents were broken into terms by
+/// `tokenizer`, and return the `limit` documents that match best. With
+/// `snippets`, also make an excerpt of each one, highlighted that way.
+///
+/// A query that can't be parsed is an error of kind `InvalidInput`.
+pub fn search(index: &IndexSearcher,
+              tokenizer: &dyn Tokenizer,
+              query: &str,
+              bm25: Bm25,
+              limit: usize,
+              snippets: Option<Highlight>) -> io::Result<SearchResults> {
+    let query = Query::parse(query, tokenizer)
+        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?
+        .expand(index)?;
+    let ranking = bm25.rank(&query, index, limit)?;
+    let postings = match snippets {
+        Some(_) => {
+            // Only the hits in the documents shown are needed.
+            let mut shown: Vec<u32> = ranking.top.iter().map(|doc| doc.doc_id).collect();
+            shown.sort_unstable();
+            query.scoring_terms().into_iter()
+                .map(|(term, scope)| {
+                    index.postings_in(term, scope)?
+                        .filter(|p| p.as_ref().map_or(true, |p| shown.binary_search(&p.doc_id).is_ok()))
+                        .collect::<io::Result<Vec<_>>>()
+                })
+                .collect::<io::Result<Vec<_>>>()?
+        }
+        None => vec![]
+    };
+
+    let hits = ranking.top.into_iter().map(|doc| {
+        let info = index.document(doc.doc_id);
+        let snippet = info.zip(snippets).and_then(|(info, highlight)| {
+            document_snippet(doc.doc_id, info, &postings, tokenizer, index.encoding(), highlight)
+        });
+        SearchHit {
+            doc_id: doc.doc_id,
+            path: info.map(|info| info.path.clone()),
+            duplicates: info.map_or(vec![], |info| {
+                info.duplicates.iter().map(|dup| dup.path.clone()).collect()
+            }),
+            score: doc.score,
+            snippet
+        }
+    }).collect();
+    Ok(SearchResults { total_matches: ranking.total_matches, hits })
+}
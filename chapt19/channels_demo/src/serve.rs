@@ -0,0 +1,334 @@
+//! A local HTTP search service: `fingertips serve`.
+//!
+//! `GET /search?q=QUERY` runs a query and returns the results as JSON:
+//!
+//! ```text
+//! {"query": "borrow checker", "total": 6,
+//!  "hits": [{"path": "notes1.txt", "duplicates": ["mirror/notes1.txt"],
+//!            "score": 4.65, "snippet": "..."}, ...]}
+//! ```
+//!
+//! `duplicates` lists other files with the same contents (see `dedup.rs`).
+//!
+//! An optional `n` parameter says how many hits to return (default 10).
+//! Snippets are HTML, with the matching words in `<mark>` tags. A query that
+//! can't be parsed gets a 400 response with a JSON body `{"error": "..."}`.
+//! `GET /` is a small page for searching from a browser.
+//!
+//! Rebuilding an index replaces the file atomically (see `tmp::publish`),
+//! so before each search, the server checks whether the file has changed,
+//! and if it has, opens the new one. Other searches carry on with the old
+//! index while the new one loads; its memory map stays valid, since it maps
+//! the old file, not the name.
+
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde::{Deserialize, Serialize};
+use crate::mmap::IndexSearcher;
+use crate::rank::Bm25;
+use crate::search::search;
+use crate::snippet::Highlight;
+use crate::tokenize::{self, Tokenizer};
+
+/// The most hits a single request can ask for.
+const MAX_HITS: usize = 1000;
+
+/// Enough about a file to notice when it has been replaced.
+///
+/// `publish` renames a new file into place, so the file at the path is a
+/// different file, with a different device and inode number, even if it
+/// has the same size and modification time (which is only so precise).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    file_id: (u64, u64)
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> io::Result<FileStamp> {
+        let metadata = path.metadata()?;
+        Ok(FileStamp {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            file_id: file_id(&metadata)
+        })
+    }
+}
+
+/// The device and inode number of a file.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+/// An open index, ready to search.
+struct LoadedIndex {
+    index: IndexSearcher,
+    tokenizer: Box<dyn Tokenizer>
+}
+
+impl LoadedIndex {
+    fn open(path: &Path) -> io::Result<LoadedIndex> {
+        let index = IndexSearcher::open(path)?;
+        let tokenizer = tokenize::from_spec(index.tokenizer())
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+        Ok(LoadedIndex { index, tokenizer })
+    }
+}
+
+/// The index being served.
+pub struct Server {
+    path: PathBuf,
+    bm25: Bm25,
+
+    /// The index most recently opened, and the stamp of the file when we
+    /// last looked at it.
+    current: Mutex<(Arc<LoadedIndex>, FileStamp)>
+}
+
+/// The query parameters for `/search`.
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    n: Option<usize>
+}
+
+/// The response to `/search`.
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    query: String,
+    total: usize,
+    hits: Vec<Hit>
+}
+
+#[derive(Debug, Serialize)]
+struct Hit {
+    path: Option<String>,
+    duplicates: Vec<String>,
+    score: f64,
+    snippet: Option<String>
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String
+}
+
+impl Server {
+    /// Open the index file at `path` to serve it.
+    pub fn new(path: PathBuf, bm25: Bm25) -> io::Result<Server> {
+        let stamp = FileStamp::of(&path)?;
+        let loaded = LoadedIndex::open(&path)?;
+        Ok(Server { path, bm25, current: Mutex::new((Arc::new(loaded), stamp)) })
+    }
+
+    /// The index to search: the one we have open, unless the file has been
+    /// replaced since, in which case open the new file. If the new file
+    /// can't be opened, keep using the old one, and don't try again until
+    /// the file changes again.
+    ///
+    /// The new file is opened without holding the lock, so that other
+    /// searches can go on using the old index meanwhile.
+    fn index(&self) -> Arc<LoadedIndex> {
+        let stamp = FileStamp::of(&self.path);
+        {
+            let mut current = self.current.lock().unwrap();
+            match stamp {
+                Ok(stamp) if stamp != current.1 => {
+                    // Only one request loads each new file; the others
+                    // carry on with the old index.
+                    current.1 = stamp;
+                }
+                _ => return current.0.clone()
+            }
+        }
+
+        let stamp = stamp.unwrap();
+        let loaded = match LoadedIndex::open(&self.path) {
+            Ok(loaded) => Arc::new(loaded),
+            Err(err) => {
+                eprintln!("warning: can't reload {}: {}; still using the old index",
+                          self.path.display(), err);
+                return self.current.lock().unwrap().0.clone();
+            }
+        };
+        let mut current = self.current.lock().unwrap();
+        // If the file was replaced again while we were loading it, whoever
+        // noticed that is loading the newer one; don't undo their work.
+        if current.1 == stamp {
+            eprintln!("reloaded {}", self.path.display());
+            current.0 = loaded.clone();
+        }
+        loaded
+    }
+
+    fn search(&self, query: &str, limit: usize) -> io::Result<SearchResponse> {
+        let loaded = self.index();
+        let results = search(&loaded.index, &*loaded.tokenizer, query, self.bm25, limit,
+                             Some(Highlight::Html))?;
+        let hits = results.hits.into_iter()
+            .map(|hit| Hit {
+                path: hit.path.map(|path| path.to_string_lossy().into_owned()),
+                duplicates: hit.duplicates.iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+                score: hit.score,
+                snippet: hit.snippet
+            })
+            .collect();
+        Ok(SearchResponse { query: query.to_string(), total: results.total_matches, hits })
+    }
+}
+
+async fn get_search(server: web::Data<Server>, params: web::Query<SearchParams>) -> HttpResponse {
+    let SearchParams { q, n } = params.into_inner();
+    let limit = n.unwrap_or(10).min(MAX_HITS);
+    let server = server.into_inner();
+
+    // Searching reads the index, and making snippets reads the documents,
+    // so do it on a thread where blocking is OK.
+    match web::block(move || server.search(&q, limit)).await {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(err)) if err.kind() == io::ErrorKind::InvalidInput =>
+            HttpResponse::BadRequest().json(ErrorResponse { error: err.to_string() }),
+        Ok(Err(err)) =>
+            HttpResponse::InternalServerError().json(ErrorResponse { error: err.to_string() }),
+        Err(err) =>
+            HttpResponse::InternalServerError().json(ErrorResponse { error: err.to_string() })
+    }
+}
+
+async fn get_index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SEARCH_PAGE)
+}
+
+/// The page served at `/`. It calls `/search` and shows the results.
+const SEARCH_PAGE: &str = r#"<!DOCTYPE html>
+<meta charset="utf-8">
+<title>fingertips</title>
+<style>
+  body { font-family: sans-serif; max-width: 50em; margin: 2em auto; }
+  input { width: 30em; }
+  .path { font-weight: bold; }
+  .score, .duplicates { color: gray; }
+  mark { background: #ff8; }
+</style>
+<form id="form">
+  <input id="q" name="q" autofocus placeholder="rust AND (thread* OR channel)">
+  <button type="submit">Search</button>
+</form>
+<p id="status"></p>
+<ol id="results"></ol>
+<script>
+document.getElementById("form").addEventListener("submit", async event => {
+  event.preventDefault();
+  const q = document.getElementById("q").value;
+  const response = await fetch("/search?q=" + encodeURIComponent(q));
+  const body = await response.json();
+  const status = document.getElementById("status");
+  const results = document.getElementById("results");
+  results.replaceChildren();
+  if (!response.ok) {
+    status.textContent = "error: " + body.error;
+    return;
+  }
+  status.textContent = body.total + " documents";
+  for (const hit of body.hits) {
+    const item = document.createElement("li");
+    const path = document.createElement("span");
+    path.className = "path";
+    path.textContent = hit.path;
+    const score = document.createElement("span");
+    score.className = "score";
+    score.textContent = " (score " + hit.score.toFixed(3) + ")";
+    const duplicates = document.createElement("div");
+    duplicates.className = "duplicates";
+    duplicates.textContent = hit.duplicates.length ? "also " + hit.duplicates.join(", ") : "";
+    const snippet = document.createElement("div");
+    snippet.innerHTML = hit.snippet || "";
+    item.append(path, score, duplicates, snippet);
+    results.append(item);
+  }
+});
+</script>
+"#;
+
+/// Serve the index file `path` over HTTP at `host:port` until killed.
+pub fn serve(path: PathBuf, host: &str, port: u16, bm25: Bm25) -> io::Result<()> {
+    let server = web::Data::new(Server::new(path.clone(), bm25)?);
+    actix_web::rt::System::new().block_on(async move {
+        let http = HttpServer::new(move || {
+            App::new()
+                .app_data(server.clone())
+                .route("/", web::get().to(get_index))
+                .route("/search", web::get().to(get_search))
+        }).bind((host, port))?;
+        println!("Serving {} on http://{}:{}/...", path.display(), host, port);
+        http.run().await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docs::{DocumentInfo, Encoding};
+    use crate::field::Field;
+    use crate::index::InMemoryIndex;
+    use crate::tmp::{publish, TmpDir};
+    use crate::tokenize::UnicodeTokenizer;
+    use crate::write::write_index_to_tmp_file;
+
+    /// Build an index of a single document, `text`, and put it at `dest`.
+    fn build(tmp_dir: &TmpDir, text: &str, dest: &Path) {
+        let info = DocumentInfo { path: PathBuf::from("doc.txt"), ..DocumentInfo::default() };
+        let fields = vec![(Field::Body, text.to_string())];
+        let index = InMemoryIndex::from_single_document(0, info, fields, &UnicodeTokenizer).unwrap();
+        let file = write_index_to_tmp_file(index, "unicode", Encoding::Utf8, tmp_dir).unwrap();
+        publish(&file, dest).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let (dest, _) = tmp_dir.create().unwrap();
+        build(&tmp_dir, "hello world", &dest);
+        let server = Server::new(dest.clone(), Bm25::default()).unwrap();
+        assert_eq!(server.search("hello", 10).unwrap().total, 1);
+        assert_eq!(server.search("goodbye", 10).unwrap().total, 0);
+        assert!(server.search("hello AND", 10).is_err());
+
+        build(&tmp_dir, "goodbye, cruel world", &dest);
+        let response = server.search("goodbye", 10).unwrap();
+        assert_eq!(response.total, 1);
+        assert_eq!(response.hits[0].path.as_deref(), Some("doc.txt"));
+
+        // A replacement is noticed even if it's the same size and has the
+        // same modification time.
+        let before = dest.metadata().unwrap();
+        build(&tmp_dir, "goodbye, cruel sword", &dest);
+        let after = dest.metadata().unwrap();
+        assert_eq!(before.len(), after.len());
+        std::fs::File::options().write(true).open(&dest).unwrap()
+            .set_modified(before.modified().unwrap()).unwrap();
+        assert_eq!(server.search("sword", 10).unwrap().total, 1);
+
+        // A damaged replacement is ignored.
+        let (damaged, _) = tmp_dir.create().unwrap();
+        std::fs::write(&damaged, b"not an index").unwrap();
+        publish(&damaged, &dest).unwrap();
+        assert_eq!(server.search("goodbye", 10).unwrap().total, 1);
+    }
+}
@@ -0,0 +1,46 @@
+//! Sizes in bytes, as given on the command line.
+
+use std::str::FromStr;
+
+/// A number of bytes, written as a number with an optional suffix: `K`, `M`,
+/// `G`, or `T` for kibibytes, mebibytes, and so on. `512M` is 512 * 2^20
+/// bytes. A `B` after the suffix, as in `512MB`, is allowed, and so is
+/// lowercase.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ByteSize, String> {
+        let upper = s.trim().to_ascii_uppercase();
+        let digits = upper.strip_suffix('B').unwrap_or(&upper);
+        let (number, shift) = match digits.char_indices().last() {
+            Some((i, 'K')) => (&digits[..i], 10),
+            Some((i, 'M')) => (&digits[..i], 20),
+            Some((i, 'G')) => (&digits[..i], 30),
+            Some((i, 'T')) => (&digits[..i], 40),
+            _ => (digits, 0)
+        };
+        number.trim().parse::<usize>().ok()
+            .and_then(|n| n.checked_mul(1usize.checked_shl(shift)?))
+            .map(ByteSize)
+            .ok_or_else(|| format!("bad size `{}`; try something like `512M`", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!("512M".parse(), Ok(ByteSize(512 << 20)));
+        assert_eq!("2g".parse(), Ok(ByteSize(2 << 30)));
+        assert_eq!("100KB".parse(), Ok(ByteSize(100 << 10)));
+        assert_eq!("4096".parse(), Ok(ByteSize(4096)));
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("M".parse::<ByteSize>().is_err());
+        assert!("12Q".parse::<ByteSize>().is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Snippets: short excerpts of a document that show why it matched.
+//!
+//! The hits in an index say where each term appears as a word position
+//! (see `Hit`), not a byte offset, so the index alone can't quote the text.
+//! Instead, when showing a result, we read the file again and tokenize it
+//! with the same tokenizer that built the index, using
+//! `Tokenizer::tokenize_spans` to learn which bytes each position came from.
+//! If the file has changed since it was indexed, the positions would point
+//! at the wrong words, so we check the file's hash first and show no
+//! snippet.
+
+use std::ops::Range;
+use std::str::FromStr;
+use crate::docs::{DocumentInfo, Encoding};
+use crate::field::Field;
+use crate::postings::Posting;
+use crate::tokenize::Tokenizer;
+
+/// How many words a snippet shows.
+pub const SNIPPET_WORDS: u32 = 24;
+
+/// How to mark the matching words in a snippet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Highlight {
+    /// Don't mark them at all.
+    Plain,
+
+    /// Bold red, using ANSI escape codes, for a terminal.
+    Ansi,
+
+    /// Wrap them in `<mark>` tags, and escape the rest of the text as HTML.
+    Html
+}
+
+impl Highlight {
+    fn start(self) -> &'static str {
+        match self {
+            Highlight::Plain => "",
+            Highlight::Ansi => "\x1b[1;31m",
+            Highlight::Html => "<mark>"
+        }
+    }
+
+    fn end(self) -> &'static str {
+        match self {
+            Highlight::Plain => "",
+            Highlight::Ansi => "\x1b[0m",
+            Highlight::Html => "</mark>"
+        }
+    }
+
+    /// Append `text` to `out`, with each run of whitespace turned into a
+    /// single space, so that a snippet fits on one line.
+    fn push_text(self, out: &mut String, text: &str) {
+        for ch in text.chars() {
+            match ch {
+                _ if ch.is_whitespace() => {
+                    if !out.ends_with(' ') {
+                        out.push(' ');
+                    }
+                }
+                '&' if self == Highlight::Html => out.push_str("&amp;"),
+                '<' if self == Highlight::Html => out.push_str("&lt;"),
+                '>' if self == Highlight::Html => out.push_str("&gt;"),
+                '"' if self == Highlight::Html => out.push_str("&quot;"),
+                _ if ch.is_control() => {}
+                _ => out.push(ch)
+            }
+        }
+    }
+}
+
+impl FromStr for Highlight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Highlight, String> {
+        match s {
+            "none" | "plain" => Ok(Highlight::Plain),
+            "ansi" => Ok(Highlight::Ansi),
+            "html" => Ok(Highlight::Html),
+            _ => Err(format!("unknown highlighting `{}` (use none, ansi, or html)", s))
+        }
+    }
+}
+
+/// Choose the `width` consecutive positions that contain the most `hits`,
+/// returning the first of them. When several windows tie, the earliest wins.
+/// `hits` must be sorted and must not be empty.
+fn best_window(hits: &[u32], width: u32) -> u32 {
+    let mut best = (0, 0);
+    let mut end = 0;
+    for (i, &start) in hits.iter().enumerate() {
+        while end < hits.len() && hits[end] - start < width {
+            end += 1;
+        }
+        if end - i > best.0 {
+            best = (end - i, i);
+        }
+    }
+
+    // Center the hits in the window, rather than starting with the first.
+    let first = hits[best.1];
+    let last = hits[best.1 + best.0 - 1];
+    first.saturating_sub((width - 1 - (last - first)) / 2)
+}
+
+/// Make a snippet for the document `info` (document number `doc_id`),
+/// highlighting the terms whose hits are in `postings`. The file is decoded
+/// as `encoding`, as it was when it was indexed.
+///
+/// Returns `None` if none of the terms are in the document, or if the file
+/// (and every exact copy of it) can't be read or has changed since it was
+/// indexed.
+pub fn document_snippet(doc_id: u32,
+                        info: &DocumentInfo,
+                        postings: &[Vec<Posting>],
+                        tokenizer: &dyn Tokenizer,
+                        encoding: Encoding,
+                        highlight: Highlight) -> Option<String> {
+    // Only the body is part of the text; the other fields come after it.
+    let mut hits: Vec<u32> = postings.iter()
+        .filter_map(|list| list.binary_search_by_key(&doc_id, |p| p.doc_id).ok().map(|i| &list[i]))
+        .flat_map(|p| p.offsets.iter().zip(&p.fields))
+        .filter(|&(_, &field)| field == Field::Body)
+        .map(|(&offset, _)| offset)
+        .collect();
+    hits.sort_unstable();
+    hits.dedup();
+
+    // If the file is gone, an exact copy of it will do.
+    let copies = info.duplicates.iter().filter(|dup| dup.hash == info.hash);
+    let text = std::iter::once(&info.path)
+        .chain(copies.map(|dup| &dup.path))
+        .find_map(|path| {
+            let (current, text) = DocumentInfo::load(path.clone(), encoding).ok()?;
+            (current.hash == info.hash).then_some(text.text)
+        })?;
+    make_snippet(&text, tokenizer, &hits, highlight)
+}
+
+/// Make a snippet of `text`, a document that was indexed with `tokenizer`.
+/// `hits` are the positions of the words that matched the query, sorted.
+/// Returns `None` if there are no hits.
+fn make_snippet(text: &str, tokenizer: &dyn Tokenizer, hits: &[u32], highlight: Highlight)
+    -> Option<String>
+{
+    if hits.is_empty() {
+        return None;
+    }
+    let start = best_window(hits, SNIPPET_WORDS);
+    let end = start + SNIPPET_WORDS;
+
+    // The tokens in the window, and which of them to highlight. Filters like
+    // `ngram` give several positions the same span, so merge those.
+    let mut words: Vec<(Range<usize>, bool)> = vec![];
+    let (mut more_before, mut more_after) = (false, false);
+    for (token, span) in tokenizer.tokenize_spans(text) {
+        if token.position < start {
+            more_before = true;
+            continue;
+        }
+        if token.position >= end {
+            more_after = true;
+            break;
+        }
+        let hit = hits.binary_search(&token.position).is_ok();
+        match words.last_mut() {
+            Some((last, last_hit)) if last.start == span.start => *last_hit |= hit,
+            _ => words.push((span, hit))
+        }
+    }
+
+    // If the window reaches the beginning or end of the document, include
+    // any punctuation there too.
+    let first = if more_before { words.first()?.0.start } else { 0 };
+    let last = if more_after { words.last()?.0.end } else { text.len() };
+
+    let mut out = String::new();
+    if more_before {
+        out.push_str("...");
+    }
+    let mut pos = first;
+    for (span, _) in words.into_iter().filter(|&(_, hit)| hit) {
+        highlight.push_text(&mut out, &text[pos..span.start]);
+        out.push_str(highlight.start());
+        highlight.push_text(&mut out, &text[span.clone()]);
+        out.push_str(highlight.end());
+        pos = span.end;
+    }
+    highlight.push_text(&mut out, &text[pos..last]);
+    let mut out = out.trim().to_string();
+    if more_after {
+        out.push_str("...");
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::tmp::TmpDir;
+    use crate::tokenize::{from_spec, UnicodeTokenizer};
+
+    #[test]
+    fn windows() {
+        assert_eq!(best_window(&[5], 5), 3);
+        assert_eq!(best_window(&[0], 5), 0);
+        assert_eq!(best_window(&[1, 20, 22, 23, 50], 5), 20);
+        assert_eq!(best_window(&[1, 2, 40, 41], 4), 0);
+    }
+
+    #[test]
+    fn snippets() {
+        let text = "The borrow checker\n  rejects this <code>, and\tthe checker is right.";
+        assert_eq!(make_snippet(text, &UnicodeTokenizer, &[1, 2], Highlight::Html).unwrap(),
+                   "The <mark>borrow</mark> <mark>checker</mark> rejects this &lt;code&gt;, \
+                    and the checker is right.");
+        assert_eq!(make_snippet(text, &UnicodeTokenizer, &[8], Highlight::Ansi).unwrap(),
+                   "The borrow checker rejects this <code>, and the \x1b[1;31mchecker\x1b[0m \
+                    is right.");
+        assert_eq!(make_snippet(text, &UnicodeTokenizer, &[], Highlight::Plain), None);
+
+        let long: String = (0..100).map(|n| format!("w{} ", n)).collect();
+        assert_eq!(make_snippet(&long, &UnicodeTokenizer, &[50], Highlight::Plain).unwrap(),
+                   (39..63).map(|n| format!("w{}", n)).collect::<Vec<_>>().join(" ")
+                       .replace("w39", "...w39") + "...");
+
+        let ngrams = from_spec("unicode,ngram:3").unwrap();
+        assert_eq!(make_snippet("a rust b", &*ngrams, &[2], Highlight::Html).unwrap(),
+                   "a <mark>rust</mark> b");
+    }
+
+    #[test]
+    fn encodings() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let (path, _) = tmp_dir.create().unwrap();
+        fs::write(&path, b"a na\xefve reader").unwrap();
+        let (info, _) = DocumentInfo::load(path, Encoding::Latin1).unwrap();
+        let postings = [vec![Posting::body(0, vec![2])]];
+        let snippet = |encoding| {
+            document_snippet(0, &info, &postings, &UnicodeTokenizer, encoding, Highlight::Html)
+        };
+        assert_eq!(snippet(Encoding::Latin1).unwrap(), "a na\u{ef}ve <mark>reader</mark>");
+
+        // Decoded any other way, the words are numbered differently.
+        assert_eq!(snippet(Encoding::Utf8Lossy).unwrap(), "a na\u{fffd}<mark>ve</mark> reader");
+        assert_eq!(snippet(Encoding::Utf8), None);
+    }
+}
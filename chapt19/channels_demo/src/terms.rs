@@ -0,0 +1,396 @@
+//! The term dictionary, and looking up terms by pattern.
+//!
+//! To search an index, we need to find terms in its table of contents: one
+//! term for a plain word, or every term that matches a pattern, for queries
+//! like `index*` (prefix), `f?o` (wildcard), or `colour~` (fuzzy: terms
+//! spelled almost the same). The table of contents is sorted, so all the
+//! terms with a given prefix are together. Fuzzy terms must start with the
+//! same letter as the word in the query, so that looking them up doesn't
+//! mean checking every term in the index.
+//!
+//! Keeping every term as a separate `String` would take a lot of memory for
+//! a big index. Instead, `TermDictionary` *front-codes* them: since
+//! neighboring terms in sorted order tend to start the same way (`index`,
+//! `indexed`, `indexes`, `indexing`), each term is stored as the length of
+//! the prefix it shares with the term before it, plus the rest. Every
+//! `BLOCK_SIZE` terms, a term is stored in full, so that a lookup can
+//! binary-search the blocks and then decode at most one block.
+
+use std::cmp::Ordering;
+use std::io;
+use crate::header::FormatError;
+use crate::postings::{read_varint, write_varint};
+use crate::walk::match_segment;
+
+/// How many terms to front-code in a row before storing one in full.
+const BLOCK_SIZE: usize = 16;
+
+/// What the table of contents says about one term.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TermInfo {
+    /// The number of documents that contain the term.
+    pub df: u32,
+
+    /// Where the term's hits are in the index file, and how many bytes long.
+    pub offset: u64,
+    pub nbytes: u64
+}
+
+/// A sorted set of terms, each with its `TermInfo`, stored compactly.
+#[derive(Default)]
+pub struct TermDictionary {
+    /// The front-coded terms. Each is written as two varints, the length of
+    /// the prefix it shares with the previous term and the length of the
+    /// rest, followed by the rest. The first term in each block shares
+    /// nothing.
+    data: Vec<u8>,
+
+    /// Where each block of `BLOCK_SIZE` terms starts in `data`.
+    blocks: Vec<usize>,
+
+    /// The information for each term, in order.
+    info: Vec<TermInfo>,
+
+    /// The last term added.
+    last: String
+}
+
+impl TermDictionary {
+    pub fn new() -> TermDictionary {
+        TermDictionary::default()
+    }
+
+    /// Add a term. Terms must be added in sorted order, with no duplicates.
+    pub fn push(&mut self, term: &str, info: TermInfo) -> Result<(), FormatError> {
+        if !self.info.is_empty() && term <= self.last.as_str() {
+            return Err(FormatError::UnsortedTerms);
+        }
+        let shared = if self.info.len().is_multiple_of(BLOCK_SIZE) {
+            self.blocks.push(self.data.len());
+            0
+        } else {
+            common_prefix_len(&self.last, term)
+        };
+        write_varint(&mut self.data, shared as u32);
+        write_varint(&mut self.data, (term.len() - shared) as u32);
+        self.data.extend_from_slice(&term.as_bytes()[shared..]);
+        self.info.push(info);
+        self.last.clear();
+        self.last.push_str(term);
+        Ok(())
+    }
+
+    /// Look up a term.
+    pub fn get(&self, term: &str) -> io::Result<Option<TermInfo>> {
+        let mut found = None;
+        self.scan(self.block_for(term)?, |t, info| match t.cmp(term) {
+            Ordering::Less => true,
+            Ordering::Equal => {
+                found = Some(info);
+                false
+            }
+            Ordering::Greater => false
+        })?;
+        Ok(found)
+    }
+
+    /// Find all the terms that match `pattern`, in sorted order.
+    pub fn expand(&self, pattern: &TermPattern) -> io::Result<Vec<String>> {
+        let prefix = pattern.literal_prefix();
+        let mut out = vec![];
+        self.scan(self.block_for(prefix)?, |term, _| {
+            if term < prefix {
+                return true;
+            }
+            if !term.starts_with(prefix) {
+                return false;
+            }
+            if pattern.matches(term) {
+                out.push(term.to_string());
+            }
+            true
+        })?;
+        Ok(out)
+    }
+
+    /// The block where `term` is, if it's in the dictionary: the last block
+    /// whose first term is no greater than `term`.
+    fn block_for(&self, term: &str) -> io::Result<usize> {
+        let mut error = None;
+        let after = self.blocks.partition_point(|&start| {
+            match first_term(&self.data[start..]) {
+                Ok(first) => first <= term.as_bytes(),
+                Err(err) => {
+                    error = Some(err);
+                    false
+                }
+            }
+        });
+        match error {
+            Some(err) => Err(err),
+            None => Ok(after.saturating_sub(1))
+        }
+    }
+
+    /// Call `f` with each term and its information, in order, starting at
+    /// the beginning of block number `block`, until `f` returns false.
+    ///
+    /// The dictionary is only ever built by `push`, so it shouldn't be
+    /// possible for the data not to decode; but if it doesn't, that's an
+    /// error, not a panic in the middle of a search.
+    fn scan<F>(&self, block: usize, mut f: F) -> io::Result<()>
+        where F: FnMut(&str, TermInfo) -> bool
+    {
+        let Some(&start) = self.blocks.get(block) else { return Ok(()) };
+        let mut buf = &self.data[start..];
+        let mut term = vec![];
+        for &info in &self.info[block * BLOCK_SIZE..] {
+            let shared = read_varint(&mut buf).ok_or_else(corrupt)? as usize;
+            let len = read_varint(&mut buf).ok_or_else(corrupt)? as usize;
+            if shared > term.len() || len > buf.len() {
+                return Err(corrupt());
+            }
+            term.truncate(shared);
+            term.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+            let text = std::str::from_utf8(&term).map_err(|_| corrupt())?;
+            if !f(text, info) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first term in the block at the start of `data`, which is stored whole.
+fn first_term(mut data: &[u8]) -> io::Result<&[u8]> {
+    read_varint(&mut data).ok_or_else(corrupt)?;
+    let len = read_varint(&mut data).ok_or_else(corrupt)? as usize;
+    data.get(..len).ok_or_else(corrupt)
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt term dictionary")
+}
+
+/// The length in bytes of the longest common prefix of `a` and `b` that
+/// ends on a character boundary.
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|&((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i)
+}
+
+/// A word in a query that stands for a set of terms.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TermPattern {
+    /// Terms that start with this text: `index*`.
+    Prefix(String),
+
+    /// Terms that match this glob pattern, with `*`, `?`, and `[...]` as in
+    /// filename patterns (see `walk::Pattern`): `f?o`, `*ing`.
+    Wildcard(String),
+
+    /// Terms at most this many edits away from this text, counting inserting,
+    /// deleting, or replacing a character, or swapping two adjacent ones, as
+    /// one edit each: `colour~` (2 edits), `colour~1`. The first
+    /// `FUZZY_PREFIX` characters aren't edited: the terms must start with
+    /// them.
+    Fuzzy(String, u32)
+}
+
+/// The largest edit distance allowed in a fuzzy query. Any more than this,
+/// and short words match nearly everything.
+const MAX_EDITS: u32 = 2;
+
+/// How many characters at the start of a fuzzy query must match exactly.
+/// Looking up a fuzzy query means checking every term that starts this way.
+const FUZZY_PREFIX: usize = 1;
+
+/// The longest pattern allowed, in characters, and the most `*`s it can
+/// have. A pattern may have to be matched against every term in the index,
+/// so a query shouldn't be able to make that arbitrarily slow.
+const MAX_PATTERN_CHARS: usize = 64;
+const MAX_STARS: usize = 4;
+
+impl TermPattern {
+    /// If `word`, from a query, is a pattern rather than a plain word, parse
+    /// it. Patterns aren't run through the tokenizer, only lowercased, so
+    /// that a wildcard doesn't get broken up or stemmed.
+    pub fn parse(word: &str) -> Result<Option<TermPattern>, String> {
+        let word = word.to_lowercase();
+        let pattern = TermPattern::parse_lowercase(&word)?;
+        if pattern.is_some() {
+            if word.chars().count() > MAX_PATTERN_CHARS {
+                return Err(format!("pattern `{}` is too long; use at most {} characters",
+                                   word, MAX_PATTERN_CHARS));
+            }
+            if word.matches('*').count() > MAX_STARS {
+                return Err(format!("too many `*`s in `{}`; use at most {}", word, MAX_STARS));
+            }
+        }
+        Ok(pattern)
+    }
+
+    /// `parse`, for a word that has already been lowercased.
+    fn parse_lowercase(word: &str) -> Result<Option<TermPattern>, String> {
+        // A `~` makes a fuzzy query only at the end of the word, or followed
+        // by nothing but the number of edits. Elsewhere, it's just text.
+        let fuzzy = word.rsplit_once('~')
+            .filter(|(_, edits)| edits.chars().all(|c| c.is_ascii_digit()));
+        if let Some((text, edits)) = fuzzy {
+            let edits = match edits {
+                "" => MAX_EDITS,
+                _ => match edits.parse::<u32>() {
+                    Ok(n) if n <= MAX_EDITS => n,
+                    _ => return Err(format!("bad edit distance in `{}`; use 0 to {}",
+                                            word, MAX_EDITS))
+                }
+            };
+            if text.is_empty() {
+                return Err(format!("nothing to search for in `{}`", word));
+            }
+            return Ok(Some(TermPattern::Fuzzy(text.to_string(), edits)));
+        }
+
+        match word.find(['*', '?', '[']) {
+            None => Ok(None),
+            Some(i) if i == word.len() - 1 && word.ends_with('*') =>
+                Ok(Some(TermPattern::Prefix(word[..i].to_string()))),
+            Some(_) => Ok(Some(TermPattern::Wildcard(word.to_string())))
+        }
+    }
+
+    /// The text that every matching term starts with.
+    fn literal_prefix(&self) -> &str {
+        match self {
+            TermPattern::Prefix(prefix) => prefix,
+            TermPattern::Wildcard(pattern) => match pattern.find(['*', '?', '[']) {
+                Some(i) => &pattern[..i],
+                None => pattern
+            },
+            TermPattern::Fuzzy(text, _) => match text.char_indices().nth(FUZZY_PREFIX) {
+                Some((i, _)) => &text[..i],
+                None => text
+            }
+        }
+    }
+
+    fn matches(&self, term: &str) -> bool {
+        match self {
+            TermPattern::Prefix(prefix) => term.starts_with(prefix.as_str()),
+            TermPattern::Wildcard(pattern) => match_segment(pattern, term),
+            TermPattern::Fuzzy(text, edits) =>
+                term.starts_with(self.literal_prefix())
+                    && within_edits(text, term, *edits as usize)
+        }
+    }
+}
+
+/// True if `a` can be turned into `b` with at most `max` edits. This is the
+/// "optimal string alignment" variant of Damerau-Levenshtein distance.
+fn within_edits(a: &str, b: &str, max: usize) -> bool {
+    let (a_len, b_len) = (a.chars().count(), b.chars().count());
+    if a_len.abs_diff(b_len) > max {
+        return false;
+    }
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // The usual dynamic programming algorithm, one row at a time. `prev[j]`
+    // is the distance between the first i-1 characters of `a` and the first
+    // j characters of `b`; `prev2` is the row before that.
+    let mut prev2 = vec![0; b_len + 1];
+    let mut prev: Vec<usize> = (0..=b_len).collect();
+    let mut row = vec![0; b_len + 1];
+    for i in 1..=a_len {
+        row[0] = i;
+        for j in 1..=b_len {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut d = (prev[j] + 1).min(row[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(prev2[j - 2] + 1);
+            }
+            row[j] = d;
+        }
+        // Distances never go down from one row to the next, so if this whole
+        // row is over the limit, so is the answer.
+        if row.iter().all(|&d| d > max) {
+            return false;
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut row);
+    }
+    prev[b_len] <= max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary(terms: &[&str]) -> TermDictionary {
+        let mut sorted = terms.to_vec();
+        sorted.sort();
+        let mut dict = TermDictionary::new();
+        for (i, term) in sorted.iter().enumerate() {
+            dict.push(term, TermInfo { df: i as u32, offset: 0, nbytes: 0 }).unwrap();
+        }
+        dict
+    }
+
+    fn expand(dict: &TermDictionary, word: &str) -> Vec<String> {
+        dict.expand(&TermPattern::parse(word).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lookup() {
+        let words: Vec<String> = (0..100).map(|n| format!("w{}", n * 7)).collect();
+        let mut terms: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+        terms.extend(["index", "indexed", "indexes", "indexing", "ïndex", "東京"]);
+        let dict = dictionary(&terms);
+        terms.sort();
+        for (i, term) in terms.iter().enumerate() {
+            assert_eq!(dict.get(term).unwrap().map(|info| info.df), Some(i as u32),
+                       "{:?}", term);
+        }
+        for missing in ["", "a", "inde", "indexer", "w692", "zzz"] {
+            assert_eq!(dict.get(missing).unwrap(), None, "{:?}", missing);
+        }
+
+        let mut unsorted = TermDictionary::new();
+        let info = TermInfo { df: 1, offset: 0, nbytes: 0 };
+        unsorted.push("b", info).unwrap();
+        assert_eq!(unsorted.push("a", info), Err(FormatError::UnsortedTerms));
+
+        let mut corrupt = dictionary(&["apple", "banana"]);
+        corrupt.data.truncate(4);
+        assert!(corrupt.get("banana").is_err());
+        assert!(corrupt.expand(&TermPattern::Prefix("b".to_string())).is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let dict = dictionary(&["colour", "color", "cooler", "index", "indexed",
+                                "indexes", "indexing", "inder", "w10", "w100", "w2"]);
+        assert_eq!(expand(&dict, "index*"), vec!["index", "indexed", "indexes", "indexing"]);
+        assert_eq!(expand(&dict, "INDEX?S"), vec!["indexes"]);
+        assert_eq!(expand(&dict, "*ing"), vec!["indexing"]);
+        assert_eq!(expand(&dict, "w1?"), vec!["w10"]);
+        assert_eq!(expand(&dict, "w[12]*"), vec!["w10", "w100", "w2"]);
+        assert_eq!(expand(&dict, "colour~1"), vec!["color", "colour"]);
+        assert_eq!(expand(&dict, "colour~"), vec!["color", "colour", "cooler"]);
+        assert_eq!(expand(&dict, "idnex~1"), vec!["index"]);
+        assert!(expand(&dict, "undex~1").is_empty());
+        assert!(expand(&dict, "zebra*").is_empty());
+
+        assert_eq!(TermPattern::parse("plain"), Ok(None));
+        assert!(TermPattern::parse("a~3").is_err());
+        assert_eq!(TermPattern::parse("a~b"), Ok(None));
+        assert_eq!(TermPattern::parse("a~b~"), Ok(Some(TermPattern::Fuzzy("a~b".to_string(), 2))));
+        assert!(TermPattern::parse("~").is_err());
+        assert!(TermPattern::parse("*a*a*a*a*a*a*a*b").is_err());
+        assert!(TermPattern::parse(&format!("{}*", "a".repeat(MAX_PATTERN_CHARS))).is_err());
+        assert_eq!(TermPattern::parse(&"a".repeat(MAX_PATTERN_CHARS + 1)), Ok(None));
+    }
+}
@@ -1,42 +1,171 @@
-use std::io::{self, BufWriter};
+//! Temporary files, and putting the finished index in place.
+//!
+//! Building an index writes lots of temporary index files, which are merged
+//! and deleted as the work goes on. They all go in a private directory,
+//! which is deleted, along with anything still in it, when the `TmpDir` is
+//! dropped. So if indexing fails partway through, it doesn't leave a mess.
+//!
+//! The finished index is published with `publish`, which makes sure that
+//! anyone opening the index file sees either the old index or the complete
+//! new one, never a half-written file.
+
 use std::fs::{self, File};
+use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Clone)]
+/// A private directory for temporary files.
+///
+/// `TmpDir` can be shared between threads (in an `Arc`); `create` takes
+/// `&self`.
 pub struct TmpDir {
     dir: PathBuf,
-    n: usize
+    n: AtomicUsize
 }
 
 impl TmpDir {
-    pub fn new<P: AsRef<Path>>(dir: P) -> TmpDir {
-        TmpDir {
-            dir: dir.as_ref().to_owned(),
-            n: 1
+    /// Create a new, empty directory for temporary files inside `parent`,
+    /// creating `parent` too if necessary.
+    pub fn new<P: AsRef<Path>>(parent: P) -> io::Result<TmpDir> {
+        let parent = parent.as_ref();
+        fs::create_dir_all(parent)?;
+        let mut attempt = 0;
+        loop {
+            // A hidden name, so that indexing a directory that contains it
+            // doesn't pick up our temporary files.
+            let name = match attempt {
+                0 => format!(".fingertips-tmp-{}", process::id()),
+                _ => format!(".fingertips-tmp-{}-{}", process::id(), attempt)
+            };
+            let dir = parent.join(name);
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok(TmpDir { dir, n: AtomicUsize::new(1) }),
+                Err(err) =>
+                    if attempt < 999 && err.kind() == io::ErrorKind::AlreadyExists {
+                        // Left over from a process that crashed; try another name.
+                    } else {
+                        return Err(err);
+                    }
+            }
+            attempt += 1;
         }
     }
 
-    pub fn create(&mut self) -> io::Result<(PathBuf, BufWriter<File>)> {
-        let mut r#try = 1;
-        loop {
-            let filename = self.dir.join(PathBuf::from(format!("tmp{:08x}.dat", self.n)));
-            self.n += 1;
-            match fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&filename)
-                {
-                    Ok(f) => 
-                        return Ok((filename, BufWriter::new(f))),
-                    Err(exc) => 
-                        if r#try < 999 && exc.kind() == io::ErrorKind::AlreadyExists {
-                            // keep going
-                        } else {
-                            return Err(exc);
-                        }
-                }
-            r#try += 1;
+    /// Create a new temporary file.
+    pub fn create(&self) -> io::Result<(PathBuf, BufWriter<File>)> {
+        let n = self.n.fetch_add(1, Ordering::Relaxed);
+        let filename = self.dir.join(format!("tmp{:08x}.dat", n));
+        let f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&filename)?;
+        Ok((filename, BufWriter::new(f)))
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            eprintln!("warning: can't remove temporary directory {}: {}",
+                      self.dir.display(), err);
         }
     }
 }
 
+/// Move the finished file `file` to `dest`, replacing any file that's already
+/// there.
+///
+/// The file is first moved (or, if it's on a different filesystem, copied)
+/// to a temporary name next to `dest` and flushed to disk; only then is it
+/// renamed to `dest`. Renaming is atomic, so a program that opens `dest`
+/// sees either the old file or the new one, and if the system crashes, the
+/// old file is still there.
+pub fn publish(file: &Path, dest: &Path) -> io::Result<()> {
+    let dest_dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let staging = dest_dir.join(format!(".{}.partial-{}", name, process::id()));
+
+    let result = move_and_sync(file, &staging)
+        .and_then(|()| fs::rename(&staging, dest))
+        .and_then(|()| sync_dir(dest_dir));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
+
+fn move_and_sync(file: &Path, staging: &Path) -> io::Result<()> {
+    if fs::rename(file, staging).is_err() {
+        fs::copy(file, staging)?;
+        fs::remove_file(file)?;
+    }
+    fs::OpenOptions::new().write(true).open(staging)?.sync_all()
+}
+
+/// Flush a directory to disk, so that a rename in it survives a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    #[test]
+    fn cleanup() {
+        let parent = env::temp_dir().join(format!("fingertips-tmp-test-{}", process::id()));
+        let tmp_dir = TmpDir::new(&parent).unwrap();
+        let other = TmpDir::new(&parent).unwrap();
+        assert_ne!(tmp_dir.dir, other.dir);
+
+        // Whatever is left in the directory when it's dropped, say because
+        // indexing failed, goes with it.
+        let dir = tmp_dir.dir.clone();
+        let fail = |tmp_dir: TmpDir| -> io::Result<()> {
+            let (_, mut f) = tmp_dir.create()?;
+            f.write_all(b"half an index")?;
+            Err(io::Error::other("indexing failed"))
+        };
+        assert!(fail(tmp_dir).is_err());
+        assert!(!dir.exists());
+        drop(other);
+        assert_eq!(fs::read_dir(&parent).unwrap().count(), 0);
+        fs::remove_dir(&parent).unwrap();
+    }
+
+    #[test]
+    fn publish_replaces() {
+        let tmp_dir = TmpDir::new(env::temp_dir()).unwrap();
+        let dest = tmp_dir.dir.join("index.dat");
+        for contents in ["old", "new"] {
+            let (file, mut f) = tmp_dir.create().unwrap();
+            f.write_all(contents.as_bytes()).unwrap();
+            drop(f);
+            publish(&file, &dest).unwrap();
+            assert!(!file.exists());
+            assert_eq!(fs::read_to_string(&dest).unwrap(), contents);
+        }
+
+        // Nothing is left behind but the published file.
+        let names: Vec<_> = fs::read_dir(&tmp_dir.dir).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["index.dat"]);
+
+        // If publishing fails, the old file is still there.
+        assert!(publish(&tmp_dir.dir.join("missing.dat"), &dest).is_err());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+    }
+}
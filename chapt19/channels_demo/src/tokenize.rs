@@ -0,0 +1,303 @@
+//! Breaking text into terms.
+//!
+//! Before a document can be indexed, its text has to be broken into the
+//! terms that go in the index. There's more than one reasonable way to do
+//! that, so it's pluggable: anything that implements `Tokenizer` will do.
+//! This module provides two ways to split text into words, and some filters
+//! that can be stacked on top:
+//!
+//! * `simple` splits at every character that isn't a letter or digit.
+//!   `don't` becomes `don` and `t`.
+//!
+//! * `unicode` splits at word boundaries as defined by Unicode (UAX #29), so
+//!   contractions like `don't` and numbers like `3.14` stay in one piece, and
+//!   Chinese or Japanese text is broken into individual characters.
+//!
+//! * `stop` drops very common English words like `the` and `of`.
+//!
+//! * `stem` reduces English words to their stems using the Snowball (Porter 2)
+//!   algorithm, so that `indexing`, `indexed`, and `indexes` are all `index`.
+//!
+//! * `ngram:N` replaces each word with its overlapping N-character pieces,
+//!   so that searching for part of a word works.
+//!
+//! A tokenizer is described by a *spec*: the names above, separated by
+//! commas, starting with a splitter; for example, `unicode,stop,stem`. The
+//! spec is saved in each index file, so that queries against the index can be
+//! broken into terms the same way the documents were.
+//!
+//! The index stores each term's position, not where it is in the file in
+//! bytes. To show a search result in context, `tokenize_spans` tokenizes the
+//! document again and says which bytes of the text each token came from.
+
+use std::collections::HashSet;
+use std::ops::Range;
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The tokenizer used if none is specified.
+pub const DEFAULT_SPEC: &str = "unicode";
+
+/// One term found in a piece of text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    /// The term, as it's stored in the index.
+    pub text: String,
+
+    /// Where the term appears: 0 for the first word of the text, 1 for the
+    /// second, and so on. Filters that drop tokens leave gaps, so that
+    /// phrase searches don't match words that weren't really side by side.
+    pub position: u32
+}
+
+/// A way of breaking text into terms.
+pub trait Tokenizer: Send + Sync {
+    /// Break `text` into terms. The tokens are returned in order by position.
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        self.tokenize_spans(text).into_iter().map(|(token, _)| token).collect()
+    }
+
+    /// Like `tokenize`, but also return the range of bytes in `text` that
+    /// each token came from. All the tokens made from a single word (by
+    /// `ngram`, for instance) have that whole word's range.
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)>;
+
+    /// The spec for this tokenizer, which `from_spec` turns back into an
+    /// equivalent tokenizer.
+    fn spec(&self) -> String;
+}
+
+/// Split text at every character that isn't alphanumeric, and lowercase it.
+pub struct SimpleTokenizer;
+
+impl Tokenizer for SimpleTokenizer {
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let mut tokens = vec![];
+        let mut start = None;
+        let end = std::iter::once((text.len(), ' '));
+        for (i, ch) in text.char_indices().chain(end) {
+            if ch.is_alphanumeric() {
+                start.get_or_insert(i);
+            } else if let Some(start) = start.take() {
+                let position = tokens.len() as u32;
+                tokens.push((Token { text: text[start..i].to_lowercase(), position }, start..i));
+            }
+        }
+        tokens
+    }
+
+    fn spec(&self) -> String {
+        "simple".to_string()
+    }
+}
+
+/// Split text at Unicode word boundaries, and lowercase it.
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        text.unicode_word_indices()
+            .zip(0..)
+            .map(|((start, word), position)| {
+                (Token { text: word.to_lowercase(), position }, start..start + word.len())
+            })
+            .collect()
+    }
+
+    fn spec(&self) -> String {
+        "unicode".to_string()
+    }
+}
+
+/// The words dropped by `StopWords`: the English stop word list used by
+/// Lucene.
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in",
+    "into", "is", "it", "no", "not", "of", "on", "or", "such", "that", "the",
+    "their", "then", "there", "these", "they", "this", "to", "was", "will",
+    "with"
+];
+
+/// A filter that drops common words.
+pub struct StopWords {
+    inner: Box<dyn Tokenizer>,
+    words: HashSet<&'static str>
+}
+
+impl StopWords {
+    pub fn new(inner: Box<dyn Tokenizer>) -> StopWords {
+        StopWords { inner, words: ENGLISH_STOP_WORDS.iter().cloned().collect() }
+    }
+}
+
+impl Tokenizer for StopWords {
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let mut tokens = self.inner.tokenize_spans(text);
+        tokens.retain(|(t, _)| !self.words.contains(t.text.as_str()));
+        tokens
+    }
+
+    fn spec(&self) -> String {
+        self.inner.spec() + ",stop"
+    }
+}
+
+/// A filter that reduces English words to their stems.
+pub struct Stem {
+    inner: Box<dyn Tokenizer>,
+    stemmer: Stemmer
+}
+
+impl Stem {
+    pub fn new(inner: Box<dyn Tokenizer>) -> Stem {
+        Stem { inner, stemmer: Stemmer::create(Algorithm::English) }
+    }
+}
+
+impl Tokenizer for Stem {
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let mut tokens = self.inner.tokenize_spans(text);
+        for (token, _) in &mut tokens {
+            token.text = self.stemmer.stem(&token.text).into_owned();
+        }
+        tokens
+    }
+
+    fn spec(&self) -> String {
+        self.inner.spec() + ",stem"
+    }
+}
+
+/// A filter that breaks each word into overlapping pieces of `n` characters.
+/// Words shorter than that are kept whole.
+///
+/// Each piece gets its own position, so a phrase search for the pieces of a
+/// word (which is what a query for that word turns into) matches any word
+/// that contains it.
+pub struct NGrams {
+    inner: Box<dyn Tokenizer>,
+    n: usize
+}
+
+impl NGrams {
+    pub fn new(inner: Box<dyn Tokenizer>, n: usize) -> NGrams {
+        assert!(n > 0);
+        NGrams { inner, n }
+    }
+}
+
+impl Tokenizer for NGrams {
+    fn tokenize_spans(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let mut out = vec![];
+        let mut position = 0;
+        let mut last = None;
+        for (token, span) in self.inner.tokenize_spans(text) {
+            // Keep any gap left by an earlier filter.
+            if let Some(last) = last {
+                position += token.position - last - 1;
+            }
+            last = Some(token.position);
+
+            let chars: Vec<char> = token.text.chars().collect();
+            if chars.len() <= self.n {
+                out.push((Token { text: token.text, position }, span));
+                position += 1;
+            } else {
+                for gram in chars.windows(self.n) {
+                    out.push((Token { text: gram.iter().collect(), position }, span.clone()));
+                    position += 1;
+                }
+            }
+        }
+        out
+    }
+
+    fn spec(&self) -> String {
+        format!("{},ngram:{}", self.inner.spec(), self.n)
+    }
+}
+
+/// Build the tokenizer described by `spec` (see the module documentation).
+pub fn from_spec(spec: &str) -> Result<Box<dyn Tokenizer>, String> {
+    let mut parts = spec.split(',').map(str::trim);
+    let mut tokenizer: Box<dyn Tokenizer> = match parts.next() {
+        Some("simple") => Box::new(SimpleTokenizer),
+        Some("unicode") => Box::new(UnicodeTokenizer),
+        _ => return Err(format!("tokenizer `{}` must start with `simple` or `unicode`", spec))
+    };
+    for part in parts {
+        tokenizer = match part {
+            "stop" => Box::new(StopWords::new(tokenizer)),
+            "stem" => Box::new(Stem::new(tokenizer)),
+            _ => match part.strip_prefix("ngram:").map(str::parse::<usize>) {
+                Some(Ok(n)) if n > 0 => Box::new(NGrams::new(tokenizer, n)),
+                _ => return Err(format!("unknown tokenizer filter `{}`", part))
+            }
+        };
+    }
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(spec: &str, text: &str) -> Vec<(String, u32)> {
+        from_spec(spec).unwrap()
+            .tokenize(text)
+            .into_iter()
+            .map(|t| (t.text, t.position))
+            .collect()
+    }
+
+    fn t(text: &str, position: u32) -> (String, u32) {
+        (text.to_string(), position)
+    }
+
+    #[test]
+    fn splitting() {
+        assert_eq!(terms("simple", "Don't panic!"), vec![t("don", 0), t("t", 1), t("panic", 2)]);
+        assert_eq!(terms("unicode", "Don't panic!"), vec![t("don't", 0), t("panic", 1)]);
+        assert_eq!(terms("unicode", "pi is 3.14"), vec![t("pi", 0), t("is", 1), t("3.14", 2)]);
+        assert_eq!(terms("unicode", "東京"), vec![t("東", 0), t("京", 1)]);
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(terms("unicode,stop", "the borrow checker is happy"),
+                   vec![t("borrow", 1), t("checker", 2), t("happy", 4)]);
+        assert_eq!(terms("unicode,stem", "Indexing indexed indexes"),
+                   vec![t("index", 0), t("index", 1), t("index", 2)]);
+        assert_eq!(terms("simple,ngram:3", "a rust"),
+                   vec![t("a", 0), t("rus", 1), t("ust", 2)]);
+        assert_eq!(terms("simple,stop,ngram:2", "abc of de"),
+                   vec![t("ab", 0), t("bc", 1), t("de", 3)]);
+    }
+
+    #[test]
+    fn spans() {
+        let text = "Ünïcode, don't panic!";
+        for spec in ["simple", "unicode,stop,stem", "unicode,ngram:3"] {
+            let tokenizer = from_spec(spec).unwrap();
+            let tokens = tokenizer.tokenize_spans(text);
+            assert_eq!(tokens.iter().map(|(t, _)| t.clone()).collect::<Vec<_>>(),
+                       tokenizer.tokenize(text));
+            let words: Vec<&str> = tokens.iter().map(|(_, span)| &text[span.clone()]).collect();
+            match spec {
+                "simple" => assert_eq!(words, vec!["Ünïcode", "don", "t", "panic"]),
+                "unicode,stop,stem" => assert_eq!(words, vec!["Ünïcode", "don't", "panic"]),
+                _ => assert_eq!(&words[..3], &["Ünïcode"; 3])
+            }
+        }
+    }
+
+    #[test]
+    fn specs() {
+        for &spec in &["simple", "unicode", "unicode,stop,stem", "simple,ngram:3"] {
+            assert_eq!(from_spec(spec).unwrap().spec(), spec);
+        }
+        assert!(from_spec("stem").is_err());
+        assert!(from_spec("unicode,ngram:0").is_err());
+        assert!(from_spec("unicode,upcase").is_err());
+    }
+}
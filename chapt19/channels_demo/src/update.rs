@@ -0,0 +1,185 @@
+//! Updating an existing index.
+//!
+//! Rebuilding an index from scratch means rereading every document. When
+//! most of the documents haven't changed since the last run, we can instead
+//! index only the new and changed files, and merge the result into the
+//! existing index file. Documents that were deleted or changed get a
+//! tombstone (see the `docs` module), which tells the merge to drop their
+//! old hits.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::docs::{content_hash, DocumentInfo, Duplicate};
+
+/// What needs to be done to bring an index up to date.
+pub struct UpdatePlan {
+    /// Files to index: new files, and files that have changed since they
+    /// were indexed.
+    pub files: Vec<PathBuf>,
+
+    /// Tombstones for documents that have been deleted or changed.
+    pub tombstones: Vec<DocumentInfo>,
+
+    /// The id to assign to the first file in `files`. This is greater than
+    /// every id in the existing index, so the new documents sort after all
+    /// the old ones.
+    pub first_doc_id: usize
+}
+
+impl UpdatePlan {
+    /// True if the index is already up to date.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.tombstones.is_empty()
+    }
+}
+
+/// Compare the document table of an existing index, `existing`, against the
+/// current list of files to index, `files`, and figure out what has changed.
+///
+/// A document with duplicates (see `dedup.rs`) has several files. If any of
+/// them has changed or been deleted, the whole document is tombstoned, and
+/// its files that haven't changed are indexed again along with the new ones.
+pub fn plan_update(existing: &[DocumentInfo], files: Vec<PathBuf>) -> io::Result<UpdatePlan> {
+    let first_doc_id = existing.iter().map(|d| d.id as usize + 1).max().unwrap_or(0);
+
+    // Every file in the index, with what was recorded about it and the
+    // document it belongs to.
+    let mut indexed: HashMap<&Path, (Duplicate, &DocumentInfo)> = HashMap::new();
+    for doc in existing.iter().filter(|d| !d.deleted) {
+        indexed.insert(&doc.path, (doc.clone().into_duplicate(), doc));
+        for dup in &doc.duplicates {
+            indexed.insert(&dup.path, (dup.clone(), doc));
+        }
+    }
+
+    let mut plan = UpdatePlan { files: vec![], tombstones: vec![], first_doc_id };
+    let mut changed: BTreeMap<u32, &DocumentInfo> = BTreeMap::new();
+    let mut unchanged: HashMap<u32, Vec<PathBuf>> = HashMap::new();
+    for file in files {
+        match indexed.remove(file.as_path()) {
+            Some((recorded, doc)) if is_unchanged(&recorded, &file)? => {
+                unchanged.entry(doc.id).or_default().push(file);
+            }
+            Some((_, doc)) => {
+                changed.insert(doc.id, doc);
+                plan.files.push(file);
+            }
+            None => plan.files.push(file)
+        }
+    }
+
+    // Whatever is left wasn't in `files`, so it must have been deleted.
+    changed.extend(indexed.into_values().map(|(_, doc)| (doc.id, doc)));
+
+    for (id, doc) in changed {
+        plan.tombstones.push(tombstone(doc));
+        plan.files.extend(unchanged.remove(&id).unwrap_or_default());
+    }
+    Ok(plan)
+}
+
+fn tombstone(doc: &DocumentInfo) -> DocumentInfo {
+    DocumentInfo { deleted: true, duplicates: vec![], ..doc.clone() }
+}
+
+/// True if the file at `path` still has the contents `recorded` when it
+/// was indexed.
+///
+/// If the size and modification time match, we assume it hasn't changed. If
+/// only the modification time is different, we check the contents.
+fn is_unchanged(recorded: &Duplicate, path: &Path) -> io::Result<bool> {
+    let metadata = fs::metadata(path)?;
+    if metadata.len() != recorded.size {
+        return Ok(false);
+    }
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if mtime == recorded.mtime {
+        return Ok(true);
+    }
+    Ok(content_hash(&fs::read(path)?) == recorded.hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docs::Encoding;
+    use crate::tmp::TmpDir;
+
+    /// Write `text` to a new file in `tmp_dir`.
+    fn write(tmp_dir: &TmpDir, text: &str) -> PathBuf {
+        let (path, _) = tmp_dir.create().unwrap();
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    /// What the index would have recorded about the file at `path`, as
+    /// document `id`.
+    fn record(id: u32, path: &Path) -> DocumentInfo {
+        let (info, _) = DocumentInfo::load(path.to_owned(), Encoding::Utf8).unwrap();
+        DocumentInfo { id, ..info }
+    }
+
+    fn ids(plan: &UpdatePlan) -> Vec<u32> {
+        plan.tombstones.iter().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn plans() {
+        let tmp_dir = TmpDir::new(std::env::temp_dir()).unwrap();
+        let a = write(&tmp_dir, "apple");
+        let b = write(&tmp_dir, "banana");
+        let c = write(&tmp_dir, "cherry");
+        let copy = write(&tmp_dir, "apple");
+        let mut doc_a = record(0, &a);
+        doc_a.duplicates.push(record(0, &copy).into_duplicate());
+        let existing = vec![doc_a, record(1, &b), record(2, &c)];
+
+        // Nothing has changed.
+        let plan = plan_update(&existing, vec![a.clone(), b.clone(), c.clone(), copy.clone()])
+            .unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(plan.first_doc_id, 3);
+
+        // A different modification time alone isn't a change, if the contents
+        // are the same.
+        let mut touched = existing.clone();
+        touched[1].mtime = 0;
+        let plan = plan_update(&touched, vec![a.clone(), b.clone(), c.clone(), copy.clone()])
+            .unwrap();
+        assert!(plan.is_empty());
+
+        // `b` changed, `c` was deleted, and `d` is new.
+        fs::write(&b, "blueberry").unwrap();
+        let d = write(&tmp_dir, "date");
+        let plan = plan_update(&existing, vec![a.clone(), b.clone(), copy.clone(), d.clone()])
+            .unwrap();
+        assert_eq!(plan.files, vec![b.clone(), d.clone()]);
+        assert_eq!(ids(&plan), vec![1, 2]);
+        assert!(plan.tombstones.iter().all(|t| t.deleted && t.duplicates.is_empty()));
+        assert_eq!(plan.first_doc_id, 3);
+
+        // When a copy changes, the whole document goes, and the files that
+        // haven't changed are indexed again.
+        fs::write(&copy, "apricot").unwrap();
+        let plan = plan_update(&existing, vec![a.clone(), copy.clone()]).unwrap();
+        assert_eq!(plan.files, vec![copy.clone(), a.clone()]);
+        assert_eq!(ids(&plan), vec![0, 1, 2]);
+
+        // Same if a copy is deleted.
+        let plan = plan_update(&existing, vec![a.clone()]).unwrap();
+        assert_eq!(plan.files, vec![a.clone()]);
+        assert_eq!(ids(&plan), vec![0, 1, 2]);
+
+        // Documents that were already deleted are ignored.
+        let mut deleted = existing.clone();
+        deleted[2].deleted = true;
+        let plan = plan_update(&deleted, vec![]).unwrap();
+        assert_eq!(ids(&plan), vec![0, 1]);
+    }
+}
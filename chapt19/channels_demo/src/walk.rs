@@ -0,0 +1,352 @@
+//! Finding the files to index.
+//!
+//! The command line names files and directories. Files are indexed as given.
+//! Directories are searched recursively, and the files found there are
+//! filtered by name using glob patterns (see `Pattern`):
+//!
+//! * A file is indexed if it matches any `--include` pattern (by default,
+//!   text, Markdown, and HTML files, gzipped or not; see `DEFAULT_INCLUDE`)
+//!   and no `--exclude` pattern.
+//!
+//! * A directory is skipped, along with everything under it, if it matches an
+//!   `--exclude` pattern.
+//!
+//! * Files and directories whose names start with `.` are skipped, unless
+//!   hidden files are asked for.
+//!
+//! Symbolic links are followed, but no directory is searched twice, so a link
+//! that points back up the tree can't send us around in circles.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A shell-style glob pattern, for matching file paths.
+///
+/// `*` matches any sequence of characters other than `/`, `?` matches any one
+/// character other than `/`, and `[abc]`, `[a-z]`, or `[!abc]` match one
+/// character from (or not from) a set. A path segment that is exactly `**`
+/// matches any number of directories, including none; so `target/**` matches
+/// `target` and everything under it.
+///
+/// A pattern with no `/` in it is matched against just the last component of
+/// the path, so `*.md` matches Markdown files in any directory. Otherwise it's
+/// matched against the whole path, relative to the directory named on the
+/// command line.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    segments: Vec<String>,
+    name_only: bool
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let pattern = pattern.trim_start_matches("./");
+        Pattern {
+            segments: pattern.split('/').filter(|s| !s.is_empty()).map(String::from).collect(),
+            name_only: !pattern.contains('/')
+        }
+    }
+
+    /// True if the file at `path` matches this pattern. `path` is relative to
+    /// the directory being searched.
+    pub fn matches(&self, path: &Path) -> bool {
+        let components: Vec<String> = path.components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if self.name_only {
+            match components.last() {
+                Some(name) => self.segments.len() == 1 && match_segment(&self.segments[0], name),
+                None => false
+            }
+        } else {
+            match_path(&self.segments, &components)
+        }
+    }
+}
+
+/// Match a pattern, split into `/`-separated segments, against a path, split
+/// into components. A `**` segment works like `*` does in `match_chars`, with
+/// whole components in place of characters, and for the same reason only the
+/// last `**` is ever revisited.
+fn match_path(pattern: &[String], path: &[String]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < path.len() {
+        if pattern.get(p).is_some_and(|s| s == "**") {
+            p += 1;
+            star = Some((p, n));
+            continue;
+        }
+        if p < pattern.len() && match_segment(&pattern[p], &path[n]) {
+            p += 1;
+            n += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, matched)) => {
+                p = after_star;
+                n = matched + 1;
+                star = Some((after_star, n));
+            }
+            None => return false
+        }
+    }
+    pattern[p..].iter().all(|s| s == "**")
+}
+
+/// Match one segment of a pattern against one file or directory name. (This
+/// is also how wildcard terms in queries are matched; see the `terms`
+/// module.)
+pub fn match_segment(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+/// Match a pattern against a name, a character at a time. When something
+/// doesn't match, we go back to the last `*` and let it take one more
+/// character of the name. Only the last `*` ever needs to be revisited: if
+/// the rest of the pattern can match from some point, an earlier `*` is never
+/// better off taking more. So this takes time proportional to the product
+/// of the lengths, rather than blowing up exponentially with the number of
+/// `*`s.
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+
+    // The pattern position just after the last `*` we've passed, and the
+    // position in `name` it's matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, matched)) => {
+                p = after_star;
+                n = matched + 1;
+                star = Some((after_star, n));
+            }
+            None => return false
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+/// If the first item in `pattern` (a character, `?`, or a class) matches
+/// `ch`, return how many characters of the pattern it takes up.
+fn match_one(pattern: &[char], ch: char) -> Option<usize> {
+    match pattern.first()? {
+        '?' => Some(1),
+        '[' => match match_class(&pattern[1..]) {
+            Some((set, negated, len)) => (set.contains(ch) != negated).then_some(len + 1),
+            // A `[` with no closing `]` is just a character.
+            None => (ch == '[').then_some(1)
+        },
+        &c => (c == ch).then_some(1)
+    }
+}
+
+/// A set of characters, as written between brackets in a pattern.
+struct CharClass {
+    ranges: Vec<(char, char)>
+}
+
+impl CharClass {
+    fn contains(&self, ch: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= ch && ch <= hi)
+    }
+}
+
+/// Parse a character class, starting just after the `[`. Returns the class,
+/// whether it's negated, and the number of pattern characters it used up,
+/// including the closing `]`; or `None` if there's no closing `]`.
+fn match_class(pattern: &[char]) -> Option<(CharClass, bool, usize)> {
+    let negated = pattern.first() == Some(&'!');
+    let mut i = if negated { 1 } else { 0 };
+    let mut ranges = vec![];
+    // A `]` right at the start is part of the set.
+    let start = i;
+    while i < pattern.len() && (pattern[i] != ']' || i == start) {
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            ranges.push((pattern[i], pattern[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((pattern[i], pattern[i]));
+            i += 1;
+        }
+    }
+    if i == pattern.len() {
+        return None;
+    }
+    Some((CharClass { ranges }, negated, i + 1))
+}
+
+/// Which files to pick up when searching a directory.
+pub struct WalkOptions {
+    /// Files are indexed only if they match one of these.
+    pub include: Vec<Pattern>,
+
+    /// Files and directories that match any of these are skipped.
+    pub exclude: Vec<Pattern>,
+
+    /// If false, skip files and directories whose names start with `.`.
+    pub hidden: bool
+}
+
+/// The files indexed when no `--include` pattern is given: the kinds of
+/// files that `loader.rs` knows how to turn into text.
+pub const DEFAULT_INCLUDE: &[&str] = &[
+    "*.txt", "*.md", "*.markdown", "*.html", "*.htm",
+    "*.txt.gz", "*.md.gz", "*.markdown.gz", "*.html.gz", "*.htm.gz"
+];
+
+impl Default for WalkOptions {
+    fn default() -> WalkOptions {
+        WalkOptions {
+            include: DEFAULT_INCLUDE.iter().map(|p| Pattern::new(p)).collect(),
+            exclude: vec![],
+            hidden: false
+        }
+    }
+}
+
+/// Given some paths, generate the complete list of files to index. We check
+/// on disk whether each path is the name of a file or a directory. Files are
+/// always indexed; directories are searched as described in the module
+/// documentation. Relative paths are fine.
+///
+/// It's an error if any of the `paths` is not a valid path to an existing file
+/// or directory.
+pub fn expand_paths(paths: Vec<PathBuf>, options: &WalkOptions) -> io::Result<Vec<PathBuf>> {
+    let mut walker = Walker { options, visited: HashSet::new(), files: vec![] };
+    for path in paths {
+        if path.metadata()?.is_dir() {
+            walker.walk(&path, Path::new(""))?;
+        } else {
+            walker.files.push(path);
+        }
+    }
+    Ok(walker.files)
+}
+
+struct Walker<'a> {
+    options: &'a WalkOptions,
+
+    /// The canonical paths of the directories we've searched so far.
+    visited: HashSet<PathBuf>,
+
+    /// The files found so far.
+    files: Vec<PathBuf>
+}
+
+impl Walker<'_> {
+    /// Search the directory `dir`, whose path relative to the directory named
+    /// on the command line is `relative`.
+    fn walk(&mut self, dir: &Path, relative: &Path) -> io::Result<()> {
+        if !self.visited.insert(dir.canonicalize()?) {
+            return Ok(());
+        }
+
+        // Sort, so that documents are numbered the same way every time.
+        let mut entries = dir.read_dir()?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for name in entries {
+            if !self.options.hidden && name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = dir.join(&name);
+            let relative = relative.join(&name);
+            if self.options.exclude.iter().any(|p| p.matches(&relative)) {
+                continue;
+            }
+
+            // Follow symbolic links. A broken link isn't worth stopping for.
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    eprintln!("skipping {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                self.walk(&path, &relative)?;
+            } else if metadata.is_file() && self.options.include.iter().any(|p| p.matches(&relative)) {
+                self.files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::new(pattern).matches(Path::new(path))
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(matches("*.txt", "dir/sub/a.txt"));
+        assert!(!matches("*.txt", "a.txt.gz"));
+        assert!(!matches("*.txt", "txt"));
+        assert!(matches("?.md", "a.md"));
+        assert!(!matches("?.md", "ab.md"));
+        assert!(matches("[a-c]*", "beta"));
+        assert!(!matches("[!a-c]*", "beta"));
+        assert!(matches("[]x]", "]"));
+        assert!(matches("a[", "a["));
+
+        assert!(matches("target/**", "target"));
+        assert!(matches("target/**", "target/debug/x.txt"));
+        assert!(!matches("target/**", "src/target/x.txt"));
+        assert!(matches("**/target", "src/target"));
+        assert!(matches("docs/*.txt", "docs/a.txt"));
+        assert!(!matches("docs/*.txt", "docs/sub/a.txt"));
+        assert!(matches("docs/**/*.txt", "docs/sub/a.txt"));
+        assert!(matches("./docs/*.txt", "docs/a.txt"));
+    }
+
+    #[test]
+    fn stars() {
+        assert!(match_segment("*", ""));
+        assert!(match_segment("a*b*c", "abc"));
+        assert!(match_segment("a*b*c", "axxbyybzc"));
+        assert!(!match_segment("a*b*c", "axxbyybzcd"));
+        assert!(match_segment("*?[!x]", "ab"));
+        assert!(match_segment("**a", "bba"));
+
+        // Backtracking into every `*` would take forever here.
+        let name = "a".repeat(40);
+        assert!(!match_segment(&format!("{}*b", "*a".repeat(20)), &name));
+        assert!(match_segment(&"*a".repeat(20), &name));
+    }
+
+    #[test]
+    fn double_stars() {
+        assert!(matches("a/**/b/**/c", "a/b/c"));
+        assert!(matches("a/**/b/**/c", "a/x/b/y/b/z/c"));
+        assert!(!matches("a/**/b/**/c", "a/x/b/y/c/d"));
+        assert!(matches("**/*.txt", "a.txt"));
+        assert!(!matches("a/**", "b"));
+
+        // As with `*`, backtracking into every `**` would take forever.
+        let path = vec!["a"; 40].join("/");
+        assert!(!matches(&format!("{}/b", vec!["**/a"; 20].join("/")), &path));
+        assert!(matches(&vec!["**/a"; 20].join("/"), &path));
+    }
+}
@@ -2,19 +2,24 @@ use std::fs::File;
 use std::io::{self, BufWriter, SeekFrom};
 use std::io::prelude::*;
 use std::path::PathBuf;
+use crate::docs::{write_documents, DocumentInfo, Encoding};
+use crate::header::{Header, HEADER_SIZE};
 use crate::index::InMemoryIndex;
+use crate::postings::{Postings, PostingsEncoder, PostingsFormat};
 use crate::tmp::TmpDir;
 use byteorder::{LittleEndian, WriteBytesExt};
+use crc32fast::Hasher;
 
 /// Writer for saving an index to a binary file.
 ///
-/// The first 8 bytes of the index file contain the offset of the table of
-/// contents, in bytes. Then come the main entries, all stored back-to-back
-/// with no particular metadata.
+/// The index file starts with a fixed-size header (see the `header` module),
+/// which gives the offsets of the other sections and their checksums. Since
+/// we don't know those until the end, `finish` goes back and fills it in.
 ///
-
-/// An index file has two parts. The main part of the file is a sequence of
-/// entries, stored back-to-back; the
+/// After the header, an index file has three parts. The main part of the file
+/// is a sequence of entries, stored back-to-back. After that comes the
+/// document table (see the `docs` module); and last, the table of contents
+/// says which term each entry belongs to and where it starts.
 pub struct IndexFileWriter {
     /// The number of bytes written so far.
     offset: u64,
@@ -23,22 +28,48 @@ pub struct IndexFileWriter {
     writer: BufWriter<File>,
 
     /// The table of contents for this file.
-    contents_buf: Vec<u8>
+    contents_buf: Vec<u8>,
+
+    /// The document table for this file.
+    documents_buf: Vec<u8>,
+
+    /// How hits are encoded in this file.
+    format: PostingsFormat,
+
+    /// Checksum of everything passed to `write_main` so far.
+    main_crc: Hasher
 }
 
 impl IndexFileWriter {
     pub fn new(mut f: BufWriter<File>) -> io::Result<IndexFileWriter> {
-        const HEADER_SIZE: u64 = 8;
-        f.write_u64::<LittleEndian>(0)?;
-        Ok(IndexFileWriter {
+        // Leave room for the header. `finish` will fill it in.
+        f.write_all(&[0; HEADER_SIZE as usize])?;
+        let mut writer = IndexFileWriter {
             offset: HEADER_SIZE,
             writer: f,
-            contents_buf: vec![]
-        })
+            contents_buf: vec![],
+            documents_buf: vec![],
+            format: PostingsFormat::Compressed,
+            main_crc: Hasher::new()
+        };
+        writer.write_documents("", Encoding::Utf8, &[]);
+        Ok(writer)
+    }
+
+    /// How hits must be encoded in the data passed to `write_main`.
+    pub fn format(&self) -> PostingsFormat {
+        self.format
+    }
+
+    /// The number of bytes written so far; that is, the offset where the next
+    /// call to `write_main` will put its data.
+    pub fn offset(&self) -> u64 {
+        self.offset
     }
 
     pub fn write_main(&mut self, buf: &[u8]) -> io::Result<()> {
         self.writer.write_all(buf)?;
+        self.main_crc.update(buf);
         self.offset += buf.len() as u64;
         Ok(())
     }
@@ -52,32 +83,60 @@ impl IndexFileWriter {
         self.contents_buf.extend(bytes);
     }
 
+    /// Set the document table for this file. `tokenizer` is the spec of the
+    /// tokenizer used to index the documents, and `encoding` is how they were
+    /// decoded. `documents` must be sorted by id.
+    pub fn write_documents(&mut self, tokenizer: &str, encoding: Encoding,
+                           documents: &[DocumentInfo]) {
+        self.documents_buf.clear();
+        write_documents(&mut self.documents_buf, tokenizer, encoding, documents).unwrap();
+    }
+
     /// Finish writing the index file and close it.
     pub fn finish(mut self) -> io::Result<()> {
-        let contents_start = self.offset;
+        let documents_start = self.offset;
+        self.writer.write_all(&self.documents_buf)?;
+        let contents_start = documents_start + self.documents_buf.len() as u64;
         self.writer.write_all(&self.contents_buf)?;
-        println!("{} bytes main, {} bytes total", contents_start, contents_start + self.contents_buf.len() as u64);
+        let file_len = contents_start + self.contents_buf.len() as u64;
+        let header = Header {
+            format: self.format,
+            documents_offset: documents_start,
+            contents_offset: contents_start,
+            file_len,
+            main_crc: self.main_crc.finalize(),
+            documents_crc: crc32fast::hash(&self.documents_buf),
+            contents_crc: crc32fast::hash(&self.contents_buf)
+        };
         self.writer.seek(SeekFrom::Start(0))?;
-        self.writer.write_u64::<LittleEndian>(contents_start)?;
-        Ok(())
+        header.write_to(&mut self.writer)?;
+        self.writer.flush()
     }
 }
 
-pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> io::Result<PathBuf> {
+pub fn write_index_to_tmp_file(index: InMemoryIndex, tokenizer: &str, encoding: Encoding,
+                               tmp_dir: &TmpDir)
+    -> io::Result<PathBuf>
+{
     let (filename, f) = tmp_dir.create()?;
     let mut writer = IndexFileWriter::new(f)?;
+    writer.write_documents(tokenizer, encoding, &index.documents);
 
     // The merge algorithm requires the entries within each file to be sorted by term.
     // Sort before writing anything.
     let mut index_as_vec: Vec<_> = index.map.into_iter().collect();
-    index_as_vec.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    index_as_vec.sort_by(|(a, _), (b, _)| a.cmp(b));
 
     for (term, hits) in index_as_vec {
         let df = hits.len() as u32;
         let start = writer.offset;
+        let mut encoder = PostingsEncoder::new(writer.format);
         for buffer in hits {
-            writer.write_main(&buffer)?;
+            for posting in Postings::new(PostingsFormat::Raw, &buffer) {
+                encoder.push(&posting?);
+            }
         }
+        writer.write_main(&encoder.finish())?;
         let stop = writer.offset;
         writer.write_contents_entry(term, df, start, stop - start);
     }